@@ -19,6 +19,30 @@ pub struct Args {
     /// Wait for response before sending next message
     #[clap(short, long, value_parser, default_value_t = false)]
     pub wait: bool,
+
+    /// Address of the server
+    #[clap(long, value_parser, default_value = crate::HOST)]
+    pub host: String,
+
+    /// Port the server listens on
+    #[clap(short, long, value_parser, default_value_t = crate::PORT)]
+    pub port: u16,
+
+    /// Connect over TLS, checking the server's certificate against --ca
+    #[clap(long, value_parser, default_value_t = false, requires = "ca")]
+    pub tls: bool,
+
+    /// PEM file with the CA certificates to trust
+    #[clap(long, value_parser)]
+    pub ca: Option<String>,
+
+    /// PEM file with the client certificate, for servers requiring one
+    #[clap(long, value_parser, requires_all = &["tls", "key"])]
+    pub cert: Option<String>,
+
+    /// PEM file with the private key of --cert
+    #[clap(long, value_parser, requires = "cert")]
+    pub key: Option<String>,
 }
 
 pub fn parse() -> Args {
@@ -0,0 +1,80 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::Arc;
+
+use rustls::ClientConfig;
+
+use crate::tls::Stream;
+
+/// One line of what the server answers to a request.
+pub enum Line {
+    /// Sent ahead of the reply by a `compute <k> progress`.
+    Progress(u8),
+    Reply(String),
+}
+
+pub struct Connection {
+    reader: BufReader<Stream>,
+}
+
+impl Connection {
+    pub fn connect(addr: &str, tls: Option<&Arc<ClientConfig>>) -> io::Result<Self> {
+        Ok(Self { reader: BufReader::new(Stream::connect(addr, tls)?) })
+    }
+
+    /// Writes the request in one piece, so the server does not read the newline as a request of its own.
+    pub fn send(&mut self, message: &str) -> io::Result<()> {
+        self.reader.get_mut().write_all(format!("{}\n", message).as_bytes())
+    }
+
+    /// Answer to the last request, progress lines first and the reply last.
+    pub fn replies(&mut self) -> Replies<'_> {
+        Replies { connection: self, done: false }
+    }
+
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.reader.get_mut().shutdown()
+    }
+}
+
+/// Stream of the lines answering one request, it ends after the reply or once the server closes.
+pub struct Replies<'a> {
+    connection: &'a mut Connection,
+    done: bool,
+}
+
+impl Iterator for Replies<'_> {
+    type Item = io::Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut line = String::new();
+        match self.connection.reader.read_line(&mut line) {
+            Ok(0) => {
+                self.done = true;
+                return None;
+            },
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            },
+            Ok(_) => {},
+        }
+
+        let percent = line.trim_end().strip_prefix("progress: ").and_then(|rest| rest.strip_suffix('%')?.parse().ok());
+        if let Some(percent) = percent {
+            return Some(Ok(Line::Progress(percent)));
+        }
+
+        // replies spanning several lines arrive in one piece, keep whatever came along with the first one
+        let reader = &mut self.connection.reader;
+        let rest = reader.buffer().len();
+        line.push_str(&String::from_utf8_lossy(reader.buffer()));
+        reader.consume(rest);
+
+        self.done = true;
+        Some(Ok(Line::Reply(line)))
+    }
+}
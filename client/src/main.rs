@@ -1,26 +1,37 @@
 mod args;
+mod connection;
+mod tls;
 
-use std::io::{self, Write, Read};
-use std::net;
+use std::io;
 use std::thread;
 use std::time;
 
-static PORT: u32 = 3000;
+use crate::connection::{Connection, Line};
+
+static HOST: &str = "127.0.0.1";
+static PORT: u16 = 3000;
 
 fn main() -> io::Result<()> {
     let args = args::parse();
 
-    let mut stream = net::TcpStream::connect(format!("127.0.0.1:{}", PORT))?;
-    let mut buf = [0; 256];
+    let tls = match (args.tls, &args.ca) {
+        (true, Some(ca)) => Some(tls::config(ca, args.cert.as_deref().zip(args.key.as_deref()))?),
+        _ => None,
+    };
+
+    let mut connection = Connection::connect(&format!("{}:{}", args.host, args.port), tls.as_ref())?;
 
     for _ in 0..args.repeat {
         println!("Sending [{}]", args.message);
-        stream.write_all(args.message.as_bytes())?;
-        stream.write_all("\n".as_bytes())?;
+        connection.send(&args.message)?;
 
         if args.wait {
-            let len = stream.read(&mut buf)?;
-            println!("Received [{}]", String::from_utf8_lossy(&buf[..len]).trim_end());
+            for line in connection.replies() {
+                match line? {
+                    Line::Progress(percent) => println!("Progress [{}%]", percent),
+                    Line::Reply(reply) => println!("Received [{}]", reply.trim_end()),
+                }
+            }
         }
 
         if args.delay > 0 {
@@ -28,8 +39,8 @@ fn main() -> io::Result<()> {
         }
     }
 
-    stream.write("done\n".as_bytes())?;
-    stream.shutdown(net::Shutdown::Both)?;
+    connection.send("done")?;
+    connection.shutdown()?;
 
     Ok(())
 }
@@ -0,0 +1,95 @@
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+/// Trusts the server certificates signed by a CA in the PEM file `ca`.
+/// With `identity` holding a certificate chain and its key, the client shows that to servers asking for one.
+pub fn config(ca: &str, identity: Option<(&str, &str)>) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca).map_err(invalid)? {
+        roots.add(cert.map_err(invalid)?).map_err(invalid)?;
+    }
+
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let config = match identity {
+        Some((cert, key)) => {
+            let chain = CertificateDer::pem_file_iter(cert).map_err(invalid)?.collect::<Result<Vec<_>, _>>().map_err(invalid)?;
+            let key = PrivateKeyDer::from_pem_file(key).map_err(invalid)?;
+            builder.with_client_auth_cert(chain, key).map_err(invalid)?
+        },
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(config))
+}
+
+fn invalid(err: impl Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Connection to the server, encrypted when connecting with a TLS config.
+pub enum Stream {
+    Plain(net::TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, net::TcpStream>>),
+}
+
+impl Stream {
+    /// The certificate must be valid for the host part of `addr`, the handshake runs along with the first write.
+    pub fn connect(addr: &str, config: Option<&Arc<ClientConfig>>) -> io::Result<Self> {
+        let socket = net::TcpStream::connect(addr)?;
+        let Some(config) = config else {
+            return Ok(Stream::Plain(socket));
+        };
+
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let name = ServerName::try_from(host.to_string()).map_err(invalid)?;
+        let conn = ClientConnection::new(Arc::clone(config), name).map_err(invalid)?;
+        Ok(Stream::Tls(Box::new(StreamOwned::new(conn, socket))))
+    }
+
+    /// Tells a TLS server the connection ends on purpose before closing the socket.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.shutdown(net::Shutdown::Both),
+            Stream::Tls(stream) => {
+                stream.conn.send_close_notify();
+                stream.flush()?;
+                stream.sock.shutdown(net::Shutdown::Both)
+            },
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.read(buf),
+            // a server closing the socket without a close_notify is taken as closing the connection
+            Stream::Tls(stream) => match stream.read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+                res => res,
+            },
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
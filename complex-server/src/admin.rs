@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::net::{self, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{info, LevelFilter};
+
+use crate::connections::Connections;
+use crate::logger;
+use crate::metrics::Metrics;
+use crate::slowlog::Slowlog;
+
+/// Requests and errors per command since the server started, as counted for `/metrics`.
+pub struct Stats {
+    started: Instant,
+    metrics: Arc<Metrics>,
+}
+
+impl Stats {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { started: Instant::now(), metrics }
+    }
+
+    /// Reply to `stats`, a header with the number of commands followed by a line for each.
+    pub fn report(&self) -> String {
+        let requests = self.metrics.requests();
+        let mut report = format!("stats: {}\n", requests.len());
+        for (command, calls, errors) in requests {
+            let _ = writeln!(report, "{} calls={} errors={}", command, calls, errors);
+        }
+        report
+    }
+
+    /// Reply to `info`, about the process as a whole.
+    pub fn info(&self, clients: usize) -> String {
+        let lines = [
+            format!("uptime: {}s", self.started.elapsed().as_secs()),
+            format!("version: {}", env!("CARGO_PKG_VERSION")),
+            format!("architecture: {} ({})", env!("CARGO_PKG_NAME"), std::env::consts::ARCH),
+            format!("threads: {}", proc_status("Threads").unwrap_or_else(|| String::from("unknown"))),
+            format!("memory: {}", proc_status("VmRSS").unwrap_or_else(|| String::from("unknown"))),
+            format!("clients: {}", clients),
+        ];
+
+        format!("info: {}\n{}\n", lines.len(), lines.join("\n"))
+    }
+}
+
+/// A field of `/proc/self/status`, only there on Linux.
+fn proc_status(field: &str) -> Option<String> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status.lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .map(|value| value.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// What `client list` shows about a connection.
+struct Client {
+    addr: SocketAddr,
+    connected: Instant,
+    last_active: Instant,
+    last_command: &'static str,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Handle to cut the connection through while its handler is busy reading.
+    socket: net::TcpStream,
+}
+
+/// Every open connection, for `client list` and `client kill`.
+#[derive(Default)]
+pub struct Clients {
+    clients: Mutex<HashMap<u64, Client>>,
+    next_id: AtomicU64,
+}
+
+impl Clients {
+    /// Lists `socket` until the returned guard is dropped.
+    pub fn register(self: &Arc<Self>, addr: SocketAddr, socket: net::TcpStream) -> Registered {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+
+        self.clients.lock().unwrap().insert(id, Client { addr, connected: now, last_active: now, last_command: "none", bytes_in: 0, bytes_out: 0, socket });
+
+        Registered { clients: Arc::clone(self), id }
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Reply to `client list`, a header with the number of clients followed by a line for each.
+    pub fn list(&self) -> String {
+        let clients = self.clients.lock().unwrap();
+        let mut clients = clients.values().collect::<Vec<_>>();
+        clients.sort_by_key(|client| client.connected);
+
+        let mut list = format!("clients: {}\n", clients.len());
+        for client in clients {
+            let _ = writeln!(list, "addr={} age={}s idle={}s cmd={} in={} out={}",
+                client.addr, client.connected.elapsed().as_secs(), client.last_active.elapsed().as_secs(),
+                client.last_command, client.bytes_in, client.bytes_out);
+        }
+        list
+    }
+
+    /// Closes the connection from `addr`, its handler sees it closed on the next read or write.
+    /// The id of the connection, to drop it if it is parked, or the error reply.
+    pub fn kill(&self, addr: &str) -> Result<u64, String> {
+        let Ok(addr) = addr.parse::<SocketAddr>() else {
+            return Err(format!("ERR invalid address {}\n", addr));
+        };
+
+        match self.clients.lock().unwrap().iter().find(|(_, client)| client.addr == addr) {
+            Some((id, client)) => {
+                info!("Killing client {}", addr);
+                let _ = client.socket.shutdown(net::Shutdown::Both);
+                Ok(*id)
+            },
+            None => Err(format!("ERR no client {}\n", addr)),
+        }
+    }
+}
+
+/// Place of a connection in the client list, given up once dropped.
+pub struct Registered {
+    clients: Arc<Clients>,
+    id: u64,
+}
+
+impl Registered {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Counts a request of `bytes_in` bytes answered with `bytes_out` bytes.
+    pub fn record(&self, command: &'static str, bytes_in: usize, bytes_out: usize) {
+        if let Some(client) = self.clients.clients.lock().unwrap().get_mut(&self.id) {
+            client.last_active = Instant::now();
+            client.last_command = command;
+            client.bytes_in += bytes_in as u64;
+            client.bytes_out += bytes_out as u64;
+        }
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.clients.clients.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Settings for `config get` and `config set`.
+///
+/// Most are fixed once the server started and only shown, `loglevel`, `max-connections` and `slowlog-threshold` can be changed.
+pub struct Config {
+    fixed: Vec<(&'static str, String)>,
+    connections: Arc<Connections>,
+    slowlog: Arc<Slowlog>,
+}
+
+impl Config {
+    pub fn new(fixed: Vec<(&'static str, String)>, connections: Arc<Connections>, slowlog: Arc<Slowlog>) -> Self {
+        Self { fixed, connections, slowlog }
+    }
+
+    /// Reply to `config get <name>`, `*` lists every setting.
+    pub fn get(&self, name: &str) -> String {
+        let settings = self.settings();
+        let found = settings.iter().filter(|(setting, _)| name == "*" || *setting == name).collect::<Vec<_>>();
+
+        if found.is_empty() {
+            return format!("ERR unknown setting {}\n", name);
+        }
+
+        let mut reply = format!("config: {}\n", found.len());
+        for (setting, value) in found {
+            let _ = writeln!(reply, "{} {}", setting, value);
+        }
+        reply
+    }
+
+    /// Reply to `config set <name> <value>`.
+    pub fn set(&self, name: &str, value: &str) -> String {
+        let res = match name {
+            "loglevel" => LevelFilter::from_str(value).map(logger::set_level).map_err(|_| format!("invalid level {}", value)),
+            "max-connections" => value.parse().map(|max| self.connections.set_max(max)).map_err(|_| format!("invalid number {}", value)),
+            "slowlog-threshold" => value.parse().map(|ms| self.slowlog.set_threshold(ms)).map_err(|_| format!("invalid number {}", value)),
+            name if self.fixed.iter().any(|(setting, _)| *setting == name) => Err(format!("{} cannot be changed while running", name)),
+            name => Err(format!("unknown setting {}", name)),
+        };
+
+        match res {
+            Ok(()) => {
+                info!("Set {} to {}", name, value);
+                format!("set: {} {}\n", name, value)
+            },
+            Err(err) => format!("ERR {}\n", err),
+        }
+    }
+
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        let mut settings = vec![
+            ("loglevel", logger::level().to_string().to_lowercase()),
+            ("max-connections", self.connections.max().to_string()),
+            ("slowlog-threshold", self.slowlog.threshold().to_string()),
+        ];
+        settings.extend(self.fixed.iter().cloned());
+        settings
+    }
+}
+
+/// A timeout as its variable takes it, in milliseconds with 0 for none.
+pub fn millis(timeout: Option<Duration>) -> String {
+    timeout.map_or(0, |timeout| timeout.as_millis()).to_string()
+}
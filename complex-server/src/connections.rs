@@ -0,0 +1,88 @@
+use std::io::{self, Write};
+use std::net::{self, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use rustls::ServerConfig;
+use socket2::{Domain, Socket, Type};
+
+use crate::settings;
+use crate::tls::Stream;
+
+pub static MAX_CONNECTIONS: usize = 1024;
+pub static LISTEN_BACKLOG: i32 = 128;
+/// How long a refused client gets to take the reply, the accept loop waits for it meanwhile.
+static REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Binds `addr` with room for `LISTEN_BACKLOG` connections the kernel keeps until they are accepted.
+pub fn listen(addr: SocketAddr) -> io::Result<net::TcpListener> {
+    let backlog = settings::parse("LISTEN_BACKLOG", LISTEN_BACKLOG);
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+
+    info!("Listening with a backlog of {}", backlog);
+
+    Ok(socket.into())
+}
+
+/// Gauge of the connections being served, at most `MAX_CONNECTIONS` of them at once.
+/// Connections waiting in the thread pool's queue count too, so that queue stays bounded.
+pub struct Connections {
+    current: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl Connections {
+    pub fn from_env() -> Arc<Self> {
+        let max = settings::parse("MAX_CONNECTIONS", MAX_CONNECTIONS);
+        info!("Serving up to {} connections", max);
+        Arc::new(Self { current: AtomicUsize::new(0), max: AtomicUsize::new(max) })
+    }
+
+    /// Counts a new connection in, None if all places are taken.
+    pub fn admit(self: &Arc<Self>) -> Option<Slot> {
+        self.current.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| (current < self.max()).then_some(current + 1)).ok()?;
+        Some(Slot { connections: Arc::clone(self) })
+    }
+
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    pub fn max(&self) -> usize {
+        self.max.load(Ordering::SeqCst)
+    }
+
+    /// Changes the limit, connections over a lowered one stay open until they close.
+    pub fn set_max(&self, max: usize) {
+        self.max.store(max, Ordering::SeqCst);
+    }
+}
+
+/// Place of one connection, given back once dropped.
+pub struct Slot {
+    connections: Arc<Connections>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.connections.current.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Tells a client there is no place for it before closing the connection.
+pub fn refuse(socket: net::TcpStream, tls: Option<&Arc<ServerConfig>>) -> io::Result<()> {
+    // a TLS client has to finish the handshake first, which must not hold up the accept loop for long
+    socket.set_read_timeout(Some(REFUSE_TIMEOUT))?;
+    socket.set_write_timeout(Some(REFUSE_TIMEOUT))?;
+
+    let mut stream = Stream::accept(socket, tls)?;
+    stream.handshake()?;
+    stream.write_all(b"ERR server-busy\n")?;
+    stream.shutdown()
+}
@@ -2,11 +2,32 @@ use std::net;
 use std::io;
 
 use std::collections::HashSet;
-use std::io::{BufRead, Write};
+use std::io::Write;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use rand::{self, RngCore};
-use log::info;
+use log::{info, warn, error};
+use rustls::ServerConfig;
+use tracing::Span;
+
+use crate::admin::{Clients, Config, Registered, Stats};
+use crate::connections::{Connections, Slot};
+use crate::logger;
+use crate::metrics::{Metrics, Open};
+use crate::persistence::{AppendLog, Entry};
+use crate::pubsub::{PubSub, Subscriber};
+use crate::ratelimit::{self, RateLimiter};
+use crate::session::{Session, Tokens};
+use crate::shutdown::Tracked;
+use crate::replication::Replication;
+use crate::slowlog::Slowlog;
+use crate::snapshot::Snapshotter;
+use crate::thread_pool::Spawner;
+use crate::timeouts::{self, Timeouts};
+use crate::tls::Stream;
+use crate::trace;
+use crate::waiters::{Comparison, Condition, Waiters};
 
 enum Command {
     Fortune,
@@ -14,6 +35,27 @@ enum Command {
     Counter,
     Upload(String),
     Download(String),
+    Save,
+    Sync(String, u64),
+    Subscribe(String),
+    Unsubscribe(Option<String>),
+    Publish(String, String),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<String>),
+    WaitCounter(String, Comparison, u64, Option<u64>),
+    WaitKey(String, Option<u64>),
+    Auth(String),
+    Connections,
+    Info,
+    Stats,
+    ClientList,
+    ClientKill(String),
+    ConfigGet(String),
+    ConfigSet(String, String),
+    SlowlogGet(Option<String>),
+    SlowlogReset,
     None
 }
 
@@ -23,6 +65,17 @@ impl Command {
             "fortune" => Command::Fortune,
             "increment" => Command::Increment,
             "counter" => Command::Counter,
+            "save" => Command::Save,
+            "unsubscribe" => Command::Unsubscribe(None),
+            "multi" => Command::Multi,
+            "exec" => Command::Exec,
+            "discard" => Command::Discard,
+            "connections" => Command::Connections,
+            "info" => Command::Info,
+            "stats" => Command::Stats,
+            "client list" => Command::ClientList,
+            "slowlog get" => Command::SlowlogGet(None),
+            "slowlog reset" => Command::SlowlogReset,
             other => {
                 if other.starts_with("upload") {
                     if let Some(split) = other.split_once(' ') {
@@ -36,10 +89,130 @@ impl Command {
                     }
                 }
 
+                if other.starts_with("subscribe") {
+                    if let Some(split) = other.split_once(' ') {
+                        return Command::Subscribe(String::from(split.1));
+                    }
+                }
+
+                if other.starts_with("unsubscribe") {
+                    if let Some(split) = other.split_once(' ') {
+                        return Command::Unsubscribe(Some(String::from(split.1)));
+                    }
+                }
+
+                if other.starts_with("publish") {
+                    if let Some((channel, message)) = other.split_once(' ').and_then(|split| split.1.split_once(' ')) {
+                        return Command::Publish(String::from(channel), String::from(message));
+                    }
+                }
+
+                if other.starts_with("watch") {
+                    if let Some(split) = other.split_once(' ') {
+                        return Command::Watch(split.1.split_whitespace().map(String::from).collect());
+                    }
+                }
+
+                if other.starts_with("wait-counter") {
+                    if let [_, name, comparison, value, timeout @ ..] = &other.split_whitespace().collect::<Vec<_>>()[..] {
+                        if let (Ok(comparison), Ok(value)) = (comparison.parse(), value.parse()) {
+                            return Command::WaitCounter(String::from(*name), comparison, value, timeout.first().and_then(|ms| ms.parse().ok()));
+                        }
+                    }
+                }
+
+                if other.starts_with("wait-key") {
+                    if let [_, key, timeout @ ..] = &other.split_whitespace().collect::<Vec<_>>()[..] {
+                        return Command::WaitKey(String::from(*key), timeout.first().and_then(|ms| ms.parse().ok()));
+                    }
+                }
+
+                if other.starts_with("auth") {
+                    if let Some(split) = other.split_once(' ') {
+                        return Command::Auth(String::from(split.1));
+                    }
+                }
+
+                if let Some(addr) = other.strip_prefix("client kill ") {
+                    return Command::ClientKill(String::from(addr));
+                }
+
+                if let Some(name) = other.strip_prefix("config get ") {
+                    return Command::ConfigGet(String::from(name));
+                }
+
+                if let Some((name, value)) = other.strip_prefix("config set ").and_then(|rest| rest.split_once(' ')) {
+                    return Command::ConfigSet(String::from(name), String::from(value));
+                }
+
+                if let Some(count) = other.strip_prefix("slowlog get ") {
+                    return Command::SlowlogGet(Some(String::from(count)));
+                }
+
+                if other.starts_with("sync") {
+                    if let Some((replid, offset)) = other.split_once(' ').and_then(|split| split.1.split_once(' ')) {
+                        return Command::Sync(String::from(replid), offset.parse().unwrap_or(0));
+                    }
+                }
+
                 Command::None
             }
         }
     }
+
+    /// What `stats` counts the command as.
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Fortune => "fortune",
+            Command::Increment => "increment",
+            Command::Counter => "counter",
+            Command::Upload(_) => "upload",
+            Command::Download(_) => "download",
+            Command::Save => "save",
+            Command::Sync(..) => "sync",
+            Command::Subscribe(_) => "subscribe",
+            Command::Unsubscribe(_) => "unsubscribe",
+            Command::Publish(..) => "publish",
+            Command::Multi => "multi",
+            Command::Exec => "exec",
+            Command::Discard => "discard",
+            Command::Watch(_) => "watch",
+            Command::WaitCounter(..) => "wait-counter",
+            Command::WaitKey(..) => "wait-key",
+            Command::Auth(_) => "auth",
+            Command::Connections => "connections",
+            Command::Info => "info",
+            Command::Stats => "stats",
+            Command::ClientList => "client-list",
+            Command::ClientKill(_) => "client-kill",
+            Command::ConfigGet(_) => "config-get",
+            Command::ConfigSet(..) => "config-set",
+            Command::SlowlogGet(_) => "slowlog-get",
+            Command::SlowlogReset => "slowlog-reset",
+            Command::None => "unknown",
+        }
+    }
+
+    /// Commands that can be queued in a transaction.
+    fn is_queueable(&self) -> bool {
+        matches!(self, Command::Fortune | Command::Increment | Command::Counter | Command::Upload(_) | Command::Download(_) | Command::None)
+    }
+}
+
+/// Per-connection `multi` queue and `watch`ed keys.
+///
+/// The key `counter` watches the counter, any other key watches the upload with that name.
+#[derive(Default)]
+struct Transaction {
+    queue: Option<Vec<Command>>,
+    watched: Vec<(String, u64)>,
+}
+
+fn watch_value(key: &str, counter: u64, uploads: &HashSet<String>) -> u64 {
+    match key {
+        "counter" => counter,
+        item => uploads.contains(item) as u64,
+    }
 }
 
 static FORTUNES: &[&str] = &[
@@ -52,56 +225,394 @@ static FORTUNES: &[&str] = &[
 type Counter = Arc<Mutex<u64>>;
 type Uploads = Arc<Mutex<HashSet<String>>>;
 
-pub fn handle(stream: net::TcpStream, counter: Counter, uploads: Uploads) -> io::Result<()> {
-    let (ip, port) = (
-        stream.peer_addr().unwrap().ip(),
-        stream.peer_addr().unwrap().port(),
-    );
+/// State shared by every connection.
+#[derive(Clone)]
+pub struct Shared {
+    pub counter: Counter,
+    pub uploads: Uploads,
+    pub log: Arc<AppendLog>,
+    pub snapshots: Arc<Snapshotter>,
+    pub replication: Arc<Replication>,
+    pub pubsub: Arc<PubSub>,
+    pub waiters: Arc<Waiters>,
+    pub spawner: Spawner,
+    pub limiter: Arc<Mutex<RateLimiter>>,
+    pub tokens: Arc<Tokens>,
+    pub tls: Option<Arc<ServerConfig>>,
+    pub connections: Arc<Connections>,
+    pub timeouts: Timeouts,
+    pub read_buffer: usize,
+    pub stats: Arc<Stats>,
+    pub metrics: Arc<Metrics>,
+    pub clients: Arc<Clients>,
+    pub config: Arc<Config>,
+    pub slowlog: Arc<Slowlog>,
+}
 
-    let mut reader = io::BufReader::new(&stream);
-    let mut writer = io::BufWriter::new(&stream);
-    let mut buf = String::new();
+/// Captures a copy of the state and hands it to the snapshotter.
+pub fn save(counter: &Counter, uploads: &Uploads, snapshots: &Snapshotter) -> bool {
+    // both at once, in the order exec takes them, so the snapshot never splits an exec
+    let (counter, uploads) = {
+        let counter = counter.lock().unwrap();
+        let uploads = uploads.lock().unwrap();
+        (*counter, uploads.clone())
+    };
+    snapshots.save(counter, uploads)
+}
+
+impl Shared {
+    /// Runs a data command against the already locked counter and uploads.
+    fn apply(&self, command: Command, counter: &mut u64, uploads: &mut HashSet<String>) -> String {
+        match command {
+            Command::Fortune => {
+                FORTUNES[rand::thread_rng().next_u32() as usize % FORTUNES.len()].to_string()
+            },
+            Command::Increment | Command::Upload(_) if self.replication.is_replica() => {
+                "ERR readonly replica\n".to_string()
+            },
+            Command::Increment => {
+                if let Err(err) = self.log.append(Entry::Increment) {
+                    return format!("ERR {}\n", err);
+                }
+                *counter += 1;
+                self.replication.propagate(String::from("increment"));
+                self.snapshots.touch();
+                self.waiters.notify(*counter, uploads);
+                "incremented\n".to_string()
+            },
+            Command::Counter => {
+                format!("counter: {}\n", counter)
+            },
+            Command::Upload(item) => {
+                if let Err(err) = self.log.append(Entry::Upload(item.clone())) {
+                    return format!("ERR {}\n", err);
+                }
+                uploads.insert(item.clone());
+                self.replication.propagate(format!("upload {}", item));
+                self.snapshots.touch();
+                self.waiters.notify(*counter, uploads);
+                "uploaded\n".to_string()
+            },
+            Command::Download(item) => {
+                let found = uploads.get(&item).cloned().unwrap_or_else(|| String::from("not found"));
+                format!("download: {}\n", found)
+            },
+            _ => {
+                "ok\n".to_string()
+            },
+        }
+    }
+}
+
+struct Connection {
+    ip: net::IpAddr,
+    port: u16,
+    reader: io::BufReader<Stream>,
+    writer: Subscriber,
+    /// Handle to set the timeouts through while the stream is busy reading.
+    socket: net::TcpStream,
+    transaction: Transaction,
+    session: Session,
+    /// Held while parked as well, a waiting client still takes up a place.
+    _slot: Slot,
+    /// Keeps shutdown waiting for the connection, parked ones included.
+    tracked: Tracked,
+    /// Entry in `client list`, through which `client kill` closes the socket.
+    registered: Registered,
+    _open: Open,
+    span: Span,
+}
+
+pub fn handle(socket: net::TcpStream, slot: Slot, tracked: Tracked, shared: Shared, span: Span) -> io::Result<()> {
+    let peer = socket.peer_addr()?;
+    socket.set_write_timeout(shared.timeouts.write)?;
+    let stream = Stream::accept(socket.try_clone()?, shared.tls.as_ref())?;
+    let registered = shared.clients.register(peer, socket.try_clone()?);
+    let _connection = logger::connection(registered.id());
+    span.record("conn", registered.id());
 
-    info!("Connection from {}:{}", ip, port);
+    logger::sampled!("Connection from {}:{} ({} open)", peer.ip(), peer.port(), shared.connections.current());
+
+    let conn = Connection {
+        ip: peer.ip(),
+        port: peer.port(),
+        writer: Arc::new(Mutex::new(io::BufWriter::new(stream.try_clone()?))),
+        reader: io::BufReader::with_capacity(shared.read_buffer, stream),
+        socket,
+        transaction: Transaction::default(),
+        session: Session::new(Arc::clone(&shared.tokens), peer),
+        _slot: slot,
+        tracked,
+        registered,
+        _open: shared.metrics.open(),
+        span,
+    };
+
+    serve(conn, shared)
+}
+
+/// Continues a connection that was parked by a wait command, `request` ends once the reply is out.
+fn resume(conn: Connection, response: String, request: Span, shared: Shared) -> io::Result<()> {
+    {
+        let _request = request.entered();
+        let mut writer = conn.writer.lock().unwrap();
+        writer.write_all(response.as_bytes())?;
+        writer.flush()?;
+    }
+
+    serve(conn, shared)
+}
+
+/// Parks the connection until `condition` holds, the worker is free to serve others meanwhile.
+fn park(conn: Connection, condition: Condition, timeout: Option<u64>, request: Span, shared: Shared) {
+    let waiters = Arc::clone(&shared.waiters);
+
+    waiters.park(conn.registered.id(), condition, timeout.map(Duration::from_millis), Box::new(move |response| {
+        let spawner = shared.spawner.clone();
+        let (ip, port) = (conn.ip, conn.port);
+        // whoever released the wait, the connection waits for a worker again as when it was accepted
+        let _connection = conn.span.clone().entered();
+        let resumed = spawner.execute(move || {
+            if let Some(err) = resume(conn, response, request, shared).err() {
+                error!("{}", err);
+            }
+        });
+
+        // the job dropped along with the connection closes it
+        if let Err(err) = resumed {
+            warn!("Closing {}:{}, {}", ip, port, err);
+        }
+    }));
+}
+
+/// Counts a request read at `started` towards the metrics, the client's entry in `client list` and the slow log.
+fn record(conn: &Connection, shared: &Shared, command: &'static str, request: &str, response: &str, started: Instant) {
+    shared.metrics.request(command, response, started.elapsed());
+    traffic(conn, shared, command, request, response, started);
+}
+
+/// Counts a request towards everything but the per command metrics, which a command queued by `multi` only adds to once `exec` runs it.
+fn traffic(conn: &Connection, shared: &Shared, command: &'static str, request: &str, response: &str, started: Instant) {
+    // the connection has its worker to itself, a request never waits for one
+    shared.slowlog.record(net::SocketAddr::new(conn.ip, conn.port), request, started, Duration::ZERO);
+    shared.metrics.received(request.len());
+    shared.metrics.sent(response.len());
+    conn.registered.record(command, request.len(), response.len());
+}
+
+fn serve(mut conn: Connection, shared: Shared) -> io::Result<()> {
+    let _connection = logger::connection(conn.registered.id());
+    let (ip, port) = (conn.ip, conn.port);
+    let mut buf = String::new();
 
     loop {
         buf.clear();
-        let len = reader.read_line(&mut buf)?;
+
+        // subscribers may wait for messages as long as they like
+        let timeouts = match shared.pubsub.is_subscribed(&conn.writer) {
+            true => Timeouts { idle: None, ..shared.timeouts },
+            false => shared.timeouts,
+        };
+
+        let len = match timeouts.read_line(&mut conn.reader, &conn.socket, &mut buf) {
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                warn!("Closing {}:{}, {}", ip, port, err);
+                break;
+            },
+            res => res?,
+        };
+
+        if len == 0 && conn.tracked.shutdown_requested() {
+            info!("Sending bye to {}:{}", ip, port);
+            let mut writer = conn.writer.lock().unwrap();
+            let _ = writer.write_all("bye\n".as_bytes()).and_then(|_| writer.flush()).and_then(|_| writer.get_mut().shutdown());
+            break;
+        }
 
         if len == 0 || buf == "done\n" {
             info!("Shutdown {}:{}", ip, port);
-            reader.into_inner().shutdown(net::Shutdown::Both)?;
+            conn.reader.get_mut().shutdown()?;
             break;
         }
 
-        match Command::parse(buf.trim_end()) {
-            Command::Fortune => {
-                writer.write_all(FORTUNES[rand::thread_rng().next_u32() as usize % FORTUNES.len()].as_bytes())?;
+        let started = Instant::now();
+        let command = Command::parse(buf.trim_end());
+        let name = command.name();
+        let request = trace::request(&conn.span, conn.registered.id(), name);
+        let _request = request.enter();
+
+        let limited = shared.limiter.lock().unwrap().check(net::SocketAddr::new(ip, port), &buf);
+        if let Err(wait) = limited {
+            let rejection = ratelimit::rejection(wait);
+            record(&conn, &shared, name, &buf, &rejection, started);
+            let mut writer = conn.writer.lock().unwrap();
+            writer.write_all(rejection.as_bytes())?;
+            writer.flush()?;
+            continue;
+        }
+
+        let mut queued = false;
+        let response = match (command, &mut conn.transaction.queue) {
+            (Command::Auth(token), _) => match conn.session.authenticate(&token) {
+                Ok(identity) => format!("authenticated: {}\n", identity),
+                Err(err) => format!("ERR {}\n", err),
             },
-            Command::Increment => {
-                let mut value = counter.lock().unwrap();
-                *value += 1;
-                writer.write_all("incremented\n".as_bytes())?;
+            (_, _) if conn.session.identity().is_none() => {
+                "ERR auth required\n".to_string()
             },
-            Command::Counter => {
-                let value = counter.lock().unwrap();
-                writer.write_all(format!("counter: {}\n", value).as_bytes())?;
+            (_, _) if !conn.session.permits(buf.trim_end()) => {
+                "ERR forbidden\n".to_string()
             },
-            Command::Upload(item) => {
-                uploads.lock().unwrap().insert(item);
-                writer.write_all("uploaded\n".as_bytes())?;
+            (command, Some(queue)) if command.is_queueable() => {
+                queue.push(command);
+                queued = true;
+                "queued\n".to_string()
             },
-            Command::Download(item) => {
-                let found = uploads.lock().unwrap().get(&item).cloned().unwrap_or_else(|| String::from("not found"));
-                writer.write_all(format!("download: {}\n", found).as_bytes())?;
+            (Command::Exec, Some(_)) => {
+                let queue = conn.transaction.queue.take().unwrap_or_default();
+                let watched = std::mem::take(&mut conn.transaction.watched);
+
+                let mut counter = shared.counter.lock().unwrap();
+                let mut uploads = shared.uploads.lock().unwrap();
+
+                if watched.iter().any(|(key, value)| watch_value(key, *counter, &uploads) != *value) {
+                    "exec: aborted\n".to_string()
+                } else {
+                    let mut response = format!("exec: {}\n", queue.len());
+                    for command in queue {
+                        let (name, started) = (command.name(), Instant::now());
+                        let reply = shared.apply(command, &mut counter, &mut uploads);
+                        shared.metrics.request(name, &reply, started.elapsed());
+                        response.push_str(&reply);
+                    }
+                    response
+                }
+            },
+            (Command::Discard, Some(_)) => {
+                conn.transaction = Transaction::default();
+                "discarded\n".to_string()
+            },
+            (_, Some(_)) => {
+                "ERR not allowed in multi\n".to_string()
+            },
+            (Command::Multi, None) => {
+                conn.transaction.queue = Some(Vec::new());
+                "multi: started\n".to_string()
+            },
+            (Command::Exec | Command::Discard, None) => {
+                "ERR no multi in progress\n".to_string()
+            },
+            (Command::Watch(keys), None) => {
+                let counter = shared.counter.lock().unwrap();
+                let uploads = shared.uploads.lock().unwrap();
+                for key in &keys {
+                    conn.transaction.watched.push((key.clone(), watch_value(key, *counter, &uploads)));
+                }
+                format!("watching: {}\n", keys.join(" "))
+            },
+            (Command::Connections, None) => {
+                format!("connections: {}/{}\n", shared.connections.current(), shared.connections.max())
+            },
+            (Command::Info, None) => {
+                let clients = shared.clients.len();
+                shared.stats.info(clients)
+            },
+            (Command::Stats, None) => {
+                shared.stats.report()
+            },
+            (Command::ClientList, None) => {
+                shared.clients.list()
+            },
+            (Command::ClientKill(addr), None) => match shared.clients.kill(&addr) {
+                Ok(id) => {
+                    // a parked connection reads nothing that would tell it
+                    shared.waiters.cancel(id);
+                    format!("killed: {}\n", addr)
+                },
+                Err(err) => err,
+            },
+            (Command::ConfigGet(name), None) => {
+                shared.config.get(&name)
+            },
+            (Command::ConfigSet(name, value), None) => {
+                shared.config.set(&name, &value)
+            },
+            (Command::SlowlogGet(count), None) => {
+                shared.slowlog.get(count)
+            },
+            (Command::SlowlogReset, None) => {
+                shared.slowlog.reset()
             },
-            Command::None => {
-                writer.write_all("ok\n".as_bytes())?;
+            (Command::Save, None) => {
+                if save(&shared.counter, &shared.uploads, &shared.snapshots) { "saving\n" } else { "already saving\n" }.to_string()
             },
+            (Command::Sync(_, _), None) if shared.replication.is_replica() => {
+                "ERR not a primary\n".to_string()
+            },
+            (Command::Sync(replid, offset), None) => {
+                info!("Replica {}:{} attached", ip, port);
+                record(&conn, &shared, name, &buf, "", started);
+                shared.replication.attach(conn.reader.get_ref().try_clone()?, &replid, offset, &shared.counter, &shared.uploads);
+                break;
+            },
+            (Command::Subscribe(channel), None) => match shared.pubsub.subscribe(&channel, &conn.writer, &conn.socket) {
+                Ok(()) => format!("subscribed: {}\n", channel),
+                Err(err) => format!("ERR {}\n", err),
+            },
+            (Command::Unsubscribe(channel), None) => {
+                shared.pubsub.unsubscribe(channel.as_deref(), &conn.writer);
+                format!("unsubscribed: {}\n", channel.as_deref().unwrap_or("all"))
+            },
+            (Command::Publish(channel, message), None) => {
+                format!("published: {}\n", shared.pubsub.publish(&channel, &message))
+            },
+            (Command::WaitCounter(name, _, _, _), None) if name != "counter" => {
+                format!("ERR unknown counter {}\n", name)
+            },
+            (command @ (Command::WaitCounter(..) | Command::WaitKey(..)), None) => {
+                let (condition, timeout) = match command {
+                    Command::WaitCounter(_, comparison, value, timeout) => (Condition::Counter(comparison, value), timeout),
+                    Command::WaitKey(key, timeout) => (Condition::Key(key), timeout),
+                    _ => unreachable!(),
+                };
+
+                let counter = shared.counter.lock().unwrap();
+                let uploads = shared.uploads.lock().unwrap();
+
+                if condition.holds(*counter, &uploads) {
+                    condition.reply(*counter)
+                } else {
+                    // the reply comes later and is not counted as traffic
+                    record(&conn, &shared, name, &buf, "", started);
+                    park(conn, condition, timeout, request.clone(), shared.clone());
+                    return Ok(());
+                }
+            },
+            (command, None) => {
+                let mut counter = shared.counter.lock().unwrap();
+                let mut uploads = shared.uploads.lock().unwrap();
+                shared.apply(command, &mut counter, &mut uploads)
+            },
+        };
+
+        if queued {
+            traffic(&conn, &shared, name, &buf, &response, started);
+        } else {
+            record(&conn, &shared, name, &buf, &response, started);
         }
 
-        writer.flush()?;
+        let mut writer = conn.writer.lock().unwrap();
+        if let Err(err) = writer.write_all(response.as_bytes()).and_then(|_| writer.flush()) {
+            if !timeouts::is_timeout(&err) {
+                return Err(err);
+            }
+            warn!("Closing {}:{}, the reply was not taken within {:?}", ip, port, shared.timeouts.write.unwrap_or_default());
+            break;
+        }
     }
 
+    shared.pubsub.unsubscribe(None, &conn.writer);
+    shared.limiter.lock().unwrap().forget(net::SocketAddr::new(ip, port));
+
     Ok(())
 }
@@ -1,42 +1,178 @@
+mod admin;
+mod connections;
 mod handler;
 mod logger;
+mod metrics;
+mod persistence;
+mod pubsub;
+mod ratelimit;
+mod replication;
+mod roles;
+mod session;
+mod settings;
+mod shutdown;
+mod slowlog;
+mod snapshot;
 mod thread_pool;
+mod timeouts;
+mod tls;
+mod trace;
+mod waiters;
 
 use std::io;
-use std::net;
+use std::thread;
+use std::time::Duration;
 
-use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 
 use log::{info, warn, error};
 
-static PORT: i32 = 3000;
-static THREADS: i32 = 4;
+use crate::admin::{Clients, Config, Stats};
+use crate::connections::Connections;
+use crate::handler::Shared;
+use crate::metrics::Metrics;
+use crate::persistence::{AppendLog, Fsync};
+use crate::pubsub::PubSub;
+use crate::ratelimit::RateLimiter;
+use crate::replication::Replication;
+use crate::session::Tokens;
+use crate::shutdown::Shutdown;
+use crate::slowlog::Slowlog;
+use crate::snapshot::Snapshotter;
+use crate::timeouts::Timeouts;
+use crate::waiters::Waiters;
+
+pub static PORT: u16 = 3000;
+pub static THREADS: i32 = 4;
+pub static READ_BUFFER: usize = 8 * 1024;
+static LOG_PATH: &str = "appendonly.log";
+static SNAPSHOT_PATH: &str = "dump.snap";
+static SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
 
 fn main() -> io::Result<()> {
+    settings::load();
     logger::setup().expect("Could not start logger");
+    let _trace = trace::setup().expect("Could not start tracing");
+
+    let threads = settings::parse("THREADS", THREADS).max(1);
+    let thread_pool = thread_pool::ThreadPool::new(threads);
+
+    info!("Created thread pool with {} threads", threads);
 
-    let thread_pool = thread_pool::ThreadPool::new(THREADS);
+    let port = settings::parse("PORT", PORT);
+    let addr = settings::bind(port)?;
+    let listener = connections::listen(addr)?;
+    let shutdown = Shutdown::install(addr)?;
 
-    info!("Created thread pool with {} threads", THREADS);
+    info!("Server started on port {}", port);
 
-    let listener = net::TcpListener::bind(format!("127.0.0.1:{}", PORT))?;
+    let fsync = Fsync::from_env();
+    let (log, mut counter, mut uploads) = AppendLog::open(LOG_PATH, fsync)?;
+    let snapshots = Snapshotter::new(SNAPSHOT_PATH, SNAPSHOT_INTERVAL);
 
-    info!("Server started on port {}", PORT);
+    if counter == 0 && uploads.is_empty() {
+        if let Some((saved_counter, saved_uploads)) = snapshots.load()? {
+            log.seed(saved_counter, saved_uploads.clone());
+            (counter, uploads) = (saved_counter, saved_uploads);
+        }
+    }
 
-    let counter = Arc::new(Mutex::new(0u64));
-    let uploads = Arc::new(Mutex::new(HashSet::new()));
+    let replica_of = settings::var("REPLICA_OF").ok();
+    let read_buffer = settings::parse("READ_BUFFER", READ_BUFFER).max(1);
+    let tls = tls::from_env()?;
+    let connections = Connections::from_env();
+    let timeouts = Timeouts::from_env();
+    let slowlog = Slowlog::from_env();
+
+    let config = Config::new(vec![
+        ("bind", addr.ip().to_string()),
+        ("port", port.to_string()),
+        ("threads", threads.to_string()),
+        ("read-buffer", read_buffer.to_string()),
+        ("idle-timeout", admin::millis(timeouts.idle)),
+        ("read-timeout", admin::millis(timeouts.read)),
+        ("write-timeout", admin::millis(timeouts.write)),
+        ("appendfsync", fsync.to_string()),
+        ("tls", if tls.is_some() { "on" } else { "off" }.to_string()),
+        ("replica-of", replica_of.clone().unwrap_or_default()),
+        ("slowlog-max-len", slowlog.capacity().to_string()),
+    ], Arc::clone(&connections), Arc::clone(&slowlog));
+
+    let metrics = Arc::new(Metrics::default());
+    metrics::serve(Arc::clone(&metrics), thread_pool.spawner())?;
+
+    let shared = Shared {
+        counter: Arc::new(Mutex::new(counter)),
+        uploads: Arc::new(Mutex::new(uploads)),
+        log: Arc::new(log),
+        snapshots: Arc::new(snapshots),
+        replication: Arc::new(if replica_of.is_some() { Replication::replica() } else { Replication::primary() }),
+        pubsub: Arc::new(PubSub::default()),
+        waiters: Waiters::new(),
+        spawner: thread_pool.spawner(),
+        limiter: Arc::new(Mutex::new(RateLimiter::from_env())),
+        tokens: Arc::new(Tokens::from_env()?),
+        tls,
+        connections,
+        timeouts,
+        read_buffer,
+        stats: Arc::new(Stats::new(Arc::clone(&metrics))),
+        metrics,
+        clients: Arc::new(Clients::default()),
+        config: Arc::new(config),
+        slowlog,
+    };
+
+    if let Some(primary) = replica_of {
+        info!("Running as replica of {}", primary);
+        Arc::clone(&shared.replication).follow(primary, Arc::clone(&shared.counter), Arc::clone(&shared.uploads), Arc::clone(&shared.log), Arc::clone(&shared.waiters));
+    }
+
+    {
+        let shared = shared.clone();
+        thread::spawn(move || loop {
+            thread::sleep(shared.snapshots.next_check());
+            if shared.snapshots.due() {
+                handler::save(&shared.counter, &shared.uploads, &shared.snapshots);
+            }
+        });
+    }
 
     for connection in listener.incoming() {
+        if shutdown.requested() {
+            break;
+        }
+
         match connection {
             Ok(stream) => {
-                let counter_clone = Arc::clone(&counter);
-                let uploads_clone = Arc::clone(&uploads);
-                thread_pool.execute(|| {
-                    if let Some(err) = handler::handle(stream, counter_clone, uploads_clone).err() {
+                let Some(slot) = shared.connections.admit() else {
+                    warn!("Refusing a connection, {} are open", shared.connections.current());
+                    if let Err(err) = connections::refuse(stream, shared.tls.as_ref()) {
+                        warn!("Could not refuse: {}", err);
+                    }
+                    continue;
+                };
+
+                let tracked = match shutdown.track(&stream) {
+                    Ok(tracked) => tracked,
+                    Err(err) => {
+                        warn!("Bad connection: {}", err);
+                        continue;
+                    },
+                };
+
+                let shared = shared.clone();
+                // the wait for a worker is part of the connection
+                let span = trace::connection();
+                let _connection = span.clone().entered();
+                let submitted = thread_pool.execute(|| {
+                    if let Some(err) = handler::handle(stream, slot, tracked, shared, span).err() {
                         error!("{}", err);
                     };
                 });
+                if let Err(err) = submitted {
+                    error!("Dropping a connection: {}", err);
+                }
             },
 
             Err(err) => {
@@ -45,5 +181,14 @@ fn main() -> io::Result<()> {
         }
     }
 
+    // parked connections are not reading, they learn of the shutdown once their wait is answered
+    shared.waiters.close();
+    shutdown.drain();
+    // the workers finish whatever the drain cut off before the log is closed behind them
+    drop(thread_pool);
+    shared.log.close();
+    info!("Server stopped");
+    logger::flush();
+
     Ok(())
 }
@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter;
+use std::net;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::logger;
+use crate::settings;
+use crate::thread_pool::Spawner;
+
+pub static METRICS_PORT: u16 = 9100;
+/// How long a scraper gets to send its request and take the reply, the listener serves one at a time.
+static SCRAPE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Bytes the request line and headers of a scrape may take together.
+static MAX_REQUEST: u64 = 8 * 1024;
+/// Upper bounds of the request duration buckets, in seconds.
+static DURATION_BUCKETS: [f64; 11] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+/// Observations counted by the smallest bucket bound they fit under.
+struct Histogram {
+    bounds: &'static [f64],
+    /// One more than there are bounds, the last one is `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self { bounds, counts: vec![0; bounds.len() + 1], sum: 0.0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    /// Writes the `_bucket`, `_sum` and `_count` series, the buckets cumulative as Prometheus wants them.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let bounds = self.bounds.iter().map(|bound| bound.to_string()).chain(iter::once(String::from("+Inf")));
+        let mut cumulative = 0;
+        for (bound, count) in bounds.zip(&self.counts) {
+            cumulative += count;
+            let le = format!("le=\"{}\"", bound);
+            let _ = writeln!(out, "{}_bucket{} {}", name, series(&[labels, &le]), cumulative);
+        }
+
+        let _ = writeln!(out, "{}_sum{} {}", name, series(&[labels]), self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, series(&[labels]), cumulative);
+    }
+}
+
+/// Label set of a series, nothing at all if there are no labels.
+fn series(labels: &[&str]) -> String {
+    let labels = labels.iter().filter(|label| !label.is_empty()).copied().collect::<Vec<_>>();
+    match labels.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", labels.join(",")),
+    }
+}
+
+/// Requests of one command.
+struct Requests {
+    calls: u64,
+    /// Replies starting with `ERR`.
+    errors: u64,
+    duration: Histogram,
+}
+
+/// Counters behind `/metrics`, `stats` reads the per command ones too.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<&'static str, Requests>>,
+    accepted: AtomicU64,
+    closed: AtomicU64,
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts a request answered with `response`, `elapsed` after it was read.
+    pub fn request(&self, command: &'static str, response: &str, elapsed: Duration) {
+        let mut requests = self.requests.lock().unwrap();
+        let requests = requests.entry(command).or_insert_with(|| Requests { calls: 0, errors: 0, duration: Histogram::new(&DURATION_BUCKETS) });
+
+        requests.calls += 1;
+        if response.starts_with("ERR") {
+            requests.errors += 1;
+        }
+        requests.duration.observe(elapsed.as_secs_f64());
+    }
+
+    /// Calls and errors by command name.
+    pub fn requests(&self) -> Vec<(&'static str, u64, u64)> {
+        self.requests.lock().unwrap().iter().map(|(command, requests)| (*command, requests.calls, requests.errors)).collect()
+    }
+
+    /// Counts a connection as active until the returned guard is dropped.
+    pub fn open(self: &Arc<Self>) -> Open {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        Open { metrics: Arc::clone(self) }
+    }
+
+    pub fn received(&self, len: usize) {
+        self.received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, len: usize) {
+        self.sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// The Prometheus text format of everything, with the thread pool's gauges read from `pool`.
+    fn render(&self, pool: &Spawner) -> String {
+        let mut out = String::new();
+
+        {
+            let requests = self.requests.lock().unwrap();
+
+            describe(&mut out, "server_requests_total", "counter", "Requests served, by command.");
+            for (command, requests) in requests.iter() {
+                let _ = writeln!(out, "server_requests_total{{command=\"{}\"}} {}", command, requests.calls);
+            }
+
+            describe(&mut out, "server_request_errors_total", "counter", "Requests answered with an error, by command.");
+            for (command, requests) in requests.iter() {
+                let _ = writeln!(out, "server_request_errors_total{{command=\"{}\"}} {}", command, requests.errors);
+            }
+
+            describe(&mut out, "server_request_duration_seconds", "histogram", "Time from reading a request to having its reply, by command.");
+            for (command, requests) in requests.iter() {
+                requests.duration.render(&mut out, "server_request_duration_seconds", &format!("command=\"{}\"", command));
+            }
+        }
+
+        // closed first, so a connection opened in between can not make active negative
+        let closed = self.closed.load(Ordering::Relaxed);
+        let accepted = self.accepted.load(Ordering::Relaxed);
+
+        metric(&mut out, "server_connections_active", "gauge", "Connections open right now.", accepted.saturating_sub(closed));
+        metric(&mut out, "server_connections_accepted_total", "counter", "Connections accepted and served.", accepted);
+        metric(&mut out, "server_connections_closed_total", "counter", "Connections closed.", closed);
+        metric(&mut out, "server_bytes_received_total", "counter", "Bytes of requests read.", self.received.load(Ordering::Relaxed));
+        metric(&mut out, "server_bytes_sent_total", "counter", "Bytes of replies written.", self.sent.load(Ordering::Relaxed));
+        metric(&mut out, "server_pool_queued_jobs", "gauge", "Jobs waiting for a worker of the thread pool.", pool.queued());
+        metric(&mut out, "server_pool_busy_workers", "gauge", "Workers of the thread pool running a job.", pool.busy());
+        metric(&mut out, "server_log_dropped_total", "counter", "Log records dropped for want of room in the buffer.", logger::dropped());
+
+        out
+    }
+}
+
+/// Keeps a connection counted as active, counts it closed once dropped.
+pub struct Open {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        self.metrics.closed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl fmt::Display) {
+    describe(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Serves `/metrics` on `METRICS_PORT` from a thread of its own, 0 turns it off.
+pub fn serve(metrics: Arc<Metrics>, pool: Spawner) -> io::Result<()> {
+    let port = settings::var("METRICS_PORT").ok().and_then(|port| port.parse().ok()).unwrap_or(METRICS_PORT);
+    if port == 0 {
+        info!("Metrics are off");
+        return Ok(());
+    }
+
+    let listener = net::TcpListener::bind(settings::bind(port)?)?;
+    info!("Serving metrics on port {}", port);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(err) = stream.and_then(|stream| scrape(stream, &metrics, &pool)) {
+                warn!("Bad metrics request: {}", err);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Answers one HTTP request, `GET /metrics` with the metrics and anything else with a 404.
+fn scrape(stream: net::TcpStream, metrics: &Metrics, pool: &Spawner) -> io::Result<()> {
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+
+    // scrapes are served one at a time, so a slow or endless request must not hold up the next
+    let deadline = Deadline { stream: &stream, at: Instant::now() + SCRAPE_TIMEOUT };
+    let mut reader = BufReader::new(deadline.take(MAX_REQUEST));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    // the headers say nothing of interest, but closing with them unread would reset the connection
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", metrics.render(pool)),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+
+    write!(&stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body)?;
+
+    Ok(())
+}
+
+/// Reads of a stream that time out once `at` has passed, however many came before.
+struct Deadline<'a> {
+    stream: &'a net::TcpStream,
+    at: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.at.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request took too long"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
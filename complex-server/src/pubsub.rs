@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net;
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::warn;
+
+use crate::tls::Stream;
+
+/// Messages a subscriber may fall behind by before it is disconnected.
+pub static MAILBOX: usize = 1024;
+
+/// Write half of a connection, shared between its own handler and publishers.
+pub type Subscriber = Arc<Mutex<io::BufWriter<Stream>>>;
+
+/// Messages on their way to one subscriber, written by a thread of its own so a slow one holds up no publisher.
+struct Mailbox {
+    subscriber: Subscriber,
+    sender: mpsc::SyncSender<String>,
+    /// Shut down once the subscriber falls behind, which ends the read of its handler.
+    socket: net::TcpStream,
+}
+
+impl Mailbox {
+    fn open(subscriber: &Subscriber, socket: &net::TcpStream) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<String>(MAILBOX);
+        let (writer, closer) = (Arc::clone(subscriber), socket.try_clone()?);
+
+        // ends once the last channel let go of the mailbox and everything queued is written
+        thread::spawn(move || {
+            for line in receiver {
+                let mut writer = writer.lock().unwrap();
+                if let Err(err) = writer.write_all(line.as_bytes()).and_then(|_| writer.flush()) {
+                    warn!("Could not push to subscriber: {}", err);
+                    let _ = closer.shutdown(net::Shutdown::Both);
+                    break;
+                }
+            }
+        });
+
+        Ok(Self { subscriber: Arc::clone(subscriber), sender, socket: socket.try_clone()? })
+    }
+}
+
+#[derive(Default)]
+pub struct PubSub {
+    channels: Mutex<HashMap<String, Vec<Arc<Mailbox>>>>,
+}
+
+impl PubSub {
+    /// Adds the subscriber to `channel`, `socket` is the connection it is disconnected through if it falls behind.
+    pub fn subscribe(&self, channel: &str, subscriber: &Subscriber, socket: &net::TcpStream) -> io::Result<()> {
+        let mut channels = self.channels.lock().unwrap();
+
+        // one mailbox per subscriber, whatever channels it is on
+        let existing = channels.values().flatten().find(|mailbox| Arc::ptr_eq(&mailbox.subscriber, subscriber)).cloned();
+        let mailboxes = channels.entry(String::from(channel)).or_default();
+
+        if !mailboxes.iter().any(|mailbox| Arc::ptr_eq(&mailbox.subscriber, subscriber)) {
+            let mailbox = match existing {
+                Some(mailbox) => mailbox,
+                None => Arc::new(Mailbox::open(subscriber, socket)?),
+            };
+            mailboxes.push(mailbox);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the subscriber from `channel`, or from every channel when `None`.
+    pub fn unsubscribe(&self, channel: Option<&str>, subscriber: &Subscriber) {
+        let mut channels = self.channels.lock().unwrap();
+
+        channels.retain(|name, mailboxes| {
+            if channel.is_none_or(|channel| channel == name) {
+                mailboxes.retain(|mailbox| !Arc::ptr_eq(&mailbox.subscriber, subscriber));
+            }
+            !mailboxes.is_empty()
+        });
+    }
+
+    pub fn is_subscribed(&self, subscriber: &Subscriber) -> bool {
+        self.channels.lock().unwrap().values().flatten().any(|mailbox| Arc::ptr_eq(&mailbox.subscriber, subscriber))
+    }
+
+    /// Queues the message for every subscriber of `channel`, returns how many of them it was queued for.
+    /// Subscribers with a full mailbox are dropped and disconnected.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let mailboxes = match self.channels.lock().unwrap().get(channel) {
+            Some(mailboxes) => mailboxes.clone(),
+            None => return 0,
+        };
+
+        let line = format!("message: {} {}\n", channel, message);
+
+        mailboxes.iter().filter(|mailbox| match mailbox.sender.try_send(line.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("Dropping subscriber {}, {} messages behind", mailbox.socket.peer_addr().map(|addr| addr.to_string()).unwrap_or_default(), MAILBOX);
+                self.unsubscribe(None, &mailbox.subscriber);
+                let _ = mailbox.socket.shutdown(net::Shutdown::Both);
+                false
+            },
+            // the writer failed and already closed the connection
+            Err(TrySendError::Disconnected(_)) => false,
+        }).count()
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use rand::{self, RngCore};
+
+use crate::persistence::{AppendLog, Entry};
+use crate::settings;
+use crate::tls::{self, Stream};
+use crate::waiters::Waiters;
+
+static BACKLOG_SIZE: usize = 10_000;
+/// Mutations a replica may fall behind before it is disconnected, to resync once it reconnects.
+static FEED_SIZE: usize = 1_000;
+static RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+type Counter = Arc<Mutex<u64>>;
+type Uploads = Arc<Mutex<HashSet<String>>>;
+
+/// Replication stream position: the id of the primary's history and the
+/// number of mutations applied from it.
+struct Backlog {
+    replid: String,
+    offset: u64,
+    entries: VecDeque<String>,
+    feeds: Vec<mpsc::SyncSender<String>>,
+}
+
+impl Backlog {
+    fn first_offset(&self) -> u64 {
+        self.offset - self.entries.len() as u64
+    }
+
+    fn push(&mut self, line: String) {
+        self.offset += 1;
+        if self.entries.len() == BACKLOG_SIZE {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(line);
+    }
+}
+
+pub struct Replication {
+    read_only: bool,
+    backlog: Mutex<Backlog>,
+}
+
+impl Replication {
+    pub fn primary() -> Self {
+        let replid = format!("{:016x}", rand::thread_rng().next_u64());
+        info!("Running as primary with replication id {}", replid);
+        Self::new(replid, false)
+    }
+
+    pub fn replica() -> Self {
+        Self::new(String::from("?"), true)
+    }
+
+    fn new(replid: String, read_only: bool) -> Self {
+        Self {
+            read_only,
+            backlog: Mutex::new(Backlog { replid, offset: 0, entries: VecDeque::new(), feeds: Vec::new() }),
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.read_only
+    }
+
+    /// Records a mutation and forwards it to the connected replicas.
+    /// Must be called while holding the lock of the state it mutated.
+    pub fn propagate(&self, line: String) {
+        let mut backlog = self.backlog.lock().unwrap();
+
+        backlog.push(line.clone());
+        backlog.feeds.retain(|feed| match feed.try_send(line.clone()) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(_)) => {
+                warn!("Replica fell {} mutations behind, disconnecting it", FEED_SIZE);
+                false
+            },
+            Err(mpsc::TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    /// Turns a connection that sent `sync <replid> <offset>` into a replica feed.
+    pub fn attach(&self, stream: Stream, replid: &str, offset: u64, counter: &Counter, uploads: &Uploads) {
+        let (sender, receiver) = mpsc::sync_channel(FEED_SIZE);
+
+        let header = {
+            let counter = counter.lock().unwrap();
+            let uploads = uploads.lock().unwrap();
+            let mut backlog = self.backlog.lock().unwrap();
+
+            let mut lines = Vec::new();
+
+            if replid == backlog.replid && offset >= backlog.first_offset() && offset <= backlog.offset {
+                info!("Partial sync of replica from offset {}", offset);
+                lines.push(format!("continue {}", backlog.replid));
+                lines.extend(backlog.entries.iter().skip((offset - backlog.first_offset()) as usize).cloned());
+            } else {
+                info!("Full sync of replica at offset {}", backlog.offset);
+                lines.push(format!("fullsync {} {} {} {}", backlog.replid, backlog.offset, *counter, uploads.len()));
+                lines.extend(uploads.iter().cloned());
+            }
+
+            backlog.feeds.push(sender);
+            lines
+        };
+
+        thread::spawn(move || {
+            if let Err(err) = feed(stream, header, receiver) {
+                warn!("Replica disconnected: {}", err);
+            }
+        });
+    }
+
+    /// Keeps this replica following `primary`, reconnecting after failures.
+    pub fn follow(self: Arc<Self>, primary: String, counter: Counter, uploads: Uploads, log: Arc<AppendLog>, waiters: Arc<Waiters>) {
+        thread::spawn(move || loop {
+            if let Err(err) = self.sync(&primary, &counter, &uploads, &log, &waiters) {
+                warn!("Lost primary {}: {}", primary, err);
+            }
+            thread::sleep(RECONNECT_DELAY);
+        });
+    }
+
+    fn sync(&self, primary: &str, counter: &Counter, uploads: &Uploads, log: &AppendLog, waiters: &Waiters) -> io::Result<()> {
+        let mut reader = io::BufReader::new(tls::connect(primary)?);
+        let (mut header, mut line) = (String::new(), String::new());
+
+        // a primary that wants a token gets the one from `PRIMARY_TOKEN`
+        if let Ok(token) = settings::var("PRIMARY_TOKEN") {
+            writeln!(reader.get_mut(), "auth {}", token)?;
+            read_line(&mut reader, &mut line)?;
+            if !line.starts_with("authenticated") {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, line));
+            }
+        }
+
+        {
+            let backlog = self.backlog.lock().unwrap();
+            writeln!(reader.get_mut(), "sync {} {}", backlog.replid, backlog.offset)?;
+        }
+
+        read_line(&mut reader, &mut header)?;
+
+        match header.split(' ').collect::<Vec<_>>()[..] {
+            ["continue", replid] => {
+                info!("Continuing replication {} from {}", replid, primary);
+            },
+
+            ["fullsync", replid, offset, value, len] => {
+                let parse = |field: &str| field.parse::<u64>().map_err(|_| invalid(&header));
+                let (offset, value, len) = (parse(offset)?, parse(value)?, parse(len)?);
+
+                let mut items = HashSet::new();
+                for _ in 0..len {
+                    read_line(&mut reader, &mut line)?;
+                    items.insert(line.clone());
+                }
+
+                let mut counter = counter.lock().unwrap();
+                let mut uploads = uploads.lock().unwrap();
+                let mut backlog = self.backlog.lock().unwrap();
+
+                (*counter, *uploads) = (value, items);
+                (backlog.replid, backlog.offset) = (String::from(replid), offset);
+                backlog.entries.clear();
+                log.seed(*counter, uploads.clone());
+                waiters.notify(*counter, &uploads);
+
+                info!("Full sync from {} -> counter {}, {} uploads", primary, *counter, uploads.len());
+            },
+
+            _ => return Err(invalid(&header)),
+        }
+
+        loop {
+            read_line(&mut reader, &mut line)?;
+            self.apply(&line, counter, uploads, log, waiters)?;
+        }
+    }
+
+    fn apply(&self, line: &str, counter: &Counter, uploads: &Uploads, log: &AppendLog, waiters: &Waiters) -> io::Result<()> {
+        let mut counter = counter.lock().unwrap();
+        let mut uploads = uploads.lock().unwrap();
+
+        match line.split_once(' ') {
+            None if line == "increment" => {
+                log.append(Entry::Increment)?;
+                *counter += 1;
+            },
+            Some(("upload", item)) => {
+                log.append(Entry::Upload(String::from(item)))?;
+                uploads.insert(String::from(item));
+            },
+            _ => return Err(invalid(line)),
+        }
+
+        waiters.notify(*counter, &uploads);
+
+        self.backlog.lock().unwrap().push(String::from(line));
+
+        Ok(())
+    }
+}
+
+fn feed(stream: Stream, header: Vec<String>, receiver: mpsc::Receiver<String>) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(stream);
+
+    for line in header {
+        writeln!(writer, "{}", line)?;
+    }
+    writer.flush()?;
+
+    for line in receiver {
+        writeln!(writer, "{}", line)?;
+        writer.flush()?;
+    }
+
+    // cut off for falling behind, the replica syncs again from where it got to
+    writer.get_mut().shutdown()
+}
+
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<()> {
+    line.clear();
+    if reader.read_line(line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "primary closed the connection"));
+    }
+    line.truncate(line.trim_end().len());
+    Ok(())
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unexpected replication line: {}", line))
+}
@@ -0,0 +1,204 @@
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{info, warn};
+
+use crate::roles::Roles;
+use crate::settings;
+
+/// Tokens accepted by `auth`, read from the file named by `AUTH_TOKENS`.
+///
+/// Every line holds an identity, its token and optionally its role separated by whitespace, `#` starts a comment.
+/// The role defaults to `admin`. Without the variable no token is needed and every client is an `anonymous` admin.
+pub struct Tokens {
+    /// Identity, token and role.
+    entries: Option<Vec<(String, String, String)>>,
+    roles: Roles,
+}
+
+impl Tokens {
+    pub fn from_env() -> io::Result<Self> {
+        let roles = Roles::from_env()?;
+        let Ok(path) = settings::var("AUTH_TOKENS") else {
+            warn!("AUTH_TOKENS is not set, clients need no token");
+            return Ok(Self { entries: None, roles });
+        };
+
+        Ok(Self::parse(&fs::read_to_string(&path)?, &path, roles))
+    }
+
+    fn parse(content: &str, path: &str, roles: Roles) -> Self {
+        let mut entries = Vec::new();
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [] => {},
+                [identity, token, ref role @ ..] if role.len() <= 1 => {
+                    let role = role.first().copied().unwrap_or("admin");
+                    if roles.get(role).is_some() {
+                        entries.push((String::from(identity), String::from(token), String::from(role)));
+                    } else {
+                        warn!("Ignoring {} in {}, there is no role {}", identity, path, role);
+                    }
+                },
+                _ => warn!("Ignoring malformed line in {}: {}", path, line),
+            }
+        }
+
+        info!("Loaded {} tokens from {}", entries.len(), path);
+
+        Self { entries: Some(entries), roles }
+    }
+
+    /// Identity and role `token` belongs to. Every token is compared in full, so the time taken tells nothing about them.
+    fn identify(&self, token: &str) -> Option<(&str, &str)> {
+        self.entries.as_ref()?.iter().fold(None, |found, (identity, known, role)| {
+            if equal(known.as_bytes(), token.as_bytes()) { Some((identity.as_str(), role.as_str())) } else { found }
+        })
+    }
+}
+
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Per-connection state, for now where the client connected from and who it authenticated as.
+pub struct Session {
+    tokens: Arc<Tokens>,
+    peer: SocketAddr,
+    identity: Option<String>,
+    role: Option<String>,
+}
+
+impl Session {
+    pub fn new(tokens: Arc<Tokens>, peer: SocketAddr) -> Self {
+        let identity = tokens.entries.is_none().then(|| String::from("anonymous"));
+        Self { tokens, peer, identity, role: None }
+    }
+
+    /// None until the client authenticated.
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    /// Switches to the identity `token` belongs to, a wrong token leaves the session as it was.
+    pub fn authenticate(&mut self, token: &str) -> Result<&str, &'static str> {
+        if self.tokens.entries.is_none() {
+            return Err("auth is not enabled");
+        }
+
+        match self.tokens.identify(token) {
+            Some((identity, role)) => {
+                info!("{} authenticated as {} with role {}", self.peer, identity, role);
+                self.role = Some(String::from(role));
+                Ok(self.identity.insert(String::from(identity)))
+            },
+            None => {
+                warn!("{} failed to authenticate", self.peer);
+                Err("invalid token")
+            },
+        }
+    }
+
+    /// Whether the role of the client allows the request `message`, refusals are logged.
+    /// Clients are only ever refused once auth is enabled.
+    pub fn permits(&self, message: &str) -> bool {
+        if self.tokens.entries.is_none() {
+            return true;
+        }
+
+        let permitted = self.role.as_deref().and_then(|role| self.tokens.roles.get(role)).is_some_and(|role| role.permits(message));
+        if !permitted {
+            warn!("{} ({}) is forbidden to run: {}", self.peer, self.identity.as_deref().unwrap_or("unauthenticated"), message);
+        }
+
+        permitted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ROLES: &str = "
+reader fortune counter download
+writer fortune counter download increment upload
+admin *
+";
+
+    fn tokens(content: &str) -> Arc<Tokens> {
+        Arc::new(Tokens::parse(content, "test", Roles::parse(ROLES, "test")))
+    }
+
+    fn session(tokens: &Arc<Tokens>) -> Session {
+        Session::new(Arc::clone(tokens), SocketAddr::from(([127, 0, 0, 1], 1)))
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let tokens = tokens("
+# comment
+alice a-token reader # trailing comment
+bob
+carol c-token writer extra
+dave d-token nosuchrole
+erin e-token
+");
+
+        assert_eq!(tokens.identify("a-token"), Some(("alice", "reader")));
+        assert_eq!(tokens.identify("e-token"), Some(("erin", "admin")));
+        for token in ["bob", "c-token", "d-token", "comment", "#", ""] {
+            assert_eq!(tokens.identify(token), None, "{}", token);
+        }
+        assert_eq!(tokens.entries.as_ref().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn refuses_everything_until_authenticated() {
+        let tokens = tokens("alice a-token reader");
+        let mut session = session(&tokens);
+
+        assert_eq!(session.identity(), None);
+        assert!(!session.permits("fortune"));
+
+        assert_eq!(session.authenticate("a-toke"), Err("invalid token"));
+        assert_eq!(session.authenticate("a-token "), Err("invalid token"));
+        assert_eq!(session.identity(), None);
+        assert!(!session.permits("fortune"));
+
+        assert_eq!(session.authenticate("a-token"), Ok("alice"));
+        assert!(session.permits("fortune"));
+    }
+
+    #[test]
+    fn role_follows_the_latest_authentication() {
+        let tokens = tokens("alice a-token reader\nbob b-token writer");
+        let mut session = session(&tokens);
+
+        assert_eq!(session.authenticate("a-token"), Ok("alice"));
+        assert!(!session.permits("increment"));
+
+        assert_eq!(session.authenticate("b-token"), Ok("bob"));
+        assert!(session.permits("increment"));
+
+        // a failed attempt keeps the identity and role already held
+        assert_eq!(session.authenticate("x-token"), Err("invalid token"));
+        assert_eq!(session.identity(), Some("bob"));
+        assert!(session.permits("upload a"));
+
+        assert_eq!(session.authenticate("a-token"), Ok("alice"));
+        assert!(!session.permits("upload a"));
+    }
+
+    #[test]
+    fn anonymous_admin_without_tokens() {
+        let tokens = Arc::new(Tokens { entries: None, roles: Roles::parse(ROLES, "test") });
+        let mut session = session(&tokens);
+
+        assert_eq!(session.identity(), Some("anonymous"));
+        assert!(session.permits("client kill 127.0.0.1:1"));
+        assert_eq!(session.authenticate("a-token"), Err("auth is not enabled"));
+    }
+}
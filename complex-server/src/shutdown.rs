@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{self, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use crate::settings;
+
+pub static DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Stops the server on SIGINT or SIGTERM.
+///
+/// The signal does not interrupt a blocking `accept` or read, so a watcher thread
+/// shuts down the read half of every open connection, which then reads end of file
+/// once its current request is answered, and connects once to the listener to wake `accept`.
+pub struct Shutdown {
+    requested: AtomicBool,
+    sockets: Mutex<HashMap<u64, net::TcpStream>>,
+    closed: Condvar,
+    next_id: AtomicU64,
+    drain: Duration,
+}
+
+impl Shutdown {
+    /// Starts watching for the signals, `DRAIN_TIMEOUT` in milliseconds bounds the wait for open connections.
+    pub fn install(addr: SocketAddr) -> io::Result<Arc<Self>> {
+        let drain = settings::var("DRAIN_TIMEOUT").ok()
+            .and_then(|ms| ms.parse().ok())
+            .map_or(DRAIN_TIMEOUT, Duration::from_millis);
+
+        let shutdown = Arc::new(Self {
+            requested: AtomicBool::new(false),
+            sockets: Mutex::new(HashMap::new()),
+            closed: Condvar::new(),
+            next_id: AtomicU64::new(0),
+            drain,
+        });
+
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let watcher = Arc::clone(&shutdown);
+
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                info!("Received signal {}, shutting down", signal);
+                watcher.trigger(addr);
+            }
+        });
+
+        Ok(shutdown)
+    }
+
+    fn trigger(&self, addr: SocketAddr) {
+        self.requested.store(true, Ordering::SeqCst);
+
+        for socket in self.sockets.lock().unwrap().values() {
+            let _ = socket.shutdown(net::Shutdown::Read);
+        }
+
+        let _ = net::TcpStream::connect(addr);
+    }
+
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Counts `socket` as open until the returned guard is dropped.
+    pub fn track(self: &Arc<Self>, socket: &net::TcpStream) -> io::Result<Tracked> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.sockets.lock().unwrap().insert(id, socket.try_clone()?);
+
+        // The signal may have arrived before the socket was stored.
+        if self.requested() {
+            socket.shutdown(net::Shutdown::Read)?;
+        }
+
+        Ok(Tracked { shutdown: Arc::clone(self), id })
+    }
+
+    /// Waits for the open connections to close, cutting off those still open after the drain timeout.
+    pub fn drain(&self) {
+        let sockets = self.sockets.lock().unwrap();
+        info!("Draining {} connections for up to {:?}", sockets.len(), self.drain);
+
+        let (sockets, _) = self.closed.wait_timeout_while(sockets, self.drain, |sockets| !sockets.is_empty()).unwrap();
+
+        if !sockets.is_empty() {
+            warn!("Closing {} connections still open after {:?}", sockets.len(), self.drain);
+            for socket in sockets.values() {
+                let _ = socket.shutdown(net::Shutdown::Both);
+            }
+        }
+    }
+}
+
+pub struct Tracked {
+    shutdown: Arc<Shutdown>,
+    id: u64,
+}
+
+impl Tracked {
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown.requested()
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.shutdown.sockets.lock().unwrap().remove(&self.id);
+        self.shutdown.closed.notify_all();
+    }
+}
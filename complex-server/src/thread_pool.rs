@@ -1,30 +1,59 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 use log::info;
+use tracing::span::EnteredSpan;
+use tracing::{info_span, Span};
+
+use crate::logger;
 
 type Job = Box<dyn FnOnce() + Send>;
 
 enum Task {
-    New(Job),
+    New(Job, Queued),
     Exit,
 }
 
+/// Span a job was submitted in, and the span of its wait for a worker under it.
+struct Queued {
+    parent: Span,
+    span: Span,
+}
+
+impl Queued {
+    fn new() -> Self {
+        Self { parent: Span::current(), span: info_span!("queued") }
+    }
+
+    /// Ends the wait, the span returned covers the job on `worker` until dropped.
+    fn start(self, worker: i32) -> EnteredSpan {
+        let Queued { parent, span } = self;
+        drop(span);
+        info_span!(parent: &parent, "job", worker).entered()
+    }
+}
+
 struct Worker {
     id: i32,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(id: i32, receiver: Arc<Mutex<mpsc::Receiver<Task>>>) -> Self {
+    fn new(id: i32, receiver: Arc<Mutex<mpsc::Receiver<Task>>>, load: Load) -> Self {
         let thread = Some(thread::spawn(move || loop {
             let task = { receiver.lock().unwrap().recv().unwrap() };
 
             match task {
-                Task::New(job) => {
-                    info!("Worker {} received job", id);
+                Task::New(job, queued) => {
+                    logger::sampled!("Worker {} received job", id);
+                    let _job = queued.start(id);
+                    load.queued.fetch_sub(1, Ordering::Relaxed);
+                    load.busy.fetch_add(1, Ordering::Relaxed);
                     job();
+                    load.busy.fetch_sub(1, Ordering::Relaxed);
                 }
                 Task::Exit => break,
             }
@@ -34,32 +63,82 @@ impl Worker {
     }
 }
 
+/// Jobs waiting for a worker and workers running one, for `/metrics`.
+#[derive(Clone, Default)]
+struct Load {
+    queued: Arc<AtomicUsize>,
+    busy: Arc<AtomicUsize>,
+}
+
 pub struct ThreadPool {
     workers: Vec<Worker>,
     sender: mpsc::Sender<Task>,
+    load: Load,
 }
 
 impl ThreadPool {
     pub fn new(n: i32) -> Self {
         let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
+        let load = Load::default();
 
         let mut workers = Vec::with_capacity(n as usize);
         for i in 0..n {
-            workers.push(Worker::new(i, Arc::clone(&receiver)));
+            workers.push(Worker::new(i, Arc::clone(&receiver), load.clone()));
         }
 
-        ThreadPool { workers, sender }
+        ThreadPool { workers, sender, load }
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// Fails once every worker is gone, the job is dropped then.
+    pub fn execute<F>(&self, f: F) -> io::Result<()>
     where
         F: FnOnce() + 'static + Send,
     {
-        self.sender.send(Task::New(Box::new(f))).unwrap();
+        submit(&self.sender, &self.load, Box::new(f))
+    }
+
+    /// Handle for submitting jobs from outside the thread that owns the pool.
+    pub fn spawner(&self) -> Spawner {
+        Spawner { sender: self.sender.clone(), load: self.load.clone() }
     }
 }
 
+#[derive(Clone)]
+pub struct Spawner {
+    sender: mpsc::Sender<Task>,
+    load: Load,
+}
+
+impl Spawner {
+    /// Unlike the pool itself, a spawner may outlive the workers, its jobs then fail to be submitted and are dropped.
+    pub fn execute<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce() + 'static + Send,
+    {
+        submit(&self.sender, &self.load, Box::new(f))
+    }
+
+    /// Jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.load.queued.load(Ordering::Relaxed)
+    }
+
+    /// Workers running a job, each connection keeps one busy while it is served.
+    pub fn busy(&self) -> usize {
+        self.load.busy.load(Ordering::Relaxed)
+    }
+}
+
+fn submit(sender: &mpsc::Sender<Task>, load: &Load, job: Job) -> io::Result<()> {
+    load.queued.fetch_add(1, Ordering::Relaxed);
+    if sender.send(Task::New(job, Queued::new())).is_err() {
+        load.queued.fetch_sub(1, Ordering::Relaxed);
+        return Err(io::Error::other("thread pool is gone"));
+    }
+    Ok(())
+}
+
 impl Drop for ThreadPool {
     fn drop(&mut self) {
         for _ in &self.workers {
@@ -74,3 +153,28 @@ impl Drop for ThreadPool {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn refuses_jobs_once_the_workers_are_gone() {
+        let pool = ThreadPool::new(1);
+        let spawner = pool.spawner();
+
+        let (sender, ran) = mpsc::channel();
+        spawner.execute(move || sender.send(()).unwrap()).unwrap();
+        ran.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        drop(pool);
+        let (sender, ran) = mpsc::channel();
+        assert!(spawner.execute(move || sender.send(()).unwrap()).is_err());
+        // the job was dropped without running
+        assert_eq!(ran.recv(), Err(mpsc::RecvError));
+        assert_eq!(spawner.queued(), 0);
+    }
+}
@@ -0,0 +1,189 @@
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net;
+use std::sync::{Arc, Mutex};
+
+use log::info;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+use crate::settings;
+
+/// TLS settings from `TLS_CERT` and `TLS_KEY`, PEM files holding the certificate chain and its private key.
+/// With `TLS_CLIENT_CA` set too, clients must present a certificate signed by one of the CAs in that file.
+/// None if `TLS_CERT` is not set, connections stay plain TCP then.
+pub fn from_env() -> io::Result<Option<Arc<ServerConfig>>> {
+    let Ok(cert) = settings::var("TLS_CERT") else {
+        return Ok(None);
+    };
+    let key = settings::var("TLS_KEY").map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "TLS_CERT is set without TLS_KEY"))?;
+
+    let chain = CertificateDer::pem_file_iter(&cert).map_err(invalid)?.collect::<Result<Vec<_>, _>>().map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_file(&key).map_err(invalid)?;
+
+    let builder = ServerConfig::builder();
+    let builder = match settings::var("TLS_CLIENT_CA") {
+        Ok(path) => {
+            info!("Requiring client certificates signed by {}", path);
+            builder.with_client_cert_verifier(WebPkiClientVerifier::builder(roots(&path)?).build().map_err(invalid)?)
+        },
+        Err(_) => builder.with_no_client_auth(),
+    };
+
+    info!("Serving TLS with certificate {}", cert);
+
+    Ok(Some(Arc::new(builder.with_single_cert(chain, key).map_err(invalid)?)))
+}
+
+fn roots(path: &str) -> io::Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for ca in CertificateDer::pem_file_iter(path).map_err(invalid)? {
+        roots.add(ca.map_err(invalid)?).map_err(invalid)?;
+    }
+    Ok(Arc::new(roots))
+}
+
+fn invalid(err: impl Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Either end of a connection to the primary.
+pub trait Duplex: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Duplex for T {}
+
+/// Connects to `addr`, over TLS if `PRIMARY_CA` names the CAs to check the primary's certificate against.
+pub fn connect(addr: &str) -> io::Result<Box<dyn Duplex>> {
+    let socket = net::TcpStream::connect(addr)?;
+    let Ok(path) = settings::var("PRIMARY_CA") else {
+        return Ok(Box::new(socket));
+    };
+
+    let config = ClientConfig::builder().with_root_certificates(roots(&path)?).with_no_client_auth();
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let name = ServerName::try_from(host.to_string()).map_err(invalid)?;
+
+    Ok(Box::new(StreamOwned::new(ClientConnection::new(Arc::new(config), name).map_err(invalid)?, socket)))
+}
+
+/// Connection of a client, encrypted if the server has TLS set up.
+///
+/// Clones share the TLS session, so that one thread can wait for the next request
+/// while others push messages. Only the socket is waited on without holding the session.
+pub enum Stream {
+    Plain(net::TcpStream),
+    Tls(Arc<Mutex<ServerConnection>>, net::TcpStream),
+}
+
+impl Stream {
+    /// The handshake runs along with the first read.
+    pub fn accept(socket: net::TcpStream, config: Option<&Arc<ServerConfig>>) -> io::Result<Self> {
+        Ok(match config {
+            Some(config) => Stream::Tls(Arc::new(Mutex::new(ServerConnection::new(Arc::clone(config)).map_err(invalid)?)), socket),
+            None => Stream::Plain(socket),
+        })
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Stream::Plain(socket) => Stream::Plain(socket.try_clone()?),
+            Stream::Tls(conn, socket) => Stream::Tls(Arc::clone(conn), socket.try_clone()?),
+        })
+    }
+
+    /// Runs the handshake now, for a stream that is written to before it is read from.
+    pub fn handshake(&mut self) -> io::Result<()> {
+        if let Stream::Tls(conn, socket) = self {
+            let mut conn = conn.lock().unwrap();
+            while conn.is_handshaking() {
+                conn.complete_io(&mut &*socket)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells a TLS client the connection ends on purpose before closing the socket.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.shutdown(net::Shutdown::Both),
+            Stream::Tls(conn, socket) => {
+                let mut conn = conn.lock().unwrap();
+                conn.send_close_notify();
+                // the client may have left already, resetting the socket on the notify
+                match flush_tls(&mut conn, socket).and_then(|_| socket.shutdown(net::Shutdown::Both)) {
+                    Err(err) if matches!(err.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::NotConnected) => Ok(()),
+                    res => res,
+                }
+            },
+        }
+    }
+}
+
+fn flush_tls(conn: &mut ServerConnection, mut socket: &net::TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        conn.write_tls(&mut socket)?;
+    }
+    Ok(())
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (conn, socket) = match self {
+            Stream::Plain(socket) => return socket.read(buf),
+            Stream::Tls(conn, socket) => (conn, socket),
+        };
+
+        let mut records = [0u8; 4096];
+        loop {
+            {
+                let mut conn = conn.lock().unwrap();
+                match conn.reader().read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                    res => return res,
+                }
+            }
+
+            let len = socket.read(&mut records)?;
+            if len == 0 {
+                // a client closing the socket without a close_notify is taken as closing the connection
+                return Ok(0);
+            }
+
+            let mut conn = conn.lock().unwrap();
+            let mut records = &records[..len];
+            while !records.is_empty() {
+                conn.read_tls(&mut records)?;
+                if let Err(err) = conn.process_new_packets() {
+                    // let the client know why, as far as it still listens
+                    let _ = flush_tls(&mut conn, socket);
+                    return Err(invalid(err));
+                }
+            }
+            // handshake messages the records called for
+            flush_tls(&mut conn, socket)?;
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.write(buf),
+            Stream::Tls(conn, socket) => {
+                let mut conn = conn.lock().unwrap();
+                let len = conn.writer().write(buf)?;
+                flush_tls(&mut conn, socket)?;
+                Ok(len)
+            },
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.flush(),
+            Stream::Tls(..) => Ok(()),
+        }
+    }
+}
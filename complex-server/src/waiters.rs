@@ -0,0 +1,213 @@
+use std::collections::HashSet;
+use std::mem;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest a wait lasts, also for waits that asked for no timeout or a longer one.
+pub static MAX_WAIT: Duration = Duration::from_secs(300);
+/// Reply to the waits still parked when the server shuts down.
+static SHUTTING_DOWN: &str = "ERR shutting down\n";
+
+#[derive(Clone, Copy)]
+pub enum Comparison {
+    AtLeast,
+    Above,
+    Equal,
+}
+
+impl FromStr for Comparison {
+    type Err = ();
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            ">=" => Ok(Comparison::AtLeast),
+            ">" => Ok(Comparison::Above),
+            "==" => Ok(Comparison::Equal),
+            _ => Err(()),
+        }
+    }
+}
+
+pub enum Condition {
+    Counter(Comparison, u64),
+    Key(String),
+}
+
+impl Condition {
+    pub fn holds(&self, counter: u64, uploads: &HashSet<String>) -> bool {
+        match self {
+            Condition::Counter(Comparison::AtLeast, value) => counter >= *value,
+            Condition::Counter(Comparison::Above, value) => counter > *value,
+            Condition::Counter(Comparison::Equal, value) => counter == *value,
+            Condition::Key(key) => uploads.contains(key),
+        }
+    }
+
+    pub fn reply(&self, counter: u64) -> String {
+        match self {
+            Condition::Counter(_, _) => format!("counter: {}\n", counter),
+            Condition::Key(key) => format!("key: {}\n", key),
+        }
+    }
+}
+
+/// Continuation of a parked connection, called with the response to send.
+pub type Resume = Box<dyn FnOnce(String) + Send>;
+
+struct Waiter {
+    /// Connection parked, as in `client list`.
+    id: u64,
+    condition: Condition,
+    deadline: Instant,
+    resume: Resume,
+}
+
+/// Connections parked until a condition on the state holds.
+///
+/// Parked connections are not tied to a worker, they are resumed by the
+/// thread that made the condition true or by the timeout thread.
+/// `client kill` drops a parked connection, shutting down answers every wait.
+pub struct Waiters {
+    waiting: Mutex<Vec<Waiter>>,
+    condvar: Condvar,
+    closed: AtomicBool,
+}
+
+impl Waiters {
+    pub fn new() -> Arc<Self> {
+        let waiters = Arc::new(Self { waiting: Mutex::new(Vec::new()), condvar: Condvar::new(), closed: AtomicBool::new(false) });
+
+        let timeouts = Arc::clone(&waiters);
+        thread::spawn(move || timeouts.expire());
+
+        waiters
+    }
+
+    /// Parks connection `id` for at most `MAX_WAIT`, must be called while holding the state locks the condition was checked under.
+    pub fn park(&self, id: u64, condition: Condition, timeout: Option<Duration>, resume: Resume) {
+        let deadline = Instant::now() + timeout.map_or(MAX_WAIT, |timeout| timeout.min(MAX_WAIT));
+        let mut waiting = self.waiting.lock().unwrap();
+
+        // checked under the lock, so a wait starting while closing is answered all the same
+        if self.closed.load(Ordering::SeqCst) {
+            drop(waiting);
+            return resume(String::from(SHUTTING_DOWN));
+        }
+
+        waiting.push(Waiter { id, condition, deadline, resume });
+        self.condvar.notify_one();
+    }
+
+    /// Drops the wait of connection `id`, which closes it. False if it was not parked.
+    pub fn cancel(&self, id: u64) -> bool {
+        let waiter = {
+            let mut waiting = self.waiting.lock().unwrap();
+            waiting.iter().position(|waiter| waiter.id == id).map(|index| waiting.remove(index))
+        };
+        waiter.is_some()
+    }
+
+    /// Answers every wait and those parked from now on, the connections then see the shutdown on their next read.
+    pub fn close(&self) {
+        let waiting = {
+            let mut waiting = self.waiting.lock().unwrap();
+            self.closed.store(true, Ordering::SeqCst);
+            mem::take(&mut *waiting)
+        };
+
+        for waiter in waiting {
+            (waiter.resume)(String::from(SHUTTING_DOWN));
+        }
+    }
+
+    /// Resumes the waiters whose condition holds, must be called while holding the state locks.
+    pub fn notify(&self, counter: u64, uploads: &HashSet<String>) {
+        let ready: Vec<Waiter> = {
+            let mut waiting = self.waiting.lock().unwrap();
+            let (ready, rest) = mem::take(&mut *waiting).into_iter().partition(|waiter| waiter.condition.holds(counter, uploads));
+            *waiting = rest;
+            ready
+        };
+
+        for waiter in ready {
+            (waiter.resume)(waiter.condition.reply(counter));
+        }
+    }
+
+    fn expire(&self) {
+        let mut waiting = self.waiting.lock().unwrap();
+
+        loop {
+            let now = Instant::now();
+            let (expired, rest): (Vec<Waiter>, Vec<Waiter>) = mem::take(&mut *waiting)
+                .into_iter()
+                .partition(|waiter| waiter.deadline <= now);
+            *waiting = rest;
+
+            if !expired.is_empty() {
+                drop(waiting);
+                for waiter in expired {
+                    (waiter.resume)(String::from("ERR timeout\n"));
+                }
+                waiting = self.waiting.lock().unwrap();
+                continue;
+            }
+
+            waiting = match waiting.iter().map(|waiter| waiter.deadline).min() {
+                Some(deadline) => self.condvar.wait_timeout(waiting, deadline - now).unwrap().0,
+                None => self.condvar.wait(waiting).unwrap(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    /// Parks connection `id` on an upload that never comes, the channel gets its reply.
+    fn park(waiters: &Waiters, id: u64, timeout: Option<Duration>) -> mpsc::Receiver<String> {
+        let (sender, reply) = mpsc::channel();
+        waiters.park(id, Condition::Key(String::from("never")), timeout, Box::new(move |response| sender.send(response).unwrap()));
+        reply
+    }
+
+    #[test]
+    fn answers_the_parked_waits_on_shutdown() {
+        let waiters = Waiters::new();
+        let parked = park(&waiters, 1, None);
+        assert!(parked.try_recv().is_err());
+
+        waiters.close();
+        assert_eq!(parked.recv_timeout(Duration::from_secs(1)).unwrap(), SHUTTING_DOWN);
+
+        // a wait starting after the shutdown does not hold up the drain either
+        assert_eq!(park(&waiters, 2, None).try_recv().unwrap(), SHUTTING_DOWN);
+    }
+
+    #[test]
+    fn drops_a_killed_wait() {
+        let waiters = Waiters::new();
+        let killed = park(&waiters, 1, None);
+        let other = park(&waiters, 2, None);
+
+        assert!(waiters.cancel(1));
+        assert!(!waiters.cancel(1));
+        assert_eq!(killed.recv_timeout(Duration::from_secs(1)), Err(mpsc::RecvTimeoutError::Disconnected));
+
+        waiters.notify(0, &HashSet::from([String::from("never")]));
+        assert_eq!(other.recv_timeout(Duration::from_secs(1)).unwrap(), "key: never\n");
+    }
+
+    #[test]
+    fn times_out_waits() {
+        let waiters = Waiters::new();
+        let parked = park(&waiters, 1, Some(Duration::from_millis(20)));
+        assert_eq!(parked.recv_timeout(Duration::from_secs(1)).unwrap(), "ERR timeout\n");
+    }
+}
@@ -1,63 +1,373 @@
-use std::net::TcpStream;
-use std::io::{Result, Read, Write};
+use std::io::{ErrorKind, Result};
 use std::os::unix::prelude::AsRawFd;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
-use log::info;
+use log::{info, warn};
 use polling::Event;
+use tracing::{info_span, Span};
 
+use crate::cancel::Cancel;
 use crate::event_handler::EventHandler;
-use crate::handler;
+use crate::handler::{self, Reply, SharedStore, Store, Transaction, Update};
+use crate::logger;
+use crate::primes::Progress;
+use crate::ratelimit;
 use crate::reactor::Reactor;
+use crate::session::Session;
+use crate::timeouts::{Deadline, Phase, Timeouts};
+use crate::tls::Stream;
+use crate::trace;
 
 enum State {
     WaitingRead,
     Reading,
     WaitingWrite,
     Writing,
+    Parked(Option<Instant>),
+    Computing,
     Finished
 }
 
+impl State {
+    /// Span of the time spent in the state, under the request being answered or else the connection.
+    fn span(&self, parent: &Span) -> Span {
+        match self {
+            State::WaitingRead => info_span!(parent: parent, "WaitingRead"),
+            State::Reading => info_span!(parent: parent, "Reading"),
+            State::WaitingWrite => info_span!(parent: parent, "WaitingWrite"),
+            State::Writing => info_span!(parent: parent, "Writing"),
+            State::Parked(_) => info_span!(parent: parent, "Parked"),
+            State::Computing => info_span!(parent: parent, "Computing"),
+            State::Finished => Span::none(),
+        }
+    }
+}
+
+/// Compute running on the pool, its updates arrive through the channel.
+struct Job {
+    updates: mpsc::Receiver<Update>,
+    cancel: Cancel,
+}
+
 pub struct AsyncClientHandler {
-    stream: TcpStream,
+    stream: Stream,
+    session: Session,
     state: State,
-    response: Option<String>
+    response: Option<String>,
+    store: SharedStore,
+    transaction: Transaction,
+    job: Option<Job>,
+    /// Set when the socket became readable while computing, which may be a hangup.
+    readable: bool,
+    /// Set while TLS records of a reply wait for the socket to take them.
+    flushing: bool,
+    timeouts: Timeouts,
+    /// None while computing or parked, those have timeouts of their own.
+    deadline: Option<Deadline>,
+    /// Earliest timer set for the deadline, later timers are set once it fired.
+    timer: Option<Instant>,
+    /// Name of the request being answered, counted in `stats` once its reply is complete.
+    command: &'static str,
+    /// When the request being answered was read.
+    started: Instant,
+    /// Command line of the request being answered, for the slow log.
+    line: String,
+    /// How long the compute of the request being answered waited for a worker.
+    queued: Duration,
+    /// Span of the connection, open as long as the handler.
+    span: Span,
+    /// Span of the request being answered, none between requests.
+    request: Span,
+    /// Span of the current state.
+    phase: Span,
+    /// Where requests are read into, `READ_BUFFER` bytes long.
+    buffer: Vec<u8>,
+    /// Bytes of a request line read so far, its end is still on the way.
+    filled: usize,
 }
 
 impl AsyncClientHandler {
-    pub fn new(stream: TcpStream) -> Self {
-        Self { stream, state: State::WaitingRead, response: None }
+    pub fn new(stream: Stream, session: Session, store: SharedStore, timeouts: Timeouts, read_buffer: usize) -> Self {
+        let span = trace::connection(stream.socket().as_raw_fd() as u64);
+        let phase = State::WaitingRead.span(&span);
+        Self { stream, session, state: State::WaitingRead, response: None, store, transaction: Transaction::default(), job: None, readable: false, flushing: false, timeouts, deadline: None, timer: None, command: "none", started: Instant::now(), line: String::new(), queued: Duration::ZERO, span, request: Span::none(), phase, buffer: vec![0; read_buffer], filled: 0 }
     }
-}
 
-impl EventHandler for AsyncClientHandler {
-    fn id(&self) -> usize {
-        self.stream.as_raw_fd() as usize
+    /// Counts the request being answered towards the metrics and the slow log once its reply is complete.
+    fn count(&self, store: &mut Store, response: &str) {
+        store.count(self.command, response, self.started);
+        store.slowlog().record(self.session.peer(), &self.line, self.started, self.queued);
     }
 
-    fn poll(&mut self, reactor: &mut Reactor) -> Result<()> {
+    /// Moves on to `state`, closing the span of the one before.
+    fn transition(&mut self, state: State) {
+        self.phase = Span::none();
+        self.phase = state.span(if self.request.is_none() { &self.span } else { &self.request });
+        self.state = state;
+    }
+
+    /// Gives the client until the timeout of `phase` to move on, subscribers may stay idle for good.
+    pub fn expect(&mut self, phase: Option<Phase>, reactor: &mut Reactor) {
+        let phase = phase.filter(|phase| *phase != Phase::Idle || !self.store.borrow_mut().pubsub().is_subscribed(self.id()));
+        self.deadline = phase.and_then(|phase| self.timeouts.start(phase));
+        self.arm(reactor);
+    }
+
+    fn arm(&mut self, reactor: &mut Reactor) {
+        if self.timer.is_some_and(|timer| timer <= Instant::now()) {
+            self.timer = None;
+        }
+
+        if let Some(deadline) = self.deadline {
+            if self.timer.is_none_or(|timer| timer > deadline.at) {
+                reactor.schedule(deadline.at, self.id());
+                self.timer = Some(deadline.at);
+            }
+        }
+    }
+}
+
+impl AsyncClientHandler {
+    fn step(&mut self, reactor: &mut Reactor) -> Result<()> {
+        if self.store.borrow().is_killed(self.id()) {
+            let peer = self.session.peer();
+            info!("Closing {}:{}, killed", peer.ip(), peer.port());
+            return self.close(reactor);
+        }
+
+        if self.store.borrow().is_overflowed(self.id()) {
+            let peer = self.session.peer();
+            warn!("Closing {}:{}, it did not keep up with its subscriptions", peer.ip(), peer.port());
+            return self.close(reactor);
+        }
+
+        if let Some(deadline) = self.deadline.filter(|deadline| deadline.at <= Instant::now()) {
+            let peer = self.session.peer();
+            warn!("Closing {}:{}, {}", peer.ip(), peer.port(), deadline);
+            return self.close(reactor);
+        }
+        // the timer that woke this step may have been set for an earlier deadline
+        self.arm(reactor);
+
         match self.state {
             State::Reading => {
-                let mut buf = [0u8; 512];
-                let len = self.stream.read(&mut buf)?;
+                let len = match self.stream.read(&mut self.buffer[self.filled..]) {
+                    // the TLS handshake is still going on, or only part of a record came in
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => return self.await_read(reactor),
+                    len => len?,
+                };
 
                 if len == 0 {
                     info!("Client disconnected!");
-                    reactor.remove(&self.stream)?;
-                    reactor.unregister(self);
-                    self.state = State::Finished;
+                    self.close(reactor)?;
                 } else {
-                    self.response.replace(handler::handle(String::from_utf8_lossy(&buf[..len]).to_string()));
+                    self.filled += len;
+                    // the rest of the line has to come in before the read deadline, a full buffer is taken as it is
+                    if self.buffer[self.filled - 1] != b'\n' && self.filled < self.buffer.len() {
+                        return self.await_read(reactor);
+                    }
+
+                    let len = std::mem::take(&mut self.filled);
+                    let message = String::from_utf8_lossy(&self.buffer[..len]).to_string();
+                    self.command = handler::name(&message);
+                    self.started = Instant::now();
+                    self.line.clear();
+                    self.line.push_str(&message);
+                    self.queued = Duration::ZERO;
+                    self.request = trace::request(&self.span, self.id() as u64, self.command);
+                    // jobs for the pool are queued under the request
+                    let _request = self.request.clone().entered();
+
+                    let mut store = self.store.borrow_mut();
+                    store.received(self.id(), len);
+                    store.record(self.id(), self.command);
+                    let reply = match store.limiter().check(self.session.peer(), &message) {
+                        Ok(()) => handler::handle(message, self.id(), &mut self.session, &mut self.transaction, &mut store),
+                        Err(wait) => Reply::Ready(ratelimit::rejection(wait)),
+                    };
+
+                    if let Reply::Ready(response) = &reply {
+                        self.count(&mut store, response);
+                    }
+
+                    for id in store.take_woken() {
+                        reactor.wake(id);
+                    }
+                    drop(store);
+
+                    match reply {
+                        Reply::Ready(response) | Reply::Queued(response) => {
+                            self.response.replace(response);
+                            self.expect(Some(Phase::Write), reactor);
+                            reactor.modify(self.stream.socket(), Event::writable(self.id()))?;
+                            self.transition(State::WaitingWrite);
+                        },
+
+                        Reply::Compute(k, timeout, progress) => {
+                            let (sender, updates) = mpsc::channel();
+                            let cancel = Cancel::new(timeout.map(|timeout| Instant::now() + timeout));
+                            let (waker, id) = (reactor.waker(), self.id());
+                            let send = Arc::new(move |update| {
+                                if sender.send(update).is_ok() {
+                                    waker.wake(id);
+                                }
+                            });
+
+                            let report = progress.then(|| {
+                                let send = Arc::clone(&send);
+                                Arc::new(move |percent| send(Update::Progress(percent))) as Progress
+                            });
+
+                            let (worker_cancel, submitted) = (cancel.clone(), Instant::now());
+                            let store = self.store.borrow();
+                            let spawner = store.pool().spawner();
+
+                            store.pool().execute(&cancel, move || {
+                                let queued = submitted.elapsed();
+                                send(Update::Done(handler::compute(k, &worker_cancel, &spawner, report), queued));
+                            });
+                            drop(store);
+
+                            if let Some(deadline) = cancel.deadline() {
+                                reactor.schedule(deadline, self.id());
+                            }
+
+                            // watch for hangups until the result is back
+                            reactor.modify(self.stream.socket(), Event::readable(self.id()))?;
+                            self.job = Some(Job { updates, cancel });
+                            self.expect(None, reactor);
+                            self.transition(State::Computing);
+                        },
+
+                        Reply::Parked(timeout) => {
+                            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+                            if let Some(deadline) = deadline {
+                                reactor.schedule(deadline, self.id());
+                            }
+
+                            reactor.modify(self.stream.socket(), Event::none(self.id()))?;
+                            self.expect(None, reactor);
+                            self.transition(State::Parked(deadline));
+                        },
+                    }
+                }
+            }
+
+            State::Parked(deadline) => {
+                let mut store = self.store.borrow_mut();
+
+                let response = match store.take_ready(self.id()) {
+                    Some(response) => response,
+                    None if deadline.is_some_and(|deadline| deadline <= Instant::now()) => {
+                        store.cancel_wait(self.id());
+                        String::from("ERR timeout\n")
+                    },
+                    None => return Ok(()),
+                };
+                self.count(&mut store, &response);
+                drop(store);
+
+                self.response.replace(response);
+                self.expect(Some(Phase::Write), reactor);
+                reactor.modify(self.stream.socket(), Event::writable(self.id()))?;
+                self.transition(State::WaitingWrite);
+            }
+
+            State::Computing => {
+                let Some(job) = &self.job else { return Ok(()) };
+
+                // progress lines go out as they come, the final line ends the job
+                let mut output = String::new();
+                let finished = loop {
+                    match job.updates.try_recv() {
+                        Ok(Update::Done(response, queued)) => {
+                            self.queued = queued;
+                            self.count(&mut self.store.borrow_mut(), &response);
+                            output.push_str(&response);
+                            break true;
+                        },
+                        Ok(update) => output.push_str(&update.line()),
+                        Err(mpsc::TryRecvError::Disconnected) => {
+                            self.count(&mut self.store.borrow_mut(), "ERR compute failed\n");
+                            output.push_str("ERR compute failed\n");
+                            break true;
+                        },
+                        Err(mpsc::TryRecvError::Empty) if job.cancel.is_expired() => {
+                            job.cancel.cancel();
+                            self.count(&mut self.store.borrow_mut(), "ERR timeout\n");
+                            output.push_str("ERR timeout\n");
+                            break true;
+                        },
+                        Err(mpsc::TryRecvError::Empty) => break false,
+                    }
+                };
+
+                if finished {
+                    self.job = None;
+                }
 
-                    reactor.modify(&self.stream, Event::writable(self.id()))?;
-                    self.state = State::WaitingWrite;
+                if !output.is_empty() {
+                    self.response.replace(output);
+                    self.expect(Some(Phase::Write), reactor);
+                    reactor.modify(self.stream.socket(), Event::writable(self.id()))?;
+                    self.transition(State::WaitingWrite);
+                } else if std::mem::take(&mut self.readable) {
+                    // only a hangup matters while computing, a pipelined request waits in the socket
+                    if self.stream.socket().peek(&mut [0u8; 1])? == 0 {
+                        info!("Client disconnected!");
+                        self.close(reactor)?;
+                    } else {
+                        reactor.modify(self.stream.socket(), Event::none(self.id()))?;
+                    }
                 }
             }
 
             State::Writing => {
-                self.stream.write_all(self.response.take().unwrap().as_bytes())?;
+                let response = self.response.take().unwrap_or_default();
+                self.stream.write_all(response.as_bytes())?;
 
-                reactor.modify(&self.stream, Event::readable(self.id()))?;
-                self.state = State::WaitingRead;
+                // published messages stay in the bounded mailbox until the socket took everything before them
+                let mut mail = String::new();
+                if !self.stream.wants_write() {
+                    for message in self.store.borrow_mut().pubsub().take_mail(self.id()) {
+                        mail.push_str(&message);
+                    }
+                    self.stream.write_all(mail.as_bytes())?;
+                }
+
+                self.store.borrow_mut().sent(self.id(), response.len() + mail.len());
+                let wrote = !response.is_empty() || !mail.is_empty() || std::mem::take(&mut self.flushing);
+
+                // the stream keeps what the socket did not take, the rest goes out once it is writable again
+                if self.stream.wants_write() {
+                    self.flushing = wrote;
+                    if self.deadline.is_none_or(|deadline| deadline.phase != Phase::Write) {
+                        self.expect(Some(Phase::Write), reactor);
+                    }
+                    reactor.modify(self.stream.socket(), Event::writable(self.id()))?;
+                    self.transition(State::WaitingWrite);
+                    return Ok(());
+                }
+
+                reactor.modify(self.stream.socket(), Event::readable(self.id()))?;
+                if self.job.is_some() {
+                    // updates that arrived while writing found nobody listening
+                    reactor.wake(self.id());
+                    self.expect(None, reactor);
+                    self.transition(State::Computing);
+                } else if reactor.draining().is_some() {
+                    return self.bye(reactor);
+                } else {
+                    // a wake up with nothing to write leaves the idle client's deadline as it was,
+                    // and a client halfway through a line keeps its read deadline
+                    if wrote && self.filled == 0 {
+                        self.expect(Some(Phase::Idle), reactor);
+                    }
+                    // the reply is out
+                    self.request = Span::none();
+                    self.transition(State::WaitingRead);
+                }
             }
 
             _ => {}
@@ -66,20 +376,91 @@ impl EventHandler for AsyncClientHandler {
         Ok(())
     }
 
+    /// Waits for the rest of a request, which must arrive before the read deadline.
+    fn await_read(&mut self, reactor: &mut Reactor) -> Result<()> {
+        if self.deadline.is_none_or(|deadline| deadline.phase != Phase::Read) {
+            self.expect(Some(Phase::Read), reactor);
+        }
+        // handshake messages the socket did not take go out once it is writable
+        let interest = if self.stream.wants_write() { Event::all(self.id()) } else { Event::readable(self.id()) };
+        reactor.modify(self.stream.socket(), interest)?;
+        self.transition(State::WaitingRead);
+        Ok(())
+    }
+
+    fn close(&mut self, reactor: &mut Reactor) -> Result<()> {
+        if let Some(job) = &self.job {
+            job.cancel.cancel();
+        }
+
+        let mut store = self.store.borrow_mut();
+        store.disconnect(self.id());
+        store.limiter().forget(self.session.peer());
+        drop(store);
+        self.transition(State::Finished);
+        self.request = Span::none();
+
+        reactor.unregister(self);
+        reactor.remove(self.stream.socket())
+    }
+
+    /// Closes the connection of a client that waits for its next request while the server shuts down.
+    fn bye(&mut self, reactor: &mut Reactor) -> Result<()> {
+        let peer = self.session.peer();
+        info!("Sending bye to {}:{}", peer.ip(), peer.port());
+
+        let _ = self.stream.write_all(b"bye\n");
+        self.close(reactor)
+    }
+}
+
+impl EventHandler for AsyncClientHandler {
+    fn id(&self) -> usize {
+        self.stream.socket().as_raw_fd() as usize
+    }
+
+    fn poll(&mut self, reactor: &mut Reactor) -> Result<()> {
+        let _connection = logger::connection(self.id() as u64);
+
+        if let Err(err) = self.step(reactor) {
+            warn!("Client error: {}", err);
+            self.close(reactor)?;
+        }
+
+        Ok(())
+    }
+
     fn event(&mut self, event: polling::Event, tasks: &mut Vec<usize>) -> Result<()> {
         match self.state {
-            State::WaitingRead => {
-                if event.readable {
-                    tasks.push(self.id());
-                    self.state = State::Reading;
-                }
+            State::WaitingRead if event.readable || event.writable => {
+                tasks.push(self.id());
+                self.transition(State::Reading);
             },
 
-            State::WaitingWrite => {
-                if event.writable {
-                    tasks.push(self.id());
-                    self.state = State::Writing;
-                }
+            // woken up by a timer or a mutation that may have released the wait
+            State::Parked(_) if !event.readable && !event.writable => {
+                tasks.push(self.id());
+            },
+
+            State::Computing => {
+                self.readable |= event.readable;
+                tasks.push(self.id());
+            },
+
+            // woken up to push published messages
+            State::WaitingRead if !event.readable && !event.writable => {
+                tasks.push(self.id());
+                self.transition(State::Writing);
+            },
+
+            State::WaitingWrite if event.writable => {
+                tasks.push(self.id());
+                self.transition(State::Writing);
+            },
+
+            // woken up by the write deadline
+            State::WaitingWrite if !event.readable && !event.writable => {
+                tasks.push(self.id());
             },
 
             _ => {}
@@ -87,4 +468,23 @@ impl EventHandler for AsyncClientHandler {
 
         Ok(())
     }
+
+    fn shutdown(&mut self, reactor: &mut Reactor) -> Result<()> {
+        let _connection = logger::connection(self.id() as u64);
+
+        // the others say bye once their reply is out, parked ones only if released before the drain timeout
+        match self.state {
+            State::WaitingRead => self.bye(reactor),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for AsyncClientHandler {
+    /// Gives up the compute of a client still busy when the loop ended.
+    fn drop(&mut self) {
+        if let Some(job) = &self.job {
+            job.cancel.cancel();
+        }
+    }
 }
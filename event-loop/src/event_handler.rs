@@ -6,4 +6,6 @@ pub trait EventHandler {
     fn id(&self) -> usize;
     fn poll(&mut self, reactor: &mut Reactor) -> Result<()>;
     fn event(&mut self, event: Event, tasks: &mut Vec<usize>) -> Result<()>;
+    /// Called once a signal came in, the loop ends when every handler unregistered or the drain timeout passed.
+    fn shutdown(&mut self, reactor: &mut Reactor) -> Result<()>;
 }
@@ -1,7 +1,12 @@
 use std::collections::HashMap;
 use std::io::Result;
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::warn;
 
 use crate::event_handler::EventHandler;
+use crate::metrics::Metrics;
 use crate::reactor::Reactor;
 
 pub struct EventLoop {
@@ -11,11 +16,11 @@ pub struct EventLoop {
 }
 
 impl EventLoop {
-    pub fn new() -> Result<Self> {
+    pub fn new(metrics: Arc<Metrics>) -> Result<Self> {
         Ok(Self {
             tasks: Vec::new(),
             handlers: HashMap::new(),
-            reactor: Reactor::new()?,
+            reactor: Reactor::new(metrics)?,
         })
     }
 
@@ -25,6 +30,8 @@ impl EventLoop {
     }
 
     pub fn run(&mut self) -> Result<()> {
+        let mut draining = false;
+
         loop {
             // 1. execute tasks
             while let Some(task) = self.tasks.pop() {
@@ -32,20 +39,42 @@ impl EventLoop {
                 handler.poll(&mut self.reactor)?;
             }
 
-            // 2. register new handlers
+            // 2. tell every handler to wind down once a signal came in
+            if !draining && self.reactor.draining().is_some() {
+                draining = true;
+                for handler in self.handlers.values_mut() {
+                    handler.shutdown(&mut self.reactor)?;
+                }
+            }
+
+            // 3. register new handlers
             while let Some(handler) = self.reactor.register.pop() {
                 self.handlers.insert(handler.id(), handler);
             }
 
-            // 3. unregister old handlers
+            // 4. unregister old handlers
             while let Some(key) = self.reactor.unregister.pop() {
                 self.handlers.remove(&key);
             }
 
-            // 4. handle events
+            // 5. stop once the handlers are gone or the drain timeout passed
+            if let Some(deadline) = self.reactor.draining() {
+                if self.handlers.is_empty() {
+                    return Ok(());
+                }
+
+                if deadline <= Instant::now() {
+                    warn!("Closing {} connections still busy after the drain timeout", self.handlers.len());
+                    return Ok(());
+                }
+            }
+
+            // 6. handle events
             for event in self.reactor.events()? {
-                let handler = self.handlers.get_mut(&event.key).unwrap();
-                handler.event(event, &mut self.tasks)?;
+                // timers and wake ups can outlive their handler
+                if let Some(handler) = self.handlers.get_mut(&event.key) {
+                    handler.event(event, &mut self.tasks)?;
+                }
             }
         }
     }
@@ -1,13 +1,47 @@
-use std::collections::HashSet;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use rand::{self, RngCore};
 
+use crate::admin::{Admin, Clients, Config, Stats};
+use crate::cancel::{Cancel, Stopped};
+use crate::metrics::Metrics;
+use crate::primes::{self, Progress};
+use crate::persistence::{AppendLog, Entry, Fsync};
+use crate::pubsub::PubSub;
+use crate::ratelimit::RateLimiter;
+use crate::session::Session;
+use crate::slowlog::Slowlog;
+use crate::snapshot::Snapshotter;
+use crate::thread_pool::{Spawner, ThreadPool};
+use crate::waiters::{Comparison, Condition, Waiters};
+
 enum Command {
     Fortune,
     Increment,
     Counter,
     Upload(String),
     Download(String),
-    Compute(u64),
+    /// The `k` to sum up to, or why it was refused.
+    Compute(Result<u64, String>, Option<u64>, bool),
+    Save,
+    Subscribe(String),
+    Unsubscribe(Option<String>),
+    Publish(String, String),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<String>),
+    WaitCounter(String, Comparison, u64, Option<u64>),
+    WaitKey(String, Option<u64>),
+    Auth(String),
+    Admin(Admin),
     None
 }
 
@@ -17,6 +51,11 @@ impl Command {
             "fortune" => Command::Fortune,
             "increment" => Command::Increment,
             "counter" => Command::Counter,
+            "save" => Command::Save,
+            "unsubscribe" => Command::Unsubscribe(None),
+            "multi" => Command::Multi,
+            "exec" => Command::Exec,
+            "discard" => Command::Discard,
             other => {
                 if other.starts_with("upload") {
                     if let Some(split) = other.split_once(' ') {
@@ -30,9 +69,63 @@ impl Command {
                     }
                 }
 
+                if let Some(admin) = Admin::parse(other) {
+                    return Command::Admin(admin);
+                }
+
                 if other.starts_with("compute") {
+                    if let [_, k, options @ ..] = &other.split_whitespace().collect::<Vec<_>>()[..] {
+                        let timeout = options.iter().find_map(|option| option.strip_prefix("timeout=")).and_then(|ms| ms.parse().ok());
+                        let k = match k.parse() {
+                            Ok(k) if k > primes::MAX_K => Err(String::from("k too large")),
+                            Ok(k) => Ok(k),
+                            Err(_) => Err(format!("invalid k {}", k)),
+                        };
+                        return Command::Compute(k, timeout, options.contains(&"progress"));
+                    }
+                }
+
+                if other.starts_with("auth") {
+                    if let Some(split) = other.split_once(' ') {
+                        return Command::Auth(String::from(split.1));
+                    }
+                }
+
+                if other.starts_with("subscribe") {
+                    if let Some(split) = other.split_once(' ') {
+                        return Command::Subscribe(String::from(split.1));
+                    }
+                }
+
+                if other.starts_with("unsubscribe") {
                     if let Some(split) = other.split_once(' ') {
-                        return Command::Compute(split.1.parse().unwrap_or(0));
+                        return Command::Unsubscribe(Some(String::from(split.1)));
+                    }
+                }
+
+                if other.starts_with("publish") {
+                    if let Some((channel, message)) = other.split_once(' ').and_then(|split| split.1.split_once(' ')) {
+                        return Command::Publish(String::from(channel), String::from(message));
+                    }
+                }
+
+                if other.starts_with("wait-counter") {
+                    if let [_, name, comparison, value, timeout @ ..] = &other.split_whitespace().collect::<Vec<_>>()[..] {
+                        if let (Ok(comparison), Ok(value)) = (comparison.parse(), value.parse()) {
+                            return Command::WaitCounter(String::from(*name), comparison, value, timeout.first().and_then(|ms| ms.parse().ok()));
+                        }
+                    }
+                }
+
+                if other.starts_with("wait-key") {
+                    if let [_, key, timeout @ ..] = &other.split_whitespace().collect::<Vec<_>>()[..] {
+                        return Command::WaitKey(String::from(*key), timeout.first().and_then(|ms| ms.parse().ok()));
+                    }
+                }
+
+                if other.starts_with("watch") {
+                    if let Some(split) = other.split_once(' ') {
+                        return Command::Watch(split.1.split_whitespace().map(String::from).collect());
                     }
                 }
 
@@ -40,6 +133,44 @@ impl Command {
             }
         }
     }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Fortune => "fortune",
+            Command::Increment => "increment",
+            Command::Counter => "counter",
+            Command::Upload(_) => "upload",
+            Command::Download(_) => "download",
+            Command::Compute(..) => "compute",
+            Command::Save => "save",
+            Command::Subscribe(_) => "subscribe",
+            Command::Unsubscribe(_) => "unsubscribe",
+            Command::Publish(..) => "publish",
+            Command::Multi => "multi",
+            Command::Exec => "exec",
+            Command::Discard => "discard",
+            Command::Watch(_) => "watch",
+            Command::WaitCounter(..) => "wait-counter",
+            Command::WaitKey(..) => "wait-key",
+            Command::Auth(_) => "auth",
+            Command::Admin(admin) => admin.name(),
+            Command::None => "unknown",
+        }
+    }
+
+    /// Commands that can be queued in a transaction.
+    fn is_queueable(&self) -> bool {
+        matches!(self, Command::Fortune | Command::Increment | Command::Counter | Command::Upload(_) | Command::Download(_) | Command::None)
+    }
+}
+
+/// Per-connection `multi` queue and `watch`ed keys.
+///
+/// The key `counter` watches the counter, any other key watches the upload with that name.
+#[derive(Default)]
+pub struct Transaction {
+    queue: Option<Vec<Command>>,
+    watched: Vec<(String, u64)>,
 }
 
 static FORTUNES: &[&str] = &[
@@ -49,61 +180,359 @@ static FORTUNES: &[&str] = &[
     "Doing your best means never stop trying.\n",
 ];
 
-static mut COUNTER: u64 = 0;
-static mut UPLOADS: Option<HashSet<String>> = None;
+pub struct Store {
+    counter: u64,
+    uploads: HashSet<String>,
+    log: AppendLog,
+    snapshots: Snapshotter,
+    pubsub: PubSub,
+    waiters: Waiters,
+    ready: HashMap<usize, String>,
+    woken: Vec<usize>,
+    pool: ThreadPool,
+    limiter: RateLimiter,
+    stats: Stats,
+    metrics: Arc<Metrics>,
+    clients: Clients,
+    config: Config,
+}
+
+impl Store {
+    pub fn open(log_path: impl AsRef<Path>, fsync: Fsync, snapshots: Snapshotter, pool: ThreadPool, limiter: RateLimiter, config: Config, metrics: Arc<Metrics>) -> io::Result<Self> {
+        let (log, mut counter, mut uploads) = AppendLog::open(log_path, fsync)?;
+
+        if counter == 0 && uploads.is_empty() {
+            if let Some((saved_counter, saved_uploads)) = snapshots.load()? {
+                log.seed(saved_counter, saved_uploads.clone());
+                (counter, uploads) = (saved_counter, saved_uploads);
+            }
+        }
+
+        Ok(Self {
+            counter,
+            uploads,
+            log,
+            snapshots,
+            pubsub: PubSub::default(),
+            waiters: Waiters::default(),
+            ready: HashMap::new(),
+            woken: Vec::new(),
+            pool,
+            limiter,
+            stats: Stats::new(Arc::clone(&metrics)),
+            metrics,
+            clients: Clients::default(),
+            config,
+        })
+    }
+
+    /// Writes out and syncs the mutations logged so far, used on shutdown.
+    pub fn close(&self) {
+        self.log.close();
+    }
+
+    /// Workers for jobs too slow to run on the loop.
+    pub fn pool(&self) -> &ThreadPool {
+        &self.pool
+    }
+
+    pub fn pubsub(&mut self) -> &mut PubSub {
+        &mut self.pubsub
+    }
+
+    /// Request budgets of every client, only ever touched by the loop.
+    pub fn limiter(&mut self) -> &mut RateLimiter {
+        &mut self.limiter
+    }
+
+    /// Handlers that have to be woken up, either for published messages or released waits.
+    pub fn take_woken(&mut self) -> Vec<usize> {
+        let mut woken = std::mem::take(&mut self.woken);
+        woken.extend(self.pubsub.take_woken());
+        woken
+    }
+
+    pub fn take_ready(&mut self, id: usize) -> Option<String> {
+        self.ready.remove(&id)
+    }
+
+    pub fn cancel_wait(&mut self, id: usize) {
+        self.waiters.cancel(id);
+    }
+
+    /// Lists a new client for `client list`.
+    pub fn connect(&mut self, id: usize, addr: SocketAddr) {
+        self.clients.register(id, addr);
+        self.metrics.accepted();
+    }
+
+    /// Forgets everything about a disconnected client.
+    pub fn disconnect(&mut self, id: usize) {
+        self.clients.remove(id);
+        self.metrics.closed();
+        self.pubsub.disconnect(id);
+        self.waiters.cancel(id);
+        self.ready.remove(&id);
+    }
+
+    /// Traffic of a client, for `client list`.
+    pub fn received(&mut self, id: usize, len: usize) {
+        self.clients.received(id, len);
+        self.metrics.received(len);
+    }
+
+    pub fn sent(&mut self, id: usize, len: usize) {
+        self.clients.sent(id, len);
+        self.metrics.sent(len);
+    }
+
+    /// Notes the request a client just made.
+    pub fn record(&mut self, id: usize, command: &'static str) {
+        self.clients.record(id, command);
+    }
+
+    /// Counts a request read at `started` towards the metrics once its reply is complete.
+    pub fn count(&mut self, command: &'static str, response: &str, started: Instant) {
+        self.metrics.request(command, response, started.elapsed());
+    }
+
+    pub fn slowlog(&self) -> &Slowlog {
+        self.config.slowlog()
+    }
+
+    /// Whether `client kill` asked to close the client.
+    pub fn is_killed(&self, id: usize) -> bool {
+        self.clients.is_killed(id)
+    }
+
+    /// Whether the client fell too far behind on its subscriptions.
+    pub fn is_overflowed(&self, id: usize) -> bool {
+        self.pubsub.is_overflowed(id)
+    }
 
-fn is_prime(x: u64) -> bool {
-    if x == 0 || x == 1 {
-        return false;
+    fn admin(&mut self, command: Admin) -> String {
+        match command {
+            Admin::Info => self.stats.info(self.clients.len()),
+            Admin::Stats => self.stats.report(),
+            Admin::ClientList => self.clients.list(),
+            Admin::ClientKill(addr) => match self.clients.kill(&addr) {
+                Ok(id) => {
+                    self.woken.push(id);
+                    format!("killed: {}\n", addr)
+                },
+                Err(err) => err,
+            },
+            Admin::ConfigGet(name) => self.config.get(&name),
+            Admin::ConfigSet(name, value) => self.config.set(&name, &value),
+            Admin::SlowlogGet(count) => self.config.slowlog().get(count),
+            Admin::SlowlogReset => self.config.slowlog().reset(),
+        }
+    }
+
+    fn notify_waiters(&mut self) {
+        for (id, reply) in self.waiters.ready(self.counter, &self.uploads) {
+            self.ready.insert(id, reply);
+            self.woken.push(id);
+        }
     }
 
-    for i in 2..x/2 {
-        if x % i == 0 {
-            return false;
+    pub fn snapshots(&self) -> &Snapshotter {
+        &self.snapshots
+    }
+
+    fn watch_value(&self, key: &str) -> u64 {
+        match key {
+            "counter" => self.counter,
+            item => self.uploads.contains(item) as u64,
         }
     }
 
-    true
+    fn apply(&mut self, command: Command) -> String {
+        match command {
+            Command::Fortune => {
+                FORTUNES[rand::thread_rng().next_u32() as usize % FORTUNES.len()].to_string()
+            },
+            Command::Increment => {
+                if let Err(err) = self.log.append(Entry::Increment) {
+                    return format!("ERR {}\n", err);
+                }
+                self.counter += 1;
+                self.snapshots.touch();
+                self.notify_waiters();
+                "incremented\n".to_string()
+            },
+            Command::Counter => {
+                format!("counter: {}\n", self.counter)
+            },
+            Command::Upload(item) => {
+                if let Err(err) = self.log.append(Entry::Upload(item.clone())) {
+                    return format!("ERR {}\n", err);
+                }
+                self.uploads.insert(item);
+                self.snapshots.touch();
+                self.notify_waiters();
+                "uploaded\n".to_string()
+            },
+            Command::Download(item) => {
+                let found = self.uploads.get(&item).cloned().unwrap_or_else(|| String::from("not found"));
+                format!("download: {}\n", found)
+            },
+            _ => {
+                "ok\n".to_string()
+            },
+        }
+    }
+
+    /// Hands a copy of the state to the snapshotter.
+    pub fn save(&self) -> bool {
+        self.snapshots.save(self.counter, self.uploads.clone())
+    }
+}
+
+pub type SharedStore = Rc<RefCell<Store>>;
+
+/// What `stats` counts the request `message` as, rate limited ones included.
+pub fn name(message: &str) -> &'static str {
+    Command::parse(message.trim_end()).name()
 }
 
-pub fn handle(message: String) -> String {
-    unsafe {
-        if UPLOADS == None {
-            UPLOADS = Some(HashSet::new());
+/// Sum of the primes up to `k`, may have to sieve for a while so it runs off the loop.
+pub fn compute(k: u64, cancel: &Cancel, pool: &Spawner, progress: Option<Progress>) -> String {
+    match primes::sum(k, cancel, pool, progress) {
+        Ok(sum) => format!("computed: {}\n", sum),
+        Err(Stopped::Timeout) => "ERR timeout\n".to_string(),
+        Err(Stopped::Cancelled) => "ERR cancelled\n".to_string(),
+    }
+}
+
+pub enum Reply {
+    Ready(String),
+    /// Acknowledges a command queued by `multi`, which only counts once `exec` runs it.
+    Queued(String),
+    /// The response has to be computed by the worker pool, within the timeout if one is given
+    /// and with progress lines ahead of it if asked for.
+    Compute(u64, Option<Duration>, bool),
+    /// The client waits for a condition, the store hands out the response once it holds.
+    Parked(Option<Duration>),
+}
+
+/// What a compute job sends back to the loop.
+pub enum Update {
+    Progress(u8),
+    /// The response, and how long the job waited for a worker.
+    Done(String, Duration),
+}
+
+impl Update {
+    pub fn line(self) -> String {
+        match self {
+            Update::Progress(percent) => format!("progress: {}%\n", percent),
+            Update::Done(response, _) => response,
         }
     }
+}
 
-    match Command::parse(message.trim_end()) {
-        Command::Fortune => {
-            FORTUNES[rand::thread_rng().next_u32() as usize % FORTUNES.len()].to_string()
+/// Handles one request, the whole call runs within a single turn of the event loop.
+/// Nothing but `auth` passes before the client authenticated.
+pub fn handle(message: String, id: usize, session: &mut Session, transaction: &mut Transaction, store: &mut Store) -> Reply {
+    Reply::Ready(match (Command::parse(message.trim_end()), &mut transaction.queue) {
+        (Command::Auth(token), _) => match session.authenticate(&token) {
+            Ok(identity) => format!("authenticated: {}\n", identity),
+            Err(err) => format!("ERR {}\n", err),
         },
-        Command::Increment => {
-            unsafe {
-                COUNTER += 1;
+        (_, _) if session.identity().is_none() => {
+            "ERR auth required\n".to_string()
+        },
+        (_, _) if !session.permits(message.trim_end()) => {
+            "ERR forbidden\n".to_string()
+        },
+        (command, Some(queue)) if command.is_queueable() => {
+            queue.push(command);
+            return Reply::Queued("queued\n".to_string());
+        },
+        (Command::Exec, Some(_)) => {
+            let queue = transaction.queue.take().unwrap_or_default();
+            let watched = std::mem::take(&mut transaction.watched);
+
+            if watched.iter().any(|(key, value)| store.watch_value(key) != *value) {
+                "exec: aborted\n".to_string()
+            } else {
+                let mut response = format!("exec: {}\n", queue.len());
+                for command in queue {
+                    let (name, started) = (command.name(), Instant::now());
+                    let reply = store.apply(command);
+                    store.count(name, &reply, started);
+                    response.push_str(&reply);
+                }
+                response
             }
-            "incremented\n".to_string()
         },
-        Command::Counter => {
-            format!("counter: {}\n", unsafe { COUNTER })
+        (Command::Discard, Some(_)) => {
+            *transaction = Transaction::default();
+            "discarded\n".to_string()
+        },
+        (_, Some(_)) => {
+            "ERR not allowed in multi\n".to_string()
+        },
+        (Command::Multi, None) => {
+            transaction.queue = Some(Vec::new());
+            "multi: started\n".to_string()
         },
-        Command::Upload(item) => {
-            unsafe {
-                UPLOADS.as_mut().map(|set| set.insert(item));
+        (Command::Exec | Command::Discard, None) => {
+            "ERR no multi in progress\n".to_string()
+        },
+        (Command::Watch(keys), None) => {
+            for key in &keys {
+                transaction.watched.push((key.clone(), store.watch_value(key)));
+            }
+            format!("watching: {}\n", keys.join(" "))
+        },
+        (Command::Save, None) => {
+            if store.save() { "saving\n" } else { "already saving\n" }.to_string()
+        },
+        (Command::Subscribe(channel), None) => {
+            store.pubsub.subscribe(&channel, id);
+            format!("subscribed: {}\n", channel)
+        },
+        (Command::Unsubscribe(channel), None) => {
+            store.pubsub.unsubscribe(channel.as_deref(), id);
+            format!("unsubscribed: {}\n", channel.as_deref().unwrap_or("all"))
+        },
+        (Command::Publish(channel, message), None) => {
+            format!("published: {}\n", store.pubsub.publish(&channel, &message))
+        },
+        (Command::WaitCounter(name, _, _, _), None) if name != "counter" => {
+            format!("ERR unknown counter {}\n", name)
+        },
+        (Command::Admin(admin), None) => {
+            store.admin(admin)
+        },
+        (Command::Compute(Err(err), ..), None) => {
+            format!("ERR {}\n", err)
+        },
+        (Command::Compute(Ok(k), timeout, progress), None) => {
+            // a sum already in the table is answered right away, however short the timeout
+            if let Some(sum) = primes::cached(k) {
+                return Reply::Ready(format!("computed: {}\n", sum));
             }
-            "uploaded\n".to_string()
+            return Reply::Compute(k, timeout.map(Duration::from_millis), progress);
         },
-        Command::Download(item) => {
-            let found = unsafe {
-                UPLOADS.as_ref().map(|set| set.get(&item).cloned().unwrap_or_else(|| String::from("not found"))).unwrap()
+        (command @ (Command::WaitCounter(..) | Command::WaitKey(..)), None) => {
+            let (condition, timeout) = match command {
+                Command::WaitCounter(_, comparison, value, timeout) => (Condition::Counter(comparison, value), timeout),
+                Command::WaitKey(key, timeout) => (Condition::Key(key), timeout),
+                _ => unreachable!(),
             };
-            format!("download: {}\n", found)
+
+            if condition.holds(store.counter, &store.uploads) {
+                condition.reply(store.counter)
+            } else {
+                store.waiters.park(id, condition);
+                return Reply::Parked(timeout.map(Duration::from_millis));
+            }
         },
-        Command::Compute(k) => {
-            let sum = (0..=k).fold(0, |acc, x| acc + if is_prime(x) { x } else { 0 });
-            format!("computed: {}\n", sum)
+        (command, None) => {
+            store.apply(command)
         },
-        Command::None => {
-            "ok\n".to_string()
-        }
-    }
+    })
 }
@@ -1,33 +1,51 @@
 use std::io::Result;
 use std::mem;
-use std::net::{TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::Arc;
 use std::os::unix::prelude::AsRawFd;
 
 use crate::client::AsyncClientHandler;
 use crate::event_handler::EventHandler;
+use crate::handler::SharedStore;
+use crate::logger;
 use crate::reactor::Reactor;
+use crate::session::{Session, Tokens};
+use crate::timeouts::{Phase, Timeouts};
+use crate::tls::Stream;
 
 use log::info;
 use polling::Event;
+use rustls::ServerConfig;
 
 enum State {
     Started,
     Waiting,
-    Accepting(TcpStream),
+    Accepting(TcpStream, SocketAddr),
 }
 
 pub struct AsyncTcpListener {
     listener: TcpListener,
     state: State,
+    store: SharedStore,
+    tokens: Arc<Tokens>,
+    tls: Option<Arc<ServerConfig>>,
+    timeouts: Timeouts,
+    read_buffer: usize,
 }
 
 impl AsyncTcpListener {
-    pub fn bind(addr: &str) -> Result<Self> {
+    pub fn bind(addr: SocketAddr, store: SharedStore, tokens: Arc<Tokens>, tls: Option<Arc<ServerConfig>>, timeouts: Timeouts, read_buffer: usize) -> Result<Self> {
         let listener = TcpListener::bind(addr)?;
         listener.set_nonblocking(true)?;
         Ok(Self {
             listener,
             state: State::Started,
+            store,
+            tokens,
+            tls,
+            timeouts,
+            read_buffer,
         })
     }
 }
@@ -43,9 +61,14 @@ impl EventHandler for AsyncTcpListener {
                 reactor.add(&self.listener, Event::readable(self.id()))?;
             },
 
-            State::Accepting(stream) => {
+            State::Accepting(stream, peer) => {
                 reactor.add(&stream, Event::readable(stream.as_raw_fd() as usize))?;
-                reactor.register(AsyncClientHandler::new(stream));
+                let stream = Stream::accept(stream, self.tls.as_ref())?;
+
+                let mut client = AsyncClientHandler::new(stream, Session::new(Arc::clone(&self.tokens), peer), Rc::clone(&self.store), self.timeouts, self.read_buffer);
+                self.store.borrow_mut().connect(client.id(), peer);
+                client.expect(Some(Phase::Idle), reactor);
+                reactor.register(client);
 
                 reactor.modify(&self.listener, Event::readable(self.id()))?;
             },
@@ -57,19 +80,113 @@ impl EventHandler for AsyncTcpListener {
     }
 
     fn event(&mut self, event: Event, tasks: &mut Vec<usize>) -> Result<()> {
-        match self.state {
-            State::Waiting => {
-                if event.readable {
-                    info!("Client connected!");
-                    let (stream, _) = self.listener.accept()?;
-                    tasks.push(self.id());
-                    self.state = State::Accepting(stream);
-                }
-            },
-
-            _ => {}
+        if let State::Waiting = self.state {
+            if event.readable {
+                logger::sampled!("Client connected!");
+                let (stream, peer) = self.listener.accept()?;
+                tasks.push(self.id());
+                self.state = State::Accepting(stream, peer);
+            }
         }
 
         Ok(())
     }
+
+    fn shutdown(&mut self, reactor: &mut Reactor) -> Result<()> {
+        info!("No longer accepting clients");
+        reactor.unregister(self);
+        reactor.remove(&self.listener)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use std::{fs, process, thread};
+
+    use super::*;
+    use crate::admin::Config;
+    use crate::event_loop::EventLoop;
+    use crate::handler::Store;
+    use crate::metrics::Metrics;
+    use crate::persistence::Fsync;
+    use crate::ratelimit::RateLimiter;
+    use crate::slowlog::Slowlog;
+    use crate::snapshot::Snapshotter;
+    use crate::thread_pool::ThreadPool;
+
+    /// Runs a server on a port of its own, for as long as the tests run.
+    fn serve(timeouts: Timeouts, read_buffer: usize) -> SocketAddr {
+        let dir = std::env::temp_dir().join(format!("listener-test-{}-{}", process::id(), read_buffer));
+        fs::create_dir_all(&dir).unwrap();
+        let (sender, addr) = mpsc::channel();
+
+        thread::spawn(move || {
+            let metrics = Arc::new(Metrics::default());
+            let mut event_loop = EventLoop::new(Arc::clone(&metrics)).unwrap();
+            let config = Config::new(Vec::new(), Slowlog::from_env());
+            let snapshots = Snapshotter::new(dir.join("dump.snap"), Duration::from_secs(60));
+            let store = Store::open(dir.join("appendonly.log"), Fsync::Never, snapshots, ThreadPool::new(1), RateLimiter::from_env(), config, metrics).unwrap();
+            // the log stays open, nothing else is written there
+            fs::remove_dir_all(&dir).unwrap();
+
+            let listener = AsyncTcpListener::bind("127.0.0.1:0".parse().unwrap(), Rc::new(RefCell::new(store)), Arc::new(Tokens::from_env().unwrap()), None, timeouts, read_buffer).unwrap();
+            sender.send(listener.listener.local_addr().unwrap()).unwrap();
+            event_loop.register(listener);
+            event_loop.run().unwrap();
+        });
+
+        addr.recv().unwrap()
+    }
+
+    fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        BufReader::new(stream)
+    }
+
+    fn request(client: &mut BufReader<TcpStream>, line: &str) -> String {
+        client.get_mut().write_all(format!("{}\n", line).as_bytes()).unwrap();
+        let mut reply = String::new();
+        client.read_line(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn serves_others_while_a_client_takes_no_replies() {
+        let addr = serve(Timeouts { idle: None, read: None, write: None }, 1 << 16);
+
+        let mut stuck = connect(addr);
+        assert_eq!(request(&mut stuck, "subscribe news"), "subscribed: news\n");
+
+        // far more than the socket buffers of the subscriber hold
+        let mut publisher = connect(addr);
+        let message = "x".repeat(60_000);
+        for _ in 0..400 {
+            assert!(request(&mut publisher, &format!("publish news {}", message)).starts_with("published: "));
+        }
+
+        let mut other = connect(addr);
+        assert_eq!(request(&mut other, "counter"), "counter: 0\n");
+    }
+
+    #[test]
+    fn closes_a_client_stuck_halfway_through_a_line() {
+        let addr = serve(Timeouts { idle: None, read: Some(Duration::from_millis(200)), write: None }, 512);
+
+        let mut client = connect(addr);
+        client.get_mut().write_all(b"coun").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.get_mut().write_all(b"ter\n").unwrap();
+        let mut reply = String::new();
+        client.read_line(&mut reply).unwrap();
+        assert_eq!(reply, "counter: 0\n");
+
+        client.get_mut().write_all(b"coun").unwrap();
+        reply.clear();
+        assert_eq!(client.read_line(&mut reply).unwrap(), 0);
+    }
 }
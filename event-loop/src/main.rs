@@ -1,24 +1,106 @@
+mod admin;
 mod logger;
+mod metrics;
 mod reactor;
 mod event_loop;
 mod event_handler;
 mod listener;
 mod client;
 mod handler;
+mod cancel;
+mod persistence;
+mod pubsub;
+mod snapshot;
+mod snapshot_timer;
+mod thread_pool;
+mod waiters;
+mod primes;
+mod ratelimit;
+mod roles;
+mod session;
+mod settings;
+mod shutdown;
+mod slowlog;
+mod timeouts;
+mod tls;
+mod trace;
 
+use std::cell::RefCell;
 use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 
+use log::info;
+
+use crate::admin::Config;
 use crate::event_loop::EventLoop;
+use crate::handler::Store;
 use crate::listener::AsyncTcpListener;
+use crate::metrics::Metrics;
+use crate::persistence::Fsync;
+use crate::ratelimit::RateLimiter;
+use crate::session::Tokens;
+use crate::slowlog::Slowlog;
+use crate::snapshot::Snapshotter;
+use crate::snapshot_timer::AsyncSnapshotTimer;
+use crate::thread_pool::ThreadPool;
+use crate::timeouts::Timeouts;
+
+static LOG_PATH: &str = "appendonly.log";
+static SNAPSHOT_PATH: &str = "dump.snap";
+static SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+pub static THREADS: i32 = 4;
+pub static PORT: u16 = 3000;
+pub static READ_BUFFER: usize = 512;
 
 fn main() -> io::Result<()> {
+    settings::load();
     logger::setup().unwrap();
+    let _trace = trace::setup().unwrap();
+
+    let addr = settings::bind(settings::parse("PORT", PORT))?;
+    let threads = settings::parse("THREADS", THREADS).max(1);
+    let read_buffer = settings::parse("READ_BUFFER", READ_BUFFER).max(1);
+
+    let metrics = Arc::new(Metrics::default());
+    let pool = ThreadPool::new(threads);
+    metrics::serve(Arc::clone(&metrics), pool.spawner())?;
 
-    let mut event_loop = EventLoop::new()?;
+    let mut event_loop = EventLoop::new(Arc::clone(&metrics))?;
 
-    event_loop.register(AsyncTcpListener::bind("localhost:3000")?);
+    let fsync = Fsync::from_env();
+    let tls = tls::from_env()?;
+    let timeouts = Timeouts::from_env();
+    let slowlog = Slowlog::from_env();
+
+    let config = Config::new(vec![
+        ("bind", addr.ip().to_string()),
+        ("port", addr.port().to_string()),
+        ("threads", threads.to_string()),
+        ("read-buffer", read_buffer.to_string()),
+        ("idle-timeout", admin::millis(timeouts.idle)),
+        ("read-timeout", admin::millis(timeouts.read)),
+        ("write-timeout", admin::millis(timeouts.write)),
+        ("appendfsync", fsync.to_string()),
+        ("tls", if tls.is_some() { "on" } else { "off" }.to_string()),
+        ("slowlog-max-len", slowlog.capacity().to_string()),
+    ], slowlog);
+
+    let snapshots = Snapshotter::new(SNAPSHOT_PATH, SNAPSHOT_INTERVAL);
+    let store = Rc::new(RefCell::new(Store::open(LOG_PATH, fsync, snapshots, pool, RateLimiter::from_env(), config, metrics)?));
+
+    event_loop.register(AsyncSnapshotTimer::new(Rc::clone(&store)));
+    event_loop.register(AsyncTcpListener::bind(addr, Rc::clone(&store), Arc::new(Tokens::from_env()?), tls, timeouts, read_buffer)?);
 
     event_loop.run()?;
 
+    // dropping the handlers cancels the computes still running, the pool waits for them along with the store
+    drop(event_loop);
+    store.borrow().close();
+    drop(store);
+    info!("Server stopped");
+    logger::flush();
+
     Ok(())
 }
@@ -0,0 +1,111 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use log::warn;
+
+/// Messages a subscriber may fall behind by before it is disconnected.
+pub static MAILBOX: usize = 1024;
+
+/// Channel subscriptions of the clients, keyed by handler id.
+///
+/// Published messages are queued in the subscriber's mailbox and the subscriber
+/// is marked as woken, the caller has to wake it through the reactor so the
+/// messages get written even if it did not send a request. A subscriber whose
+/// mailbox is full is unsubscribed and marked as overflowed, to be closed once woken.
+#[derive(Default)]
+pub struct PubSub {
+    channels: HashMap<String, HashSet<usize>>,
+    mailboxes: HashMap<usize, Vec<String>>,
+    overflowed: HashSet<usize>,
+    woken: Vec<usize>,
+}
+
+impl PubSub {
+    pub fn subscribe(&mut self, channel: &str, id: usize) {
+        self.channels.entry(String::from(channel)).or_default().insert(id);
+    }
+
+    /// Removes the client from `channel`, or from every channel when `None`.
+    pub fn unsubscribe(&mut self, channel: Option<&str>, id: usize) {
+        self.channels.retain(|name, subscribers| {
+            if channel.is_none_or(|channel| channel == name) {
+                subscribers.remove(&id);
+            }
+            !subscribers.is_empty()
+        });
+    }
+
+    pub fn is_subscribed(&self, id: usize) -> bool {
+        self.channels.values().any(|subscribers| subscribers.contains(&id))
+    }
+
+    /// Forgets everything about a disconnected client.
+    pub fn disconnect(&mut self, id: usize) {
+        self.unsubscribe(None, id);
+        self.mailboxes.remove(&id);
+        self.overflowed.remove(&id);
+    }
+
+    /// Queues the message for every subscriber of `channel`, returns how many of them it was queued for.
+    pub fn publish(&mut self, channel: &str, message: &str) -> usize {
+        let subscribers = match self.channels.get(channel) {
+            Some(subscribers) => subscribers.clone(),
+            None => return 0,
+        };
+
+        let mut queued = 0;
+        for id in subscribers {
+            let mailbox = self.mailboxes.entry(id).or_default();
+            if mailbox.len() >= MAILBOX {
+                warn!("Dropping subscriber {}, {} messages behind", id, mailbox.len());
+                self.unsubscribe(None, id);
+                self.mailboxes.remove(&id);
+                self.overflowed.insert(id);
+            } else {
+                mailbox.push(format!("message: {} {}\n", channel, message));
+                queued += 1;
+            }
+            self.woken.push(id);
+        }
+
+        queued
+    }
+
+    /// Whether the client fell too far behind and has to be closed.
+    pub fn is_overflowed(&self, id: usize) -> bool {
+        self.overflowed.contains(&id)
+    }
+
+    pub fn take_mail(&mut self, id: usize) -> Vec<String> {
+        self.mailboxes.remove(&id).unwrap_or_default()
+    }
+
+    pub fn take_woken(&mut self) -> Vec<usize> {
+        mem::take(&mut self.woken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_subscribers_that_fall_behind() {
+        let mut pubsub = PubSub::default();
+        pubsub.subscribe("news", 1);
+        pubsub.subscribe("news", 2);
+
+        for _ in 0..MAILBOX {
+            assert_eq!(pubsub.publish("news", "a"), 2);
+            pubsub.take_mail(2);
+        }
+
+        assert_eq!(pubsub.publish("news", "b"), 1);
+        assert!(pubsub.is_overflowed(1) && !pubsub.is_subscribed(1));
+        assert!(pubsub.take_mail(1).is_empty());
+        assert_eq!(pubsub.take_mail(2), vec![String::from("message: news b\n")]);
+
+        pubsub.disconnect(1);
+        assert!(!pubsub.is_overflowed(1));
+    }
+}
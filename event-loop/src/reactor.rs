@@ -1,20 +1,61 @@
-use std::io::Result;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{ErrorKind, Result};
+use std::os::unix::io::AsRawFd;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
 use crate::event_handler::EventHandler;
+use crate::metrics::Metrics;
+use crate::shutdown::{self, Signals};
+use log::info;
 use polling::{Event, Poller, Source};
 
 pub struct Reactor {
     pub register: Vec<Box<dyn EventHandler>>,
     pub unregister: Vec<usize>,
-    poller: Poller,
+    poller: Arc<Poller>,
+    timers: BinaryHeap<Reverse<(Instant, usize)>>,
+    woken: Vec<Event>,
+    wakeups: (mpsc::Sender<usize>, mpsc::Receiver<usize>),
+    /// Polled along with the sockets, no handler owns it.
+    signals: Signals,
+    /// Set once a signal came in, the handlers have until then to finish.
+    draining: Option<Instant>,
+    metrics: Arc<Metrics>,
+}
+
+/// Wakes handlers from other threads, the poller is notified so that a blocked wait returns.
+#[derive(Clone)]
+pub struct Waker {
+    poller: Arc<Poller>,
+    sender: mpsc::Sender<usize>,
+}
+
+impl Waker {
+    pub fn wake(&self, key: usize) {
+        if self.sender.send(key).is_ok() {
+            let _ = self.poller.notify();
+        }
+    }
 }
 
 impl Reactor {
-    pub fn new() -> Result<Self> {
+    pub fn new(metrics: Arc<Metrics>) -> Result<Self> {
+        let poller = Poller::new()?;
+        let signals = Signals::new()?;
+        poller.add(&signals, Event::readable(signals.as_raw_fd() as usize))?;
+
         Ok(Self {
             register: Vec::new(),
             unregister: Vec::new(),
-            poller: Poller::new()?,
+            poller: Arc::new(poller),
+            timers: BinaryHeap::new(),
+            woken: Vec::new(),
+            wakeups: mpsc::channel(),
+            signals,
+            draining: None,
+            metrics,
         })
     }
 
@@ -38,9 +79,63 @@ impl Reactor {
         self.unregister.push(handler.id());
     }
 
-    pub fn events(&self) -> Result<std::vec::IntoIter<Event>> {
-        let mut evs = Vec::new();
-        self.poller.wait(&mut evs, None)?;
+    /// Delivers an empty event to the handler with `key` once `deadline` has passed.
+    pub fn schedule(&mut self, deadline: Instant, key: usize) {
+        self.timers.push(Reverse((deadline, key)));
+    }
+
+    /// Delivers an empty event to the handler with `key` on the next turn of the loop.
+    pub fn wake(&mut self, key: usize) {
+        self.woken.push(Event::none(key));
+    }
+
+    pub fn waker(&self) -> Waker {
+        Waker { poller: Arc::clone(&self.poller), sender: self.wakeups.0.clone() }
+    }
+
+    /// Deadline of the drain once SIGINT or SIGTERM came in.
+    pub fn draining(&self) -> Option<Instant> {
+        self.draining
+    }
+
+    pub fn events(&mut self) -> Result<std::vec::IntoIter<Event>> {
+        let timeout = match self.woken.is_empty() {
+            true => self.timers.peek().map(|Reverse((deadline, _))| *deadline).into_iter().chain(self.draining).min()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now())),
+            false => Some(Duration::ZERO),
+        };
+
+        let mut evs = std::mem::take(&mut self.woken);
+        match self.poller.wait(&mut evs, timeout) {
+            // the signal pipe is readable on the next wait
+            Err(err) if err.kind() == ErrorKind::Interrupted => {},
+            res => { res?; },
+        }
+
+        let signals_id = self.signals.as_raw_fd() as usize;
+        if evs.iter().any(|ev| ev.key == signals_id) {
+            evs.retain(|ev| ev.key != signals_id);
+            if self.signals.received() && self.draining.is_none() {
+                let timeout = shutdown::drain_timeout();
+                info!("Shutting down, draining for up to {:?}", timeout);
+                self.draining = Some(Instant::now() + timeout);
+            }
+            self.poller.modify(&self.signals, Event::readable(signals_id))?;
+        }
+
+        evs.extend(self.wakeups.1.try_iter().map(Event::none));
+
+        let now = Instant::now();
+        while let Some(Reverse((deadline, key))) = self.timers.peek() {
+            if *deadline > now {
+                break;
+            }
+            evs.push(Event::none(*key));
+            self.timers.pop();
+        }
+
+        self.metrics.wake(evs.len());
+
         Ok(evs.into_iter())
     }
 }
@@ -0,0 +1,57 @@
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::low_level::pipe;
+
+use crate::settings;
+
+pub static DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// SIGINT and SIGTERM as a pipe that turns readable, so the loop waits for them with the sockets.
+pub struct Signals {
+    receiver: UnixStream,
+}
+
+impl Signals {
+    pub fn new() -> io::Result<Self> {
+        let (receiver, sender) = UnixStream::pair()?;
+        receiver.set_nonblocking(true)?;
+
+        for signal in [SIGINT, SIGTERM] {
+            pipe::register(signal, sender.try_clone()?)?;
+        }
+
+        Ok(Self { receiver })
+    }
+
+    /// Empties the pipe, true if a signal arrived since the last call.
+    pub fn received(&mut self) -> bool {
+        let mut buf = [0; 16];
+        let mut received = false;
+
+        while let Ok(len) = self.receiver.read(&mut buf) {
+            if len == 0 {
+                break;
+            }
+            received = true;
+        }
+
+        received
+    }
+}
+
+impl AsRawFd for Signals {
+    fn as_raw_fd(&self) -> RawFd {
+        self.receiver.as_raw_fd()
+    }
+}
+
+/// How long open connections get to finish, `DRAIN_TIMEOUT` in milliseconds.
+pub fn drain_timeout() -> Duration {
+    settings::var("DRAIN_TIMEOUT").ok()
+        .and_then(|ms| ms.parse().ok())
+        .map_or(DRAIN_TIMEOUT, Duration::from_millis)
+}
@@ -0,0 +1,64 @@
+use std::io::Result;
+use std::time::Instant;
+
+use polling::Event;
+
+use crate::event_handler::EventHandler;
+use crate::handler::SharedStore;
+use crate::reactor::Reactor;
+
+/// Key of the snapshot timer, kept clear of the file descriptors used by sockets.
+static SNAPSHOT_KEY: usize = usize::MAX;
+
+enum State {
+    Started,
+    Waiting,
+    Checking,
+}
+
+pub struct AsyncSnapshotTimer {
+    store: SharedStore,
+    state: State,
+}
+
+impl AsyncSnapshotTimer {
+    pub fn new(store: SharedStore) -> Self {
+        Self { store, state: State::Started }
+    }
+}
+
+impl EventHandler for AsyncSnapshotTimer {
+    fn id(&self) -> usize {
+        SNAPSHOT_KEY
+    }
+
+    fn poll(&mut self, reactor: &mut Reactor) -> Result<()> {
+        let store = self.store.borrow();
+
+        if let State::Checking = self.state {
+            if store.snapshots().due() {
+                store.save();
+            }
+        }
+
+        reactor.schedule(Instant::now() + store.snapshots().next_check(), self.id());
+        self.state = State::Waiting;
+
+        Ok(())
+    }
+
+    fn event(&mut self, _event: Event, tasks: &mut Vec<usize>) -> Result<()> {
+        if let State::Waiting = self.state {
+            tasks.push(self.id());
+            self.state = State::Checking;
+        }
+
+        Ok(())
+    }
+
+    fn shutdown(&mut self, reactor: &mut Reactor) -> Result<()> {
+        // its last timer finds no handler
+        reactor.unregister(self);
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net;
+use std::sync::Arc;
+
+use log::info;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+
+use crate::settings;
+
+/// TLS settings from `TLS_CERT` and `TLS_KEY`, PEM files holding the certificate chain and its private key.
+/// With `TLS_CLIENT_CA` set too, clients must present a certificate signed by one of the CAs in that file.
+/// None if `TLS_CERT` is not set, connections stay plain TCP then.
+pub fn from_env() -> io::Result<Option<Arc<ServerConfig>>> {
+    let Ok(cert) = settings::var("TLS_CERT") else {
+        return Ok(None);
+    };
+    let key = settings::var("TLS_KEY").map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "TLS_CERT is set without TLS_KEY"))?;
+
+    let chain = CertificateDer::pem_file_iter(&cert).map_err(invalid)?.collect::<Result<Vec<_>, _>>().map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_file(&key).map_err(invalid)?;
+
+    let builder = ServerConfig::builder();
+    let builder = match settings::var("TLS_CLIENT_CA") {
+        Ok(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(&path).map_err(invalid)? {
+                roots.add(ca.map_err(invalid)?).map_err(invalid)?;
+            }
+            info!("Requiring client certificates signed by {}", path);
+            builder.with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build().map_err(invalid)?)
+        },
+        Err(_) => builder.with_no_client_auth(),
+    };
+
+    info!("Serving TLS with certificate {}", cert);
+
+    Ok(Some(Arc::new(builder.with_single_cert(chain, key).map_err(invalid)?)))
+}
+
+fn invalid(err: impl Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Connection of a client over a non-blocking socket, encrypted if the server has TLS set up.
+///
+/// The TLS session only moves forward when the socket is read or written, so a read
+/// can fail with `WouldBlock` although the socket was readable, for instance while handshaking.
+/// A plain connection keeps the part of a reply the socket did not take, as rustls does for TLS.
+pub enum Stream {
+    Plain(net::TcpStream, Vec<u8>),
+    Tls(Box<ServerConnection>, net::TcpStream),
+}
+
+impl Stream {
+    pub fn accept(socket: net::TcpStream, config: Option<&Arc<ServerConfig>>) -> io::Result<Self> {
+        // a client that sends half a line or takes no replies must not block the loop
+        socket.set_nonblocking(true)?;
+        Ok(match config {
+            Some(config) => {
+                let mut conn = ServerConnection::new(Arc::clone(config)).map_err(invalid)?;
+                // a reply is taken whole, and sent on as the socket takes it
+                conn.set_buffer_limit(None);
+                Stream::Tls(Box::new(conn), socket)
+            },
+            None => Stream::Plain(socket, Vec::new()),
+        })
+    }
+
+    /// What to register with the poller, or peek at for a hangup.
+    pub fn socket(&self) -> &net::TcpStream {
+        match self {
+            Stream::Plain(socket, _) | Stream::Tls(_, socket) => socket,
+        }
+    }
+
+    /// Whether records or plain bytes are still waiting for the socket to take them.
+    pub fn wants_write(&self) -> bool {
+        match self {
+            Stream::Plain(_, pending) => !pending.is_empty(),
+            Stream::Tls(conn, _) => conn.wants_write(),
+        }
+    }
+
+    /// Plaintext that arrived so far, 0 once the client closed the connection.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (conn, socket) = match self {
+            Stream::Plain(socket, _) => return socket.read(buf),
+            Stream::Tls(conn, socket) => (conn, socket),
+        };
+
+        // handshake messages the socket did not take before
+        flush(conn, socket)?;
+
+        loop {
+            match conn.reader().read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                // a client closing the socket without a close_notify is taken as closing the connection
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                res => return res,
+            }
+
+            if conn.read_tls(socket)? == 0 {
+                return Ok(0);
+            }
+
+            let processed = conn.process_new_packets();
+            // handshake messages or the alert telling the client what went wrong
+            flush(conn, socket)?;
+            processed.map_err(invalid)?;
+        }
+    }
+
+    /// Sends `buf` after what is still pending, keeping whatever the socket does not take now.
+    pub fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket, pending) => {
+                pending.extend_from_slice(buf);
+                send(pending, socket)
+            },
+            Stream::Tls(conn, socket) => {
+                conn.writer().write_all(buf)?;
+                flush(conn, socket)
+            },
+        }
+    }
+}
+
+/// Writes out pending plain bytes until done or the socket is full.
+fn send(pending: &mut Vec<u8>, socket: &mut net::TcpStream) -> io::Result<()> {
+    let mut sent = 0;
+    while sent < pending.len() {
+        match socket.write(&pending[sent..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => sent += len,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    pending.drain(..sent);
+    Ok(())
+}
+
+/// Writes out pending records until done or the socket is full.
+fn flush(conn: &mut ServerConnection, socket: &mut net::TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        match conn.write_tls(socket) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            res => res?,
+        };
+    }
+    Ok(())
+}
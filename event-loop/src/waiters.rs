@@ -0,0 +1,70 @@
+use std::collections::HashSet;
+use std::mem;
+use std::str::FromStr;
+
+#[derive(Clone, Copy)]
+pub enum Comparison {
+    AtLeast,
+    Above,
+    Equal,
+}
+
+impl FromStr for Comparison {
+    type Err = ();
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            ">=" => Ok(Comparison::AtLeast),
+            ">" => Ok(Comparison::Above),
+            "==" => Ok(Comparison::Equal),
+            _ => Err(()),
+        }
+    }
+}
+
+pub enum Condition {
+    Counter(Comparison, u64),
+    Key(String),
+}
+
+impl Condition {
+    pub fn holds(&self, counter: u64, uploads: &HashSet<String>) -> bool {
+        match self {
+            Condition::Counter(Comparison::AtLeast, value) => counter >= *value,
+            Condition::Counter(Comparison::Above, value) => counter > *value,
+            Condition::Counter(Comparison::Equal, value) => counter == *value,
+            Condition::Key(key) => uploads.contains(key),
+        }
+    }
+
+    pub fn reply(&self, counter: u64) -> String {
+        match self {
+            Condition::Counter(_, _) => format!("counter: {}\n", counter),
+            Condition::Key(key) => format!("key: {}\n", key),
+        }
+    }
+}
+
+/// Clients parked until a condition on the store holds, keyed by handler id.
+#[derive(Default)]
+pub struct Waiters {
+    waiting: Vec<(usize, Condition)>,
+}
+
+impl Waiters {
+    pub fn park(&mut self, id: usize, condition: Condition) {
+        self.waiting.push((id, condition));
+    }
+
+    pub fn cancel(&mut self, id: usize) {
+        self.waiting.retain(|(waiter, _)| *waiter != id);
+    }
+
+    /// Removes the waiters whose condition holds and returns them with their reply.
+    pub fn ready(&mut self, counter: u64, uploads: &HashSet<String>) -> Vec<(usize, String)> {
+        let (ready, rest) = mem::take(&mut self.waiting).into_iter().partition(|(_, condition)| condition.holds(counter, uploads));
+        self.waiting = rest;
+
+        ready.into_iter().map(|(id, condition): (usize, Condition)| (id, condition.reply(counter))).collect()
+    }
+}
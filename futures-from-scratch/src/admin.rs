@@ -0,0 +1,242 @@
+use std::fmt::Write;
+use std::fs;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{info, LevelFilter};
+
+use crate::logger;
+use crate::metrics::Metrics;
+use crate::slowlog::Slowlog;
+
+/// Commands about the server rather than its data.
+pub enum Admin {
+    Info,
+    Stats,
+    ClientList,
+    ClientKill(String),
+    ConfigGet(String),
+    ConfigSet(String, String),
+    SlowlogGet(Option<String>),
+    SlowlogReset,
+}
+
+impl Admin {
+    pub fn parse(str: &str) -> Option<Self> {
+        match str {
+            "info" => Some(Admin::Info),
+            "stats" => Some(Admin::Stats),
+            "client list" => Some(Admin::ClientList),
+            "slowlog get" => Some(Admin::SlowlogGet(None)),
+            "slowlog reset" => Some(Admin::SlowlogReset),
+            other => {
+                if let Some(addr) = other.strip_prefix("client kill ") {
+                    return Some(Admin::ClientKill(String::from(addr)));
+                }
+
+                if let Some(name) = other.strip_prefix("config get ") {
+                    return Some(Admin::ConfigGet(String::from(name)));
+                }
+
+                if let Some((name, value)) = other.strip_prefix("config set ").and_then(|rest| rest.split_once(' ')) {
+                    return Some(Admin::ConfigSet(String::from(name), String::from(value)));
+                }
+
+                if let Some(count) = other.strip_prefix("slowlog get ") {
+                    return Some(Admin::SlowlogGet(Some(String::from(count))));
+                }
+
+                None
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Admin::Info => "info",
+            Admin::Stats => "stats",
+            Admin::ClientList => "client-list",
+            Admin::ClientKill(_) => "client-kill",
+            Admin::ConfigGet(_) => "config-get",
+            Admin::ConfigSet(..) => "config-set",
+            Admin::SlowlogGet(_) => "slowlog-get",
+            Admin::SlowlogReset => "slowlog-reset",
+        }
+    }
+}
+
+/// Requests and errors per command since the server started, as counted for `/metrics`.
+pub struct Stats {
+    started: Instant,
+    metrics: Arc<Metrics>,
+}
+
+impl Stats {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { started: Instant::now(), metrics }
+    }
+
+    /// Reply to `stats`, a header with the number of commands followed by a line for each.
+    pub fn report(&self) -> String {
+        let requests = self.metrics.requests();
+        let mut report = format!("stats: {}\n", requests.len());
+        for (command, calls, errors) in requests {
+            let _ = writeln!(report, "{} calls={} errors={}", command, calls, errors);
+        }
+        report
+    }
+
+    /// Reply to `info`, about the process as a whole.
+    pub fn info(&self, clients: usize) -> String {
+        let lines = [
+            format!("uptime: {}s", self.started.elapsed().as_secs()),
+            format!("version: {}", env!("CARGO_PKG_VERSION")),
+            format!("architecture: {} ({})", env!("CARGO_PKG_NAME"), std::env::consts::ARCH),
+            format!("threads: {}", proc_status("Threads").unwrap_or_else(|| String::from("unknown"))),
+            format!("memory: {}", proc_status("VmRSS").unwrap_or_else(|| String::from("unknown"))),
+            format!("clients: {}", clients),
+        ];
+
+        format!("info: {}\n{}\n", lines.len(), lines.join("\n"))
+    }
+}
+
+/// A field of `/proc/self/status`, only there on Linux.
+fn proc_status(field: &str) -> Option<String> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status.lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .map(|value| value.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// What `client list` shows about the connection being served, the only one open at a time.
+pub struct Client {
+    addr: SocketAddr,
+    connected: Instant,
+    last_active: Instant,
+    last_command: &'static str,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Set by `client kill`, the connection closes once the reply is out.
+    pub killed: bool,
+}
+
+impl Client {
+    pub fn new(addr: SocketAddr) -> Self {
+        let now = Instant::now();
+        Self { addr, connected: now, last_active: now, last_command: "none", bytes_in: 0, bytes_out: 0, killed: false }
+    }
+
+    /// Notes a request of `len` bytes.
+    pub fn record(&mut self, command: &'static str, len: usize) {
+        self.last_active = Instant::now();
+        self.last_command = command;
+        self.bytes_in += len as u64;
+    }
+
+    pub fn sent(&mut self, len: usize) {
+        self.bytes_out += len as u64;
+    }
+}
+
+/// What admin commands look at, kept across connections.
+pub struct Server {
+    pub stats: Stats,
+    pub metrics: Arc<Metrics>,
+    pub config: Config,
+    pub slowlog: Arc<Slowlog>,
+    /// Bytes read from a client at once.
+    pub read_buffer: usize,
+}
+
+impl Server {
+    /// Answers an admin command of `client`.
+    pub fn run(&self, command: Admin, client: &mut Client) -> String {
+        match command {
+            Admin::Info => self.stats.info(1),
+            Admin::Stats => self.stats.report(),
+            Admin::ClientList => {
+                format!("clients: 1\naddr={} age={}s idle={}s cmd={} in={} out={}\n",
+                    client.addr, client.connected.elapsed().as_secs(), client.last_active.elapsed().as_secs(),
+                    client.last_command, client.bytes_in, client.bytes_out)
+            },
+            Admin::ClientKill(addr) => match addr.parse::<SocketAddr>() {
+                Ok(addr) if addr == client.addr => {
+                    info!("Killing client {}", addr);
+                    client.killed = true;
+                    format!("killed: {}\n", addr)
+                },
+                Ok(addr) => format!("ERR no client {}\n", addr),
+                Err(_) => format!("ERR invalid address {}\n", addr),
+            },
+            Admin::ConfigGet(name) => self.config.get(&name),
+            Admin::ConfigSet(name, value) => self.config.set(&name, &value),
+            Admin::SlowlogGet(count) => self.slowlog.get(count),
+            Admin::SlowlogReset => self.slowlog.reset(),
+        }
+    }
+}
+
+/// Settings for `config get` and `config set`.
+///
+/// All but `loglevel` and `slowlog-threshold` are fixed once the server started and only shown.
+pub struct Config {
+    fixed: Vec<(&'static str, String)>,
+    slowlog: Arc<Slowlog>,
+}
+
+impl Config {
+    pub fn new(fixed: Vec<(&'static str, String)>, slowlog: Arc<Slowlog>) -> Self {
+        Self { fixed, slowlog }
+    }
+
+    /// Reply to `config get <name>`, `*` lists every setting.
+    pub fn get(&self, name: &str) -> String {
+        let settings = self.settings();
+        let found = settings.iter().filter(|(setting, _)| name == "*" || *setting == name).collect::<Vec<_>>();
+
+        if found.is_empty() {
+            return format!("ERR unknown setting {}\n", name);
+        }
+
+        let mut reply = format!("config: {}\n", found.len());
+        for (setting, value) in found {
+            let _ = writeln!(reply, "{} {}", setting, value);
+        }
+        reply
+    }
+
+    /// Reply to `config set <name> <value>`.
+    pub fn set(&self, name: &str, value: &str) -> String {
+        let res = match name {
+            "loglevel" => LevelFilter::from_str(value).map(logger::set_level).map_err(|_| format!("invalid level {}", value)),
+            "slowlog-threshold" => value.parse().map(|ms| self.slowlog.set_threshold(ms)).map_err(|_| format!("invalid number {}", value)),
+            name if self.fixed.iter().any(|(setting, _)| *setting == name) => Err(format!("{} cannot be changed while running", name)),
+            name => Err(format!("unknown setting {}", name)),
+        };
+
+        match res {
+            Ok(()) => {
+                info!("Set {} to {}", name, value);
+                format!("set: {} {}\n", name, value)
+            },
+            Err(err) => format!("ERR {}\n", err),
+        }
+    }
+
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        let mut settings = vec![
+            ("loglevel", logger::level().to_string().to_lowercase()),
+            ("slowlog-threshold", self.slowlog.threshold().to_string()),
+        ];
+        settings.extend(self.fixed.iter().cloned());
+        settings
+    }
+}
+
+/// A timeout as its variable takes it, in milliseconds with 0 for none.
+pub fn millis(timeout: Option<Duration>) -> String {
+    timeout.map_or(0, |timeout| timeout.as_millis()).to_string()
+}
@@ -0,0 +1,46 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Why a job gave up before finishing.
+pub enum Stopped {
+    Timeout,
+    Cancelled,
+}
+
+/// Cooperative stop signal for a long running job, checked by the job between units of work.
+///
+/// Clones share the cancellation flag, so the connection can keep one and hand the other to the worker.
+#[derive(Clone, Default)]
+pub struct Cancel {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl Cancel {
+    pub fn new(deadline: Option<Instant>) -> Self {
+        Self { cancelled: Arc::new(AtomicBool::new(false)), deadline }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    pub fn check(&self) -> Result<(), Stopped> {
+        if self.is_cancelled() {
+            Err(Stopped::Cancelled)
+        } else if self.is_expired() {
+            Err(Stopped::Timeout)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -1,13 +1,25 @@
 use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
 use rand::{self, RngCore};
 
+use crate::admin::Admin;
+use crate::cancel::{Cancel, Stopped};
+use crate::primes::{self, Progress};
+use crate::session::Session;
+use crate::thread_pool::Spawner;
+
 enum Command {
     Fortune,
     Increment,
     Counter,
     Upload(String),
     Download(String),
-    Compute(u64),
+    /// The `k` to sum up to, or why it was refused.
+    Compute(Result<u64, String>, Option<u64>, bool),
+    Auth(String),
+    Admin(Admin),
     None
 }
 
@@ -18,6 +30,12 @@ impl Command {
             "increment" => Command::Increment,
             "counter" => Command::Counter,
             other => {
+                if other.starts_with("auth") {
+                    if let Some(split) = other.split_once(' ') {
+                        return Command::Auth(String::from(split.1));
+                    }
+                }
+
                 if other.starts_with("upload") {
                     if let Some(split) = other.split_once(' ') {
                         return Command::Upload(String::from(split.1));
@@ -30,9 +48,19 @@ impl Command {
                     }
                 }
 
+                if let Some(admin) = Admin::parse(other) {
+                    return Command::Admin(admin);
+                }
+
                 if other.starts_with("compute") {
-                    if let Some(split) = other.split_once(' ') {
-                        return Command::Compute(split.1.parse().unwrap_or(0));
+                    if let [_, k, options @ ..] = &other.split_whitespace().collect::<Vec<_>>()[..] {
+                        let timeout = options.iter().find_map(|option| option.strip_prefix("timeout=")).and_then(|ms| ms.parse().ok());
+                        let k = match k.parse() {
+                            Ok(k) if k > primes::MAX_K => Err(String::from("k too large")),
+                            Ok(k) => Ok(k),
+                            Err(_) => Err(format!("invalid k {}", k)),
+                        };
+                        return Command::Compute(k, timeout, options.contains(&"progress"));
                     }
                 }
 
@@ -40,6 +68,25 @@ impl Command {
             }
         }
     }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Fortune => "fortune",
+            Command::Increment => "increment",
+            Command::Counter => "counter",
+            Command::Upload(_) => "upload",
+            Command::Download(_) => "download",
+            Command::Compute(..) => "compute",
+            Command::Auth(_) => "auth",
+            Command::Admin(admin) => admin.name(),
+            Command::None => "unknown",
+        }
+    }
+}
+
+/// What `stats` counts the request `message` as.
+pub fn name(message: &str) -> &'static str {
+    Command::parse(message.trim_end()).name()
 }
 
 static FORTUNES: &[&str] = &[
@@ -49,61 +96,85 @@ static FORTUNES: &[&str] = &[
     "Doing your best means never stop trying.\n",
 ];
 
-static mut COUNTER: u64 = 0;
-static mut UPLOADS: Option<HashSet<String>> = None;
+static COUNTER: Mutex<u64> = Mutex::new(0);
+static UPLOADS: Mutex<Option<HashSet<String>>> = Mutex::new(None);
 
-fn is_prime(x: u64) -> bool {
-    if x == 0 || x == 1 {
-        return false;
+/// Sum of the primes up to `k`, blocks for as long as the sieve needs.
+pub fn compute(k: u64, cancel: &Cancel, pool: &Spawner, progress: Option<Progress>) -> String {
+    match primes::sum(k, cancel, pool, progress) {
+        Ok(sum) => format!("computed: {}\n", sum),
+        Err(Stopped::Timeout) => "ERR timeout\n".to_string(),
+        Err(Stopped::Cancelled) => "ERR cancelled\n".to_string(),
     }
+}
 
-    for i in 2..x/2 {
-        if x % i == 0 {
-            return false;
-        }
-    }
+pub enum Reply {
+    Ready(String),
+    /// The response has to be computed by the worker pool, within the timeout if one is given
+    /// and with progress lines ahead of it if asked for.
+    Compute(u64, Option<Duration>, bool),
+    /// Answered by the server, which knows the connection.
+    Admin(Admin),
+}
 
-    true
+/// What a running compute has to tell its connection.
+pub enum Update {
+    Progress(u8),
+    /// The response, and how long the job waited for a worker.
+    Done(String, Duration),
 }
 
-pub fn handle(message: String) -> String {
-    unsafe {
-        if UPLOADS == None {
-            UPLOADS = Some(HashSet::new());
+impl Update {
+    pub fn line(self) -> String {
+        match self {
+            Update::Progress(percent) => format!("progress: {}%\n", percent),
+            Update::Done(response, _) => response,
         }
     }
+}
 
-    match Command::parse(message.trim_end()) {
+/// Nothing but `auth` passes before the client authenticated.
+pub fn handle(message: String, session: &mut Session) -> Reply {
+    Reply::Ready(match Command::parse(message.trim_end()) {
+        Command::Auth(token) => match session.authenticate(&token) {
+            Ok(identity) => format!("authenticated: {}\n", identity),
+            Err(err) => format!("ERR {}\n", err),
+        },
+        _ if session.identity().is_none() => {
+            "ERR auth required\n".to_string()
+        },
+        _ if !session.permits(message.trim_end()) => {
+            "ERR forbidden\n".to_string()
+        },
         Command::Fortune => {
             FORTUNES[rand::thread_rng().next_u32() as usize % FORTUNES.len()].to_string()
         },
         Command::Increment => {
-            unsafe {
-                COUNTER += 1;
-            }
+            *COUNTER.lock().unwrap() += 1;
             "incremented\n".to_string()
         },
         Command::Counter => {
-            format!("counter: {}\n", unsafe { COUNTER })
+            format!("counter: {}\n", *COUNTER.lock().unwrap())
         },
         Command::Upload(item) => {
-            unsafe {
-                UPLOADS.as_mut().map(|set| set.insert(item));
-            }
+            UPLOADS.lock().unwrap().get_or_insert_with(HashSet::new).insert(item);
             "uploaded\n".to_string()
         },
         Command::Download(item) => {
-            let found = unsafe {
-                UPLOADS.as_ref().map(|set| set.get(&item).cloned().unwrap_or_else(|| String::from("not found"))).unwrap()
-            };
+            let found = UPLOADS.lock().unwrap().get_or_insert_with(HashSet::new).get(&item).cloned().unwrap_or_else(|| String::from("not found"));
             format!("download: {}\n", found)
         },
-        Command::Compute(k) => {
-            let sum = (0..=k).fold(0, |acc, x| acc + if is_prime(x) { x } else { 0 });
-            format!("computed: {}\n", sum)
+        Command::Compute(Err(err), ..) => {
+            format!("ERR {}\n", err)
+        },
+        Command::Compute(Ok(k), timeout, progress) => {
+            return Reply::Compute(k, timeout.map(Duration::from_millis), progress);
+        },
+        Command::Admin(admin) => {
+            return Reply::Admin(admin);
         },
         Command::None => {
             "ok\n".to_string()
         }
-    }
+    })
 }
@@ -1,19 +1,46 @@
+mod admin;
+mod cancel;
 mod executor;
 mod handler;
 mod logger;
+mod metrics;
 mod myfutures;
+mod primes;
 mod reactor;
+mod roles;
+mod session;
+mod settings;
+mod shutdown;
+mod slowlog;
+mod thread_pool;
+mod timeouts;
+mod tls;
+mod trace;
 
 use std::io;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use futures::join;
-use log::info;
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::{join, StreamExt};
+use log::{info, warn};
 
+use admin::{Client, Config, Server, Stats};
+use cancel::Cancel;
 use executor::block_on;
+use handler::{Reply, Update};
+use metrics::Metrics;
+use primes::Progress;
+use session::{Session, Tokens};
+use shutdown::Signals;
+use slowlog::Slowlog;
+use timeouts::{Phase, Timeouts};
+use tls::Stream;
 use myfutures::*;
 
 fn main() {
+    settings::load();
     let start = std::time::Instant::now();
 
     let fut1 = async {
@@ -40,34 +67,178 @@ fn main() {
     block_on(mainfut);
 }
 
+pub static PORT: u16 = 3000;
+pub static READ_BUFFER: usize = 512;
+
 async fn server() -> io::Result<()> {
     logger::setup().unwrap();
-
-    let listener = TcpListener::bind("127.0.0.1:3000")?;
+    let _trace = trace::setup().unwrap();
+
+    let tokens = Arc::new(Tokens::from_env()?);
+    let tls = tls::from_env()?;
+    let timeouts = Timeouts::from_env();
+    let addr = settings::bind(settings::parse("PORT", PORT))?;
+    let read_buffer = settings::parse("READ_BUFFER", READ_BUFFER).max(1);
+    let listener = TcpListener::bind(addr)?;
+
+    let metrics = Arc::new(Metrics::default());
+    metrics::serve(Arc::clone(&metrics), myfutures::spawner())?;
+
+    let slowlog = Slowlog::from_env();
+    let mut server = Server {
+        stats: Stats::new(Arc::clone(&metrics)),
+        metrics,
+        config: Config::new(vec![
+            ("bind", addr.ip().to_string()),
+            ("port", addr.port().to_string()),
+            ("read-buffer", read_buffer.to_string()),
+            ("idle-timeout", admin::millis(timeouts.idle)),
+            ("read-timeout", admin::millis(timeouts.read)),
+            ("write-timeout", admin::millis(timeouts.write)),
+            ("tls", if tls.is_some() { "on" } else { "off" }.to_string()),
+            ("slowlog-max-len", slowlog.capacity().to_string()),
+        ], Arc::clone(&slowlog)),
+        slowlog,
+        read_buffer,
+    };
 
     info!("Started TCP Listener");
 
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        process(stream, addr).await?;
+    let mut signals = Signals::new()?;
+    let mut accepted = 0;
+
+    while !signals.received() {
+        let (stream, addr) = match future::select(listener.accept(), &mut signals).await {
+            Either::Left((res, _)) => res?,
+            Either::Right(_) => break,
+        };
+        let stream = Stream::accept(stream, tls.as_ref())?;
+        server.metrics.accepted();
+        accepted += 1;
+        // one client at a time, so everything logged meanwhile is about this one
+        let _connection = logger::connection(accepted);
+        // a client failing the TLS handshake or resetting the connection only ends its own turn
+        // a client stalling is dropped once it times out, the next one is waiting meanwhile
+        match process(stream, addr, accepted, Session::new(Arc::clone(&tokens), addr), timeouts, &mut server, &mut signals).await {
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => warn!("Closing {}:{}, {}", addr.ip(), addr.port(), err),
+            Err(err) => warn!("Client error: {}", err),
+            Ok(()) => {},
+        }
+        server.metrics.closed();
     }
+
+    info!("Server stopped");
+    logger::flush();
+
+    Ok(())
 }
 
-async fn process(mut stream: mio::net::TcpStream, addr: std::net::SocketAddr) -> io::Result<()> {
-    info!("Proccessing TCP Stream");
+async fn process(mut stream: Stream, addr: std::net::SocketAddr, connection: u64, mut session: Session, timeouts: Timeouts, server: &mut Server, signals: &mut Signals) -> io::Result<()> {
+    logger::sampled!("Proccessing TCP Stream");
+    let span = trace::connection(connection);
 
-    let mut buf = [0u8; 512];
+    let mut client = Client::new(addr);
 
-    loop {
-        let len = stream.async_read(&mut buf).await?;
+    let mut buf = vec![0u8; server.read_buffer];
+
+    'requests: loop {
+        let phase = if stream.is_handshaking() { Phase::Read } else { Phase::Idle };
+        let read = match future::select(Box::pin(timeouts.limit(phase, stream.async_read(&mut buf))), &mut *signals).await {
+            Either::Left((len, _)) => Some(len),
+            Either::Right(_) => None,
+        };
+
+        let Some(len) = read else {
+            info!("Sending bye to {}:{}", addr.ip(), addr.port());
+            let _ = timeouts.limit(Phase::Write, stream.async_write(b"bye\n")).await;
+            break;
+        };
+        let len = len?;
 
         if len == 0 {
             break;
         }
 
-        let res = handler::handle(String::from_utf8_lossy(&buf[..len]).to_string());
-
-        stream.async_write(res.as_bytes()).await;
+        let message = String::from_utf8_lossy(&buf[..len]).to_string();
+        let name = handler::name(&message);
+        // ends once the reply is out, along with this turn of the loop
+        let request = trace::request(&span, connection, name);
+        client.record(name, len);
+        server.metrics.received(len);
+        let started = Instant::now();
+        let mut queued = Duration::ZERO;
+
+        let res = match handler::handle(message, &mut session) {
+            Reply::Ready(response) => response,
+
+            Reply::Admin(command) => server.run(command, &mut client),
+
+            Reply::Compute(k, timeout, progress) => {
+                let cancel = Cancel::new(timeout.map(|timeout| Instant::now() + timeout));
+                let (sender, mut updates) = mpsc::unbounded();
+                let report = progress.then(|| {
+                    let sender = sender.clone();
+                    Arc::new(move |percent| { let _ = sender.unbounded_send(Update::Progress(percent)); }) as Progress
+                });
+
+                // progress and result come through the same channel, so they arrive in order
+                let (worker_cancel, spawner, submitted) = (cancel.clone(), myfutures::spawner(), Instant::now());
+                request.in_scope(|| myfutures::execute(&cancel, move || {
+                    let queued = submitted.elapsed();
+                    let _ = sender.unbounded_send(Update::Done(handler::compute(k, &worker_cancel, &spawner, report), queued));
+                }));
+
+                // once a signal came in the compute gets until the drain timeout
+                let drain = async {
+                    (&mut *signals).await;
+                    ReactorTimeout::new(shutdown::drain_timeout()).await;
+                };
+                futures::pin_mut!(drain);
+
+                loop {
+                    let update = match future::select(updates.next(), future::select(hangup(&mut stream), &mut drain)).await {
+                        Either::Left((update, _)) => update.unwrap_or_else(|| Update::Done(String::from("ERR compute failed\n"), Duration::ZERO)),
+                        Either::Right((Either::Left(_), _)) => {
+                            cancel.cancel();
+                            break 'requests;
+                        },
+                        Either::Right((Either::Right(_), _)) => {
+                            warn!("Closing {}:{}, still busy after the drain timeout", addr.ip(), addr.port());
+                            cancel.cancel();
+                            break 'requests;
+                        },
+                    };
+
+                    match update {
+                        Update::Progress(_) => {
+                            let line = update.line();
+                            client.sent(line.len());
+                            server.metrics.sent(line.len());
+                            // a client that left while progress went out is found here rather than by the hangup
+                            if let Err(err) = timeouts.limit(Phase::Write, stream.async_write(line.as_bytes())).await {
+                                cancel.cancel();
+                                return Err(err);
+                            }
+                        },
+                        Update::Done(_, waited) => {
+                            queued = waited;
+                            break update.line();
+                        },
+                    }
+                }
+            },
+        };
+
+        server.metrics.request(name, &res, started.elapsed());
+        server.slowlog.record(addr, &String::from_utf8_lossy(&buf[..len]), started, queued);
+        client.sent(res.len());
+        server.metrics.sent(res.len());
+        timeouts.limit(Phase::Write, stream.async_write(res.as_bytes())).await?;
+
+        if client.killed {
+            info!("Closing {}:{}, killed", addr.ip(), addr.port());
+            break;
+        }
     }
 
     info!("Disconnected {}:{}", addr.ip(), addr.port());
@@ -8,8 +8,31 @@ use std::time::{Duration, Instant};
 
 use mio::event::Source;
 use mio::net::TcpStream;
+use once_cell::sync::Lazy;
 
+use crate::cancel::Cancel;
 use crate::reactor::REACTOR;
+use crate::settings;
+use crate::thread_pool::{Spawner, ThreadPool};
+use crate::tls::Stream;
+
+pub static COMPUTE_THREADS: i32 = 4;
+
+/// Workers for CPU bound jobs, kept apart from the executor thread.
+static POOL: Lazy<ThreadPool> = Lazy::new(|| ThreadPool::new(settings::parse("THREADS", COMPUTE_THREADS).max(1)));
+
+/// Handle for splitting a job that already runs on the pool.
+pub fn spawner() -> Spawner {
+    POOL.spawner()
+}
+
+/// Runs `f` on the worker pool, it is dropped without running if `cancel` fires while it is still queued.
+pub fn execute<F>(cancel: &Cancel, f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    POOL.execute(cancel, f);
+}
 
 #[derive(Clone)]
 pub struct Timeout {
@@ -29,7 +52,7 @@ impl Future for Timeout {
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         match self.deadline.duration_since(Instant::now()) {
             Duration::ZERO => Poll::Ready(()),
-            remaning @ _ => {
+            remaning => {
                 let waker = cx.waker().clone();
 
                 thread::spawn(move || {
@@ -164,7 +187,7 @@ where
     }
 }
 
-impl AsyncRead for TcpStream {
+impl AsyncRead for Stream {
     fn async_read<'a, 'b>(&'a mut self, buf: &'b mut [u8]) -> Read<'a, 'b, Self> {
         Read {
             source: self,
@@ -173,7 +196,7 @@ impl AsyncRead for TcpStream {
     }
 }
 
-impl AsyncWrite for TcpStream {
+impl AsyncWrite for Stream {
     fn async_write<'a, 'b>(&'a mut self, buf: &'b [u8]) -> Write<'a, 'b, Self> {
         Write {
             source: self,
@@ -182,18 +205,56 @@ impl AsyncWrite for TcpStream {
     }
 }
 
+/// Resolves once the peer closes the stream, a pipelined request is left in the socket.
+/// Over TLS the client's close_notify looks like such a request, only closing the socket itself counts.
+pub struct Hangup<'a> {
+    source: &'a mut Stream,
+    registered: bool,
+}
+
+pub fn hangup(source: &mut Stream) -> Hangup<'_> {
+    Hangup { source, registered: false }
+}
+
+impl<'a> Future for Hangup<'a> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.source.socket().peek(&mut [0u8; 1]) {
+            Err(er) if er.kind() == ErrorKind::WouldBlock => {
+                if !self.registered {
+                    let waker = cx.waker().clone();
+                    REACTOR.lock().unwrap().register_read(self.source, waker);
+                    self.registered = true;
+                }
+                Poll::Pending
+            }
+            Ok(0) | Err(_) => Poll::Ready(()),
+            Ok(_) => Poll::Pending,
+        }
+    }
+}
+
+impl<'a> Drop for Hangup<'a> {
+    fn drop(&mut self) {
+        if self.registered {
+            REACTOR.lock().unwrap().deregister(self.source);
+        }
+    }
+}
+
 pub struct TcpListener {
     inner: mio::net::TcpListener,
 }
 
 impl TcpListener {
-    pub fn bind(addr: &str) -> io::Result<Self> {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
         Ok(Self {
-            inner: mio::net::TcpListener::bind(addr.parse().unwrap())?,
+            inner: mio::net::TcpListener::bind(addr)?,
         })
     }
 
-    pub fn accept(&self) -> Accept {
+    pub fn accept(&self) -> Accept<'_> {
         Accept { listener: self }
     }
 }
@@ -211,7 +272,7 @@ impl<'a> Future for Accept<'a> {
                 cx.waker().wake_by_ref();
                 Poll::Pending
             }
-            other @ _ => Poll::Ready(other),
+            other => Poll::Ready(other),
         }
     }
 }
@@ -1,5 +1,6 @@
 use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::io;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
@@ -12,7 +13,7 @@ use mio::event::Source;
 use mio::{Events, Interest, Poll, Token};
 use once_cell::sync::Lazy;
 
-pub static REACTOR: Lazy<Mutex<Box<Reactor>>> = Lazy::new(|| Reactor::new());
+pub static REACTOR: Lazy<Mutex<Box<Reactor>>> = Lazy::new(Reactor::new);
 
 pub struct Reactor {
     timeout_handler: TimeoutHandler,
@@ -153,8 +154,11 @@ impl IOHandler {
                 {
                     let mut poll = poll.lock().unwrap();
 
-                    poll.poll(&mut events, Some(Duration::from_millis(10)))
-                        .unwrap();
+                    // a signal for the server interrupts the wait whichever thread it lands on
+                    match poll.poll(&mut events, Some(Duration::from_millis(10))) {
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        res => res.unwrap(),
+                    }
 
                     for event in &events {
                         let Token(id) = event.token();
@@ -186,13 +190,16 @@ impl IOHandler {
 
         let poll = self.poll.lock().unwrap();
         let registry = poll.registry();
-        registry.register(source, Token(id), interest).unwrap();
+        // a future polled again before its event fired is still registered, it moves to the new waker
+        registry.register(source, Token(id), interest)
+            .or_else(|_| registry.reregister(source, Token(id), interest))
+            .unwrap();
     }
 
     fn deregister_operation<S: Source>(&self, source: &mut S) {
         let poll = self.poll.lock().unwrap();
         let registry = poll.registry();
-        registry.deregister(source);
+        let _ = registry.deregister(source);
     }
 }
 
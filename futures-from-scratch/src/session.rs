@@ -0,0 +1,115 @@
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{info, warn};
+
+use crate::roles::Roles;
+use crate::settings;
+
+/// Tokens accepted by `auth`, read from the file named by `AUTH_TOKENS`.
+///
+/// Every line holds an identity, its token and optionally its role separated by whitespace, `#` starts a comment.
+/// The role defaults to `admin`. Without the variable no token is needed and every client is an `anonymous` admin.
+pub struct Tokens {
+    /// Identity, token and role.
+    entries: Option<Vec<(String, String, String)>>,
+    roles: Roles,
+}
+
+impl Tokens {
+    pub fn from_env() -> io::Result<Self> {
+        let roles = Roles::from_env()?;
+        let Ok(path) = settings::var("AUTH_TOKENS") else {
+            warn!("AUTH_TOKENS is not set, clients need no token");
+            return Ok(Self { entries: None, roles });
+        };
+
+        let mut entries = Vec::new();
+        for line in fs::read_to_string(&path)?.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [] => {},
+                [identity, token, ref role @ ..] if role.len() <= 1 => {
+                    let role = role.first().copied().unwrap_or("admin");
+                    if roles.get(role).is_some() {
+                        entries.push((String::from(identity), String::from(token), String::from(role)));
+                    } else {
+                        warn!("Ignoring {} in {}, there is no role {}", identity, path, role);
+                    }
+                },
+                _ => warn!("Ignoring malformed line in {}: {}", path, line),
+            }
+        }
+
+        info!("Loaded {} tokens from {}", entries.len(), path);
+
+        Ok(Self { entries: Some(entries), roles })
+    }
+
+    /// Identity and role `token` belongs to. Every token is compared in full, so the time taken tells nothing about them.
+    fn identify(&self, token: &str) -> Option<(&str, &str)> {
+        self.entries.as_ref()?.iter().fold(None, |found, (identity, known, role)| {
+            if equal(known.as_bytes(), token.as_bytes()) { Some((identity.as_str(), role.as_str())) } else { found }
+        })
+    }
+}
+
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Per-connection state, for now where the client connected from and who it authenticated as.
+pub struct Session {
+    tokens: Arc<Tokens>,
+    peer: SocketAddr,
+    identity: Option<String>,
+    role: Option<String>,
+}
+
+impl Session {
+    pub fn new(tokens: Arc<Tokens>, peer: SocketAddr) -> Self {
+        let identity = tokens.entries.is_none().then(|| String::from("anonymous"));
+        Self { tokens, peer, identity, role: None }
+    }
+
+    /// None until the client authenticated.
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    /// Switches to the identity `token` belongs to, a wrong token leaves the session as it was.
+    pub fn authenticate(&mut self, token: &str) -> Result<&str, &'static str> {
+        if self.tokens.entries.is_none() {
+            return Err("auth is not enabled");
+        }
+
+        match self.tokens.identify(token) {
+            Some((identity, role)) => {
+                info!("{} authenticated as {} with role {}", self.peer, identity, role);
+                self.role = Some(String::from(role));
+                Ok(self.identity.insert(String::from(identity)))
+            },
+            None => {
+                warn!("{} failed to authenticate", self.peer);
+                Err("invalid token")
+            },
+        }
+    }
+
+    /// Whether the role of the client allows the request `message`, refusals are logged.
+    /// Clients are only ever refused once auth is enabled.
+    pub fn permits(&self, message: &str) -> bool {
+        if self.tokens.entries.is_none() {
+            return true;
+        }
+
+        let permitted = self.role.as_deref().and_then(|role| self.tokens.roles.get(role)).is_some_and(|role| role.permits(message));
+        if !permitted {
+            warn!("{} ({}) is forbidden to run: {}", self.peer, self.identity.as_deref().unwrap_or("unauthenticated"), message);
+        }
+
+        permitted
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::env::{self, VarError};
+use std::fmt::{Display, Write as _};
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::process;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::{logger, metrics, myfutures, shutdown, slowlog, timeouts};
+
+/// Address the listeners bind to, `BIND` changes it.
+static BIND: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+static LAYERS: OnceLock<Layers> = OnceLock::new();
+
+/// Settings given on the command line and in the config file, by variable name.
+struct Layers {
+    flags: HashMap<String, String>,
+    file: HashMap<String, String>,
+}
+
+/// Something the server reads at startup, listed by `--help` and `--print-config`.
+struct Setting {
+    name: &'static str,
+    help: &'static str,
+    default: Option<String>,
+    /// Why a value will not do, checked by `load` before anything reads it.
+    valid: fn(&str) -> Result<(), String>,
+}
+
+impl Setting {
+    /// A setting whose values have to parse as the type of its default.
+    fn new<T: Display + FromStr>(name: &'static str, help: &'static str, default: T) -> Self
+    where
+        T::Err: Display,
+    {
+        Self { name, help, default: Some(default.to_string()), valid: valid::<T> }
+    }
+
+    /// A setting that takes any value, until `parsed` says otherwise.
+    fn text(name: &'static str, help: &'static str, default: &str) -> Self {
+        Self { name, help, default: Some(default.to_string()), valid: |_| Ok(()) }
+    }
+
+    fn unset(name: &'static str, help: &'static str) -> Self {
+        Self { name, help, default: None, valid: |_| Ok(()) }
+    }
+
+    fn millis(name: &'static str, help: &'static str, default: Duration) -> Self {
+        Self::new(name, help, default.as_millis()).parsed::<u64>()
+    }
+
+    /// Values have to parse as a `T`.
+    fn parsed<T: FromStr>(self) -> Self
+    where
+        T::Err: Display,
+    {
+        Self { valid: valid::<T>, ..self }
+    }
+
+    /// The flag and config file key, `LOG_LEVEL` is `log-level`.
+    fn key(&self) -> String {
+        self.name.to_lowercase().replace('_', "-")
+    }
+}
+
+fn settings() -> Vec<Setting> {
+    vec![
+        Setting::new("BIND", "address the listeners bind to", BIND),
+        Setting::new("PORT", "port clients connect to", crate::PORT),
+        Setting::new("THREADS", "threads of the pool computing primes", myfutures::COMPUTE_THREADS),
+        Setting::new("READ_BUFFER", "bytes read from a client at once", crate::READ_BUFFER),
+        Setting::millis("IDLE_TIMEOUT", "milliseconds a client may wait between requests, 0 for no limit", timeouts::IDLE_TIMEOUT),
+        Setting::millis("READ_TIMEOUT", "milliseconds a client may take to send a request, 0 for no limit", timeouts::READ_TIMEOUT),
+        Setting::millis("WRITE_TIMEOUT", "milliseconds a client may take to read a reply, 0 for no limit", timeouts::WRITE_TIMEOUT),
+        Setting::millis("DRAIN_TIMEOUT", "milliseconds connections get to finish on shutdown", shutdown::DRAIN_TIMEOUT),
+        Setting::new("SLOWLOG_THRESHOLD", "milliseconds a request must take to be kept in the slow log", slowlog::SLOWLOG_THRESHOLD),
+        Setting::new("SLOWLOG_LEN", "requests kept in the slow log", slowlog::SLOWLOG_LEN),
+        Setting::new("METRICS_PORT", "port serving Prometheus metrics, 0 turns them off", metrics::METRICS_PORT),
+        Setting::text("LOG_LEVEL", "level logged, per module as module=level", "info").parsed::<logger::Levels>(),
+        Setting::text("LOG_FORMAT", "text or json", "text").parsed::<logger::Format>(),
+        Setting::unset("LOG_FILE", "file logged to instead of stdout"),
+        Setting::unset("LOG_ROTATE", "when the log file is rotated, hourly, daily or a size in bytes").parsed::<logger::Rotation>(),
+        Setting::new("LOG_BUFFER", "records waiting for the log writer", logger::LOG_BUFFER),
+        Setting::text("LOG_OVERFLOW", "what a full log buffer does: drop or block", "drop").parsed::<logger::Overflow>(),
+        Setting::new("LOG_SAMPLE", "one in this many messages on the hot path is logged", 1u64),
+        Setting::unset("TRACE_FILE", "file the tracing spans are written to, in Chrome's format"),
+        Setting::unset("AUTH_TOKENS", "file of identities, tokens and roles clients authenticate with"),
+        Setting::unset("ROLES", "file of roles and the commands they grant, instead of the built-in ones"),
+        Setting::unset("TLS_CERT", "certificate chain to serve TLS with, PEM"),
+        Setting::unset("TLS_KEY", "private key of the certificate, PEM"),
+        Setting::unset("TLS_CLIENT_CA", "CAs client certificates must be signed by, PEM"),
+    ]
+}
+
+/// Reads the command line and the TOML file it or `CONFIG_FILE` names, before anything looks at a setting.
+/// Exits once `--help` or `--print-config` printed, and on a flag or key that is not a setting.
+pub fn load() {
+    let settings = settings();
+    let mut flags = HashMap::new();
+    let mut path = None;
+    let mut print = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            fail(&settings, &format!("unexpected argument {}", arg));
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (flag, None),
+        };
+
+        match name {
+            "help" => {
+                usage(&settings);
+                process::exit(0);
+            },
+            "print-config" => print = true,
+            _ => {
+                let Some(value) = value.or_else(|| args.next()) else {
+                    fail(&settings, &format!("--{} needs a value", name));
+                };
+                if name == "config" {
+                    path = Some(value);
+                } else {
+                    flags.insert(known(&settings, name).unwrap_or_else(|err| fail(&settings, &err)), value);
+                }
+            },
+        }
+    }
+
+    let file = match path.or_else(|| env::var("CONFIG_FILE").ok()) {
+        Some(path) => read(&settings, &path).unwrap_or_else(|err| fail(&settings, &format!("{}: {}", path, err))),
+        None => HashMap::new(),
+    };
+
+    let _ = LAYERS.set(Layers { flags, file });
+
+    for setting in &settings {
+        if let Ok(value) = var(setting.name) {
+            if let Err(err) = (setting.valid)(&value) {
+                fail(&settings, &format!("invalid {} {}: {}", setting.key(), value, err));
+            }
+        }
+    }
+
+    if print {
+        print_config(&settings);
+        process::exit(0);
+    }
+}
+
+/// The setting `name`, from its flag, else the environment, else the config file.
+pub fn var(name: &str) -> Result<String, VarError> {
+    let layers = LAYERS.get();
+    if let Some(value) = layers.and_then(|layers| layers.flags.get(name)) {
+        return Ok(value.clone());
+    }
+
+    match env::var(name) {
+        Err(VarError::NotPresent) => layers.and_then(|layers| layers.file.get(name)).cloned().ok_or(VarError::NotPresent),
+        value => value,
+    }
+}
+
+/// The setting `name` parsed, `default` if it is not set.
+/// `load` already refused values that do not parse.
+pub fn parse<T: FromStr>(name: &str, default: T) -> T {
+    var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+/// Where a listener on `port` binds, on the address from `BIND`.
+pub fn bind(port: u16) -> io::Result<SocketAddr> {
+    let ip = match var("BIND") {
+        Ok(ip) => ip.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid bind address {}", ip)))?,
+        Err(_) => BIND,
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn valid<T: FromStr>(value: &str) -> Result<(), String>
+where
+    T::Err: Display,
+{
+    value.parse::<T>().map(|_| ()).map_err(|err| err.to_string())
+}
+
+/// The variable a flag or key names, `log-level` and `log.level` are `LOG_LEVEL`.
+fn known(settings: &[Setting], key: &str) -> Result<String, String> {
+    let name = key.to_uppercase().replace(['-', '.'], "_");
+    match settings.iter().any(|setting| setting.name == name) {
+        true => Ok(name),
+        false => Err(format!("unknown setting {}", key)),
+    }
+}
+
+/// The settings in the TOML file at `path`, a table like `[log]` prefixes the keys in it.
+fn read(settings: &[Setting], path: &str) -> Result<HashMap<String, String>, String> {
+    let table = fs::read_to_string(path).map_err(|err| err.to_string())?
+        .parse::<toml::Table>().map_err(|err| err.to_string())?;
+
+    let mut values = HashMap::new();
+    flatten(settings, "", table, &mut values)?;
+    Ok(values)
+}
+
+fn flatten(settings: &[Setting], prefix: &str, table: toml::Table, values: &mut HashMap<String, String>) -> Result<(), String> {
+    for (key, value) in table {
+        let key = format!("{}{}", prefix, key);
+        let value = match value {
+            toml::Value::Table(table) => {
+                flatten(settings, &format!("{}.", key), table, values)?;
+                continue;
+            },
+            toml::Value::String(value) => value,
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Float(value) => value.to_string(),
+            toml::Value::Boolean(value) => value.to_string(),
+            _ => return Err(format!("{} must be a string, number or boolean", key)),
+        };
+        values.insert(known(settings, &key)?, value);
+    }
+    Ok(())
+}
+
+/// Where the value of `name` comes from.
+fn source(name: &str) -> Option<&'static str> {
+    let layers = LAYERS.get()?;
+    if layers.flags.contains_key(name) {
+        Some("flag")
+    } else if env::var_os(name).is_some() {
+        Some("env")
+    } else if layers.file.contains_key(name) {
+        Some("file")
+    } else {
+        None
+    }
+}
+
+/// The effective settings as a config file, each commented with where its value comes from.
+fn print_config(settings: &[Setting]) {
+    let mut config = String::new();
+    for setting in settings {
+        let _ = match (var(setting.name), source(setting.name), &setting.default) {
+            (Ok(value), Some(source), _) => writeln!(config, "{} = {}  # {}", setting.key(), toml_value(value), source),
+            (_, _, Some(default)) => writeln!(config, "{} = {}  # default", setting.key(), toml_value(default.clone())),
+            _ => writeln!(config, "# {} is not set", setting.key()),
+        };
+    }
+    // a closed pipe, as with `| head`, is no reason to panic
+    let _ = io::stdout().write_all(config.as_bytes());
+}
+
+/// `value` as TOML, numbers and booleans stay bare.
+fn toml_value(value: String) -> toml::Value {
+    match value.parse::<i64>() {
+        Ok(number) => toml::Value::Integer(number),
+        Err(_) => match value.parse::<bool>() {
+            Ok(bool) => toml::Value::Boolean(bool),
+            Err(_) => toml::Value::String(value),
+        },
+    }
+}
+
+fn usage(settings: &[Setting]) {
+    eprintln!("Usage: {} [--config <file>] [--print-config] [--<setting> <value>]...", env!("CARGO_PKG_NAME"));
+    eprintln!();
+    eprintln!("A flag overrides the variable of the same name in capitals, like PORT for --port,");
+    eprintln!("which overrides the key in the TOML file from --config or CONFIG_FILE.");
+    eprintln!();
+    for setting in settings {
+        let default = setting.default.as_ref().map(|default| format!(" [{}]", default)).unwrap_or_default();
+        eprintln!("  --{:<20} {}{}", setting.key(), setting.help, default);
+    }
+}
+
+fn fail(settings: &[Setting], message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!();
+    usage(settings);
+    process::exit(2);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn setting(name: &str) -> Setting {
+        settings().into_iter().find(|setting| setting.name == name).unwrap()
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let _ = LAYERS.set(Layers {
+            flags: map(&[("LOG_LEVEL", "debug")]),
+            file: map(&[("LOG_LEVEL", "error"), ("LOG_FORMAT", "text"), ("LOG_SAMPLE", "5")]),
+        });
+        env::set_var("LOG_LEVEL", "warn");
+        env::set_var("LOG_FORMAT", "json");
+
+        assert_eq!((var("LOG_LEVEL").ok(), source("LOG_LEVEL")), (Some(String::from("debug")), Some("flag")));
+        assert_eq!((var("LOG_FORMAT").ok(), source("LOG_FORMAT")), (Some(String::from("json")), Some("env")));
+        assert_eq!((var("LOG_SAMPLE").ok(), source("LOG_SAMPLE")), (Some(String::from("5")), Some("file")));
+        assert_eq!((var("LOG_BUFFER").ok(), source("LOG_BUFFER")), (None, None));
+        assert_eq!(parse("LOG_SAMPLE", 1), 5);
+        assert_eq!(parse("LOG_BUFFER", 7), 7);
+    }
+
+    #[test]
+    fn tables_prefix_their_keys() {
+        let table = "port = 4000\n[log]\nlevel = \"debug\"\nsample = 10\n".parse::<toml::Table>().unwrap();
+        let mut values = HashMap::new();
+        flatten(&settings(), "", table, &mut values).unwrap();
+
+        assert_eq!(values, map(&[("PORT", "4000"), ("LOG_LEVEL", "debug"), ("LOG_SAMPLE", "10")]));
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        assert_eq!(known(&settings(), "log-level"), Ok(String::from("LOG_LEVEL")));
+        assert_eq!(known(&settings(), "log.level"), Ok(String::from("LOG_LEVEL")));
+        assert!(known(&settings(), "colour").is_err());
+
+        let table = "[log]\ncolour = \"red\"\n".parse::<toml::Table>().unwrap();
+        assert!(flatten(&settings(), "", table, &mut HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn values_must_parse() {
+        assert!((setting("PORT").valid)("3000").is_ok());
+        assert!((setting("PORT").valid)("70000").is_err());
+        assert!((setting("PORT").valid)("abc").is_err());
+        assert!((setting("READ_TIMEOUT").valid)("-1").is_err());
+        assert!((setting("LOG_FORMAT").valid)("json").is_ok());
+        assert!((setting("LOG_FORMAT").valid)("xml").is_err());
+        assert!((setting("LOG_FILE").valid)("any/path").is_ok());
+    }
+}
@@ -0,0 +1,71 @@
+use std::future::Future;
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::net;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::info;
+use mio::net::UnixStream;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::low_level::pipe;
+
+use crate::reactor::REACTOR;
+use crate::settings;
+
+pub static DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolves on the first SIGINT or SIGTERM and stays resolved, the signal arrives
+/// through a pipe the reactor polls like any socket.
+pub struct Signals {
+    receiver: UnixStream,
+    received: bool,
+}
+
+impl Signals {
+    pub fn new() -> io::Result<Self> {
+        let (receiver, sender) = net::UnixStream::pair()?;
+        receiver.set_nonblocking(true)?;
+
+        for signal in [SIGINT, SIGTERM] {
+            pipe::register(signal, sender.try_clone()?)?;
+        }
+
+        Ok(Self { receiver: UnixStream::from_std(receiver), received: false })
+    }
+
+    pub fn received(&self) -> bool {
+        self.received
+    }
+}
+
+impl Future for Signals {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.received {
+            return Poll::Ready(());
+        }
+
+        match self.receiver.read(&mut [0u8; 16]) {
+            Err(er) if er.kind() == ErrorKind::WouldBlock => {
+                let waker = cx.waker().clone();
+                REACTOR.lock().unwrap().register_read(&mut self.receiver, waker);
+                Poll::Pending
+            }
+            _ => {
+                REACTOR.lock().unwrap().deregister(&mut self.receiver);
+                info!("Received a signal, shutting down");
+                self.received = true;
+                Poll::Ready(())
+            }
+        }
+    }
+}
+
+/// How long the connection being served gets to finish, `DRAIN_TIMEOUT` in milliseconds.
+pub fn drain_timeout() -> Duration {
+    settings::var("DRAIN_TIMEOUT").ok()
+        .and_then(|ms| ms.parse().ok())
+        .map_or(DRAIN_TIMEOUT, Duration::from_millis)
+}
@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::info;
+
+use crate::settings;
+
+/// Requests taking at least this many milliseconds are logged, `SLOWLOG_THRESHOLD` changes it.
+pub static SLOWLOG_THRESHOLD: u64 = 10;
+/// Entries kept before the oldest make room, `SLOWLOG_LEN` changes it.
+pub static SLOWLOG_LEN: usize = 128;
+/// Entries `slowlog get` lists without a count.
+static GET_COUNT: usize = 10;
+/// Characters of a command line kept, the rest of a long upload is cut.
+static LINE_LEN: usize = 64;
+
+/// A request that took at least the threshold.
+struct Entry {
+    id: u64,
+    time: SystemTime,
+    addr: SocketAddr,
+    line: String,
+    /// Spent waiting for a worker.
+    queued: Duration,
+    /// Spent on everything else until the reply was ready.
+    took: Duration,
+}
+
+/// The latest slow requests, newest first, for `slowlog get` and `slowlog reset`.
+pub struct Slowlog {
+    entries: Mutex<VecDeque<Entry>>,
+    next_id: AtomicU64,
+    /// In milliseconds, `config set slowlog-threshold` changes it.
+    threshold: AtomicU64,
+    capacity: usize,
+}
+
+impl Slowlog {
+    pub fn from_env() -> Arc<Self> {
+        let threshold = settings::parse("SLOWLOG_THRESHOLD", SLOWLOG_THRESHOLD);
+        let capacity = settings::parse("SLOWLOG_LEN", SLOWLOG_LEN);
+        info!("Logging the last {} requests slower than {}ms", capacity, threshold);
+        Arc::new(Self { entries: Mutex::new(VecDeque::with_capacity(capacity)), next_id: AtomicU64::new(0), threshold: AtomicU64::new(threshold), capacity })
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.threshold.load(Ordering::Relaxed)
+    }
+
+    pub fn set_threshold(&self, threshold: u64) {
+        self.threshold.store(threshold, Ordering::Relaxed);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Logs the request `line` from `addr` read at `started` if it took at least the threshold, `queued` of it waiting for a worker.
+    pub fn record(&self, addr: SocketAddr, line: &str, started: Instant, queued: Duration) {
+        let elapsed = started.elapsed();
+        if elapsed < Duration::from_millis(self.threshold()) || self.capacity == 0 {
+            return;
+        }
+
+        let took = elapsed.saturating_sub(queued);
+        let entry = Entry { id: self.next_id.fetch_add(1, Ordering::Relaxed), time: SystemTime::now(), addr, line: redact(line), queued, took };
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.truncate(self.capacity - 1);
+        entries.push_front(entry);
+    }
+
+    /// Reply to `slowlog get [n]`, a header with the number of entries followed by a line for each.
+    pub fn get(&self, count: Option<String>) -> String {
+        let count = match count {
+            Some(count) => match count.parse() {
+                Ok(count) => count,
+                Err(_) => return format!("ERR invalid count {}\n", count),
+            },
+            None => GET_COUNT,
+        };
+
+        let entries = self.entries.lock().unwrap();
+        let mut reply = format!("slowlog: {}\n", entries.len().min(count));
+        for entry in entries.iter().take(count) {
+            let time = entry.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let _ = writeln!(reply, "id={} time={}.{:03} addr={} queued={} took={} cmd={}",
+                entry.id, time.as_secs(), time.subsec_millis(), entry.addr, ms(entry.queued), ms(entry.took), entry.line);
+        }
+        reply
+    }
+
+    /// Reply to `slowlog reset`, with the number of entries dropped.
+    pub fn reset(&self) -> String {
+        let mut entries = self.entries.lock().unwrap();
+        let dropped = entries.len();
+        entries.clear();
+        format!("reset: {}\n", dropped)
+    }
+}
+
+/// `duration` in milliseconds down to the microsecond.
+fn ms(duration: Duration) -> String {
+    format!("{}.{:03}ms", duration.as_millis(), duration.subsec_micros() % 1000)
+}
+
+/// The command line as logged, without the token of an `auth` and at most `LINE_LEN` characters long.
+fn redact(line: &str) -> String {
+    let line = line.trim_end();
+    if line.starts_with("auth") {
+        return String::from("auth");
+    }
+
+    match line.char_indices().nth(LINE_LEN) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
+    }
+}
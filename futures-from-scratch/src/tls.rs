@@ -0,0 +1,164 @@
+use std::error::Error;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::Arc;
+
+use log::info;
+use mio::event::Source;
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+
+use crate::settings;
+
+/// TLS settings from `TLS_CERT` and `TLS_KEY`, PEM files holding the certificate chain and its private key.
+/// With `TLS_CLIENT_CA` set too, clients must present a certificate signed by one of the CAs in that file.
+/// None if `TLS_CERT` is not set, connections stay plain TCP then.
+pub fn from_env() -> io::Result<Option<Arc<ServerConfig>>> {
+    let Ok(cert) = settings::var("TLS_CERT") else {
+        return Ok(None);
+    };
+    let key = settings::var("TLS_KEY").map_err(|_| io::Error::new(ErrorKind::InvalidInput, "TLS_CERT is set without TLS_KEY"))?;
+
+    let chain = CertificateDer::pem_file_iter(&cert).map_err(invalid)?.collect::<Result<Vec<_>, _>>().map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_file(&key).map_err(invalid)?;
+
+    let builder = ServerConfig::builder();
+    let builder = match settings::var("TLS_CLIENT_CA") {
+        Ok(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(&path).map_err(invalid)? {
+                roots.add(ca.map_err(invalid)?).map_err(invalid)?;
+            }
+            info!("Requiring client certificates signed by {}", path);
+            builder.with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build().map_err(invalid)?)
+        },
+        Err(_) => builder.with_no_client_auth(),
+    };
+
+    info!("Serving TLS with certificate {}", cert);
+
+    Ok(Some(Arc::new(builder.with_single_cert(chain, key).map_err(invalid)?)))
+}
+
+fn invalid(err: impl Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
+
+/// Connection of a client, encrypted if the server has TLS set up.
+///
+/// Reads and writes fail with `WouldBlock` until the TLS session can move on,
+/// so the `Read` and `Write` futures wait for the socket as they would without TLS.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<ServerConnection>, TcpStream),
+}
+
+impl Stream {
+    pub fn accept(socket: TcpStream, config: Option<&Arc<ServerConfig>>) -> io::Result<Self> {
+        Ok(match config {
+            Some(config) => Stream::Tls(Box::new(ServerConnection::new(Arc::clone(config)).map_err(invalid)?), socket),
+            None => Stream::Plain(socket),
+        })
+    }
+
+    /// What to peek at for a hangup.
+    pub fn socket(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(socket) | Stream::Tls(_, socket) => socket,
+        }
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        match self {
+            Stream::Plain(_) => false,
+            Stream::Tls(conn, _) => conn.is_handshaking(),
+        }
+    }
+
+    fn socket_mut(&mut self) -> &mut TcpStream {
+        match self {
+            Stream::Plain(socket) | Stream::Tls(_, socket) => socket,
+        }
+    }
+}
+
+/// Writes out pending records until done or the socket is full.
+fn flush(conn: &mut ServerConnection, socket: &mut TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        match conn.write_tls(socket) {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            res => res?,
+        };
+    }
+    Ok(())
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (conn, socket) = match self {
+            Stream::Plain(socket) => return socket.read(buf),
+            Stream::Tls(conn, socket) => (conn, socket),
+        };
+
+        loop {
+            match conn.reader().read(buf) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {},
+                // a client closing the socket without a close_notify is taken as closing the connection
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+                res => return res,
+            }
+
+            if conn.read_tls(socket)? == 0 {
+                return Ok(0);
+            }
+
+            let processed = conn.process_new_packets();
+            // handshake messages or the alert telling the client what went wrong
+            flush(conn, socket)?;
+            processed.map_err(invalid)?;
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (conn, socket) = match self {
+            Stream::Plain(socket) => return socket.write(buf),
+            Stream::Tls(conn, socket) => (conn, socket),
+        };
+
+        // records of the last write go out first, so that a slow client holds back the next one
+        flush(conn, socket)?;
+        if conn.wants_write() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let len = conn.writer().write(buf)?;
+        flush(conn, socket)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.flush(),
+            Stream::Tls(conn, socket) => flush(conn, socket),
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.socket_mut().register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.socket_mut().reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.socket_mut().deregister(registry)
+    }
+}
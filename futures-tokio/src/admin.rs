@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{info, LevelFilter};
+use tokio::sync::Notify;
+
+use crate::connections::Connections;
+use crate::logger;
+use crate::metrics::Metrics;
+use crate::slowlog::Slowlog;
+use crate::thread_pool::Spawner;
+
+/// Commands about the server rather than its data.
+pub enum Admin {
+    Info,
+    Stats,
+    ClientList,
+    ClientKill(String),
+    ConfigGet(String),
+    ConfigSet(String, String),
+    SlowlogGet(Option<String>),
+    SlowlogReset,
+}
+
+impl Admin {
+    pub fn parse(str: &str) -> Option<Self> {
+        match str {
+            "info" => Some(Admin::Info),
+            "stats" => Some(Admin::Stats),
+            "client list" => Some(Admin::ClientList),
+            "slowlog get" => Some(Admin::SlowlogGet(None)),
+            "slowlog reset" => Some(Admin::SlowlogReset),
+            other => {
+                if let Some(addr) = other.strip_prefix("client kill ") {
+                    return Some(Admin::ClientKill(String::from(addr)));
+                }
+
+                if let Some(name) = other.strip_prefix("config get ") {
+                    return Some(Admin::ConfigGet(String::from(name)));
+                }
+
+                if let Some((name, value)) = other.strip_prefix("config set ").and_then(|rest| rest.split_once(' ')) {
+                    return Some(Admin::ConfigSet(String::from(name), String::from(value)));
+                }
+
+                if let Some(count) = other.strip_prefix("slowlog get ") {
+                    return Some(Admin::SlowlogGet(Some(String::from(count))));
+                }
+
+                None
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Admin::Info => "info",
+            Admin::Stats => "stats",
+            Admin::ClientList => "client-list",
+            Admin::ClientKill(_) => "client-kill",
+            Admin::ConfigGet(_) => "config-get",
+            Admin::ConfigSet(..) => "config-set",
+            Admin::SlowlogGet(_) => "slowlog-get",
+            Admin::SlowlogReset => "slowlog-reset",
+        }
+    }
+}
+
+/// Requests and errors per command since the server started, as counted for `/metrics`.
+pub struct Stats {
+    started: Instant,
+    metrics: Arc<Metrics>,
+}
+
+impl Stats {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { started: Instant::now(), metrics }
+    }
+
+    /// Reply to `stats`, a header with the number of commands followed by a line for each.
+    pub fn report(&self) -> String {
+        let requests = self.metrics.requests();
+        let mut report = format!("stats: {}\n", requests.len());
+        for (command, calls, errors) in requests {
+            let _ = writeln!(report, "{} calls={} errors={}", command, calls, errors);
+        }
+        report
+    }
+
+    /// Reply to `info`, about the process as a whole.
+    pub fn info(&self, clients: usize) -> String {
+        let lines = [
+            format!("uptime: {}s", self.started.elapsed().as_secs()),
+            format!("version: {}", env!("CARGO_PKG_VERSION")),
+            format!("architecture: {} ({})", env!("CARGO_PKG_NAME"), std::env::consts::ARCH),
+            format!("threads: {}", proc_status("Threads").unwrap_or_else(|| String::from("unknown"))),
+            format!("memory: {}", proc_status("VmRSS").unwrap_or_else(|| String::from("unknown"))),
+            format!("clients: {}", clients),
+        ];
+
+        format!("info: {}\n{}\n", lines.len(), lines.join("\n"))
+    }
+}
+
+/// A field of `/proc/self/status`, only there on Linux.
+fn proc_status(field: &str) -> Option<String> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status.lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .map(|value| value.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// What `client list` shows about a connection.
+struct Client {
+    addr: SocketAddr,
+    connected: Instant,
+    last_active: Instant,
+    last_command: &'static str,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Wakes the task serving the connection to close it.
+    kill: Arc<Notify>,
+}
+
+/// Every open connection, for `client list` and `client kill`.
+#[derive(Default)]
+pub struct Clients {
+    clients: Mutex<HashMap<u64, Client>>,
+    next_id: AtomicU64,
+}
+
+impl Clients {
+    /// Lists the connection from `addr` until the returned guard is dropped.
+    pub fn register(self: &Arc<Self>, addr: SocketAddr) -> Registered {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let kill = Arc::new(Notify::new());
+
+        self.clients.lock().unwrap().insert(id, Client { addr, connected: now, last_active: now, last_command: "none", bytes_in: 0, bytes_out: 0, kill: Arc::clone(&kill) });
+
+        Registered { clients: Arc::clone(self), id, kill }
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Reply to `client list`, a header with the number of clients followed by a line for each.
+    pub fn list(&self) -> String {
+        let clients = self.clients.lock().unwrap();
+        let mut clients = clients.values().collect::<Vec<_>>();
+        clients.sort_by_key(|client| client.connected);
+
+        let mut list = format!("clients: {}\n", clients.len());
+        for client in clients {
+            let _ = writeln!(list, "addr={} age={}s idle={}s cmd={} in={} out={}",
+                client.addr, client.connected.elapsed().as_secs(), client.last_active.elapsed().as_secs(),
+                client.last_command, client.bytes_in, client.bytes_out);
+        }
+        list
+    }
+
+    /// Reply to `client kill <addr>`, the task serving the client closes it at its next await.
+    pub fn kill(&self, addr: &str) -> String {
+        let Ok(addr) = addr.parse::<SocketAddr>() else {
+            return format!("ERR invalid address {}\n", addr);
+        };
+
+        match self.clients.lock().unwrap().values().find(|client| client.addr == addr) {
+            Some(client) => {
+                info!("Killing client {}", addr);
+                // the permit is kept if the task is not waiting right now
+                client.kill.notify_one();
+                format!("killed: {}\n", addr)
+            },
+            None => format!("ERR no client {}\n", addr),
+        }
+    }
+
+    fn update(&self, id: u64, update: impl FnOnce(&mut Client)) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            update(client);
+        }
+    }
+}
+
+/// Place of a connection in the client list, given up once dropped.
+pub struct Registered {
+    clients: Arc<Clients>,
+    id: u64,
+    kill: Arc<Notify>,
+}
+
+impl Registered {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Notes a request of `len` bytes.
+    pub fn record(&self, command: &'static str, len: usize) {
+        self.clients.update(self.id, |client| {
+            client.last_active = Instant::now();
+            client.last_command = command;
+            client.bytes_in += len as u64;
+        });
+    }
+
+    pub fn sent(&self, len: usize) {
+        self.clients.update(self.id, |client| client.bytes_out += len as u64);
+    }
+
+    /// Resolves once `client kill` picked this connection.
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.clients.clients.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// What admin commands look at, shared by every connection task.
+pub struct Server {
+    pub connections: Arc<Connections>,
+    pub stats: Stats,
+    pub metrics: Arc<Metrics>,
+    pub clients: Arc<Clients>,
+    pub config: Config,
+    pub slowlog: Arc<Slowlog>,
+    /// Threads computing primes.
+    pub pool: Spawner,
+    /// Bytes read from a client at once.
+    pub read_buffer: usize,
+}
+
+impl Server {
+    pub fn run(&self, command: Admin) -> String {
+        match command {
+            Admin::Info => {
+                let clients = self.clients.len();
+                self.stats.info(clients)
+            },
+            Admin::Stats => self.stats.report(),
+            Admin::ClientList => self.clients.list(),
+            Admin::ClientKill(addr) => self.clients.kill(&addr),
+            Admin::ConfigGet(name) => self.config.get(&name),
+            Admin::ConfigSet(name, value) => self.config.set(&name, &value),
+            Admin::SlowlogGet(count) => self.slowlog.get(count),
+            Admin::SlowlogReset => self.slowlog.reset(),
+        }
+    }
+}
+
+/// Settings for `config get` and `config set`.
+///
+/// Most are fixed once the server started and only shown, `loglevel`, `max-connections` and `slowlog-threshold` can be changed.
+pub struct Config {
+    fixed: Vec<(&'static str, String)>,
+    connections: Arc<Connections>,
+    slowlog: Arc<Slowlog>,
+}
+
+impl Config {
+    pub fn new(fixed: Vec<(&'static str, String)>, connections: Arc<Connections>, slowlog: Arc<Slowlog>) -> Self {
+        Self { fixed, connections, slowlog }
+    }
+
+    /// Reply to `config get <name>`, `*` lists every setting.
+    pub fn get(&self, name: &str) -> String {
+        let settings = self.settings();
+        let found = settings.iter().filter(|(setting, _)| name == "*" || *setting == name).collect::<Vec<_>>();
+
+        if found.is_empty() {
+            return format!("ERR unknown setting {}\n", name);
+        }
+
+        let mut reply = format!("config: {}\n", found.len());
+        for (setting, value) in found {
+            let _ = writeln!(reply, "{} {}", setting, value);
+        }
+        reply
+    }
+
+    /// Reply to `config set <name> <value>`.
+    pub fn set(&self, name: &str, value: &str) -> String {
+        let res = match name {
+            "loglevel" => LevelFilter::from_str(value).map(logger::set_level).map_err(|_| format!("invalid level {}", value)),
+            "max-connections" => value.parse().map(|max| self.connections.set_max(max)).map_err(|_| format!("invalid number {}", value)),
+            "slowlog-threshold" => value.parse().map(|ms| self.slowlog.set_threshold(ms)).map_err(|_| format!("invalid number {}", value)),
+            name if self.fixed.iter().any(|(setting, _)| *setting == name) => Err(format!("{} cannot be changed while running", name)),
+            name => Err(format!("unknown setting {}", name)),
+        };
+
+        match res {
+            Ok(()) => {
+                info!("Set {} to {}", name, value);
+                format!("set: {} {}\n", name, value)
+            },
+            Err(err) => format!("ERR {}\n", err),
+        }
+    }
+
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        let mut settings = vec![
+            ("loglevel", logger::level().to_string().to_lowercase()),
+            ("max-connections", self.connections.max().to_string()),
+            ("slowlog-threshold", self.slowlog.threshold().to_string()),
+        ];
+        settings.extend(self.fixed.iter().cloned());
+        settings
+    }
+}
+
+/// A timeout as its variable takes it, in milliseconds with 0 for none.
+pub fn millis(timeout: Option<Duration>) -> String {
+    timeout.map_or(0, |timeout| timeout.as_millis()).to_string()
+}
@@ -0,0 +1,92 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+use crate::settings;
+use crate::tls::Stream;
+
+pub static MAX_CONNECTIONS: usize = 1024;
+pub static LISTEN_BACKLOG: u32 = 1024;
+/// How long a refused client gets to take the reply.
+static REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Binds `addr` with room for `LISTEN_BACKLOG` connections the kernel keeps until they are accepted.
+pub fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let backlog = settings::parse("LISTEN_BACKLOG", LISTEN_BACKLOG);
+
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+
+    info!("Listening with a backlog of {}", backlog);
+
+    socket.listen(backlog)
+}
+
+/// Gauge of the connections being served, at most `MAX_CONNECTIONS` of them at once.
+pub struct Connections {
+    current: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl Connections {
+    pub fn from_env() -> Arc<Self> {
+        let max = settings::parse("MAX_CONNECTIONS", MAX_CONNECTIONS);
+        info!("Serving up to {} connections", max);
+        Arc::new(Self { current: AtomicUsize::new(0), max: AtomicUsize::new(max) })
+    }
+
+    /// Counts a new connection in, None if all places are taken.
+    pub fn admit(self: &Arc<Self>) -> Option<Slot> {
+        self.current.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| (current < self.max()).then_some(current + 1)).ok()?;
+        Some(Slot { connections: Arc::clone(self) })
+    }
+
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    pub fn max(&self) -> usize {
+        self.max.load(Ordering::SeqCst)
+    }
+
+    /// Changes the limit, connections over a lowered one stay open until they close.
+    pub fn set_max(&self, max: usize) {
+        self.max.store(max, Ordering::SeqCst);
+    }
+}
+
+/// Place of one connection, given back once dropped.
+pub struct Slot {
+    connections: Arc<Connections>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.connections.current.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Tells a client there is no place for it before closing the connection, after the handshake if it speaks TLS.
+pub async fn refuse(stream: TcpStream, acceptor: Option<TlsAcceptor>) -> io::Result<()> {
+    let refusal = async {
+        match acceptor {
+            Some(acceptor) => reply(acceptor.accept(stream).await?).await,
+            None => reply(stream).await,
+        }
+    };
+
+    tokio::time::timeout(REFUSE_TIMEOUT, refusal).await.unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+async fn reply(mut stream: impl Stream) -> io::Result<()> {
+    stream.write_all(b"ERR server-busy\n").await?;
+    stream.shutdown().await
+}
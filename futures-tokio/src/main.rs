@@ -1,42 +1,273 @@
+mod admin;
+mod cancel;
+mod connections;
 mod handler;
 mod logger;
+mod metrics;
+mod primes;
+mod roles;
+mod session;
+mod settings;
+mod shutdown;
+mod slowlog;
+mod thread_pool;
+mod timeouts;
+mod tls;
+mod trace;
 
-use std::{io::Result, net::SocketAddr};
+use std::{io::{ErrorKind, Result}, net::SocketAddr, num::NonZeroUsize, sync::Arc, thread, time::{Duration, Instant}};
 
 use log::{info, warn};
-use tokio::{net::{TcpListener, TcpStream}, io::{AsyncReadExt, AsyncWriteExt}};
+use tokio::{net::TcpStream, io::{AsyncReadExt, AsyncWriteExt}, runtime, sync::{broadcast, mpsc, oneshot}};
+use tokio_rustls::TlsAcceptor;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+use crate::admin::{Clients, Config, Registered, Server, Stats};
+use crate::cancel::Cancel;
+use crate::connections::Connections;
+use crate::handler::Reply;
+use crate::metrics::Metrics;
+use crate::primes::Progress;
+use crate::session::{Session, Tokens};
+use crate::slowlog::Slowlog;
+use crate::thread_pool::{Spawner, ThreadPool};
+use crate::timeouts::{Phase, Timeouts};
+use crate::tls::Stream;
+use crate::trace::Queued;
+
+pub static PORT: u16 = 3000;
+pub static READ_BUFFER: usize = 512;
+
+fn main() -> Result<()> {
+    settings::load();
     logger::setup().unwrap();
+    let _trace = trace::setup().unwrap();
+
+    let mut builder = runtime::Builder::new_multi_thread();
+    if let Some(threads) = settings::var("THREADS").ok().and_then(|threads| threads.parse::<usize>().ok()) {
+        builder.worker_threads(threads.max(1));
+    }
+
+    let cores = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let compute_threads = settings::var("COMPUTE_THREADS").ok().and_then(|threads| threads.parse::<usize>().ok()).unwrap_or(cores).max(1);
+    let pool = ThreadPool::new(compute_threads);
+
+    let runtime = builder.enable_all().build()?;
+    let res = runtime.block_on(serve(pool.spawner()));
+    // the tasks dropped along with the runtime cancel their computes, which lets the workers exit
+    drop(runtime);
+    drop(pool);
+
+    info!("Server stopped");
+    logger::flush();
+    res
+}
 
-    let listener = TcpListener::bind("127.0.0.1:3000").await?;
+async fn serve(pool: Spawner) -> Result<()> {
+    let tokens = Arc::new(Tokens::from_env()?);
+    let acceptor = tls::from_env()?.map(TlsAcceptor::from);
+    let connections = Connections::from_env();
+    let timeouts = Timeouts::from_env();
+    let addr = settings::bind(settings::parse("PORT", PORT))?;
+    let read_buffer = settings::parse("READ_BUFFER", READ_BUFFER).max(1);
+    let listener = connections::listen(addr)?;
+
+    let metrics = Arc::new(Metrics::default());
+    metrics::serve(Arc::clone(&metrics)).await?;
+
+    let slowlog = Slowlog::from_env();
+    let server = Arc::new(Server {
+        connections: Arc::clone(&connections),
+        stats: Stats::new(Arc::clone(&metrics)),
+        metrics,
+        clients: Arc::new(Clients::default()),
+        config: Config::new(vec![
+            ("bind", addr.ip().to_string()),
+            ("port", addr.port().to_string()),
+            ("read-buffer", read_buffer.to_string()),
+            ("idle-timeout", admin::millis(timeouts.idle)),
+            ("read-timeout", admin::millis(timeouts.read)),
+            ("write-timeout", admin::millis(timeouts.write)),
+            ("tls", if acceptor.is_some() { "on" } else { "off" }.to_string()),
+            ("slowlog-max-len", slowlog.capacity().to_string()),
+        ], Arc::clone(&connections), Arc::clone(&slowlog)),
+        slowlog,
+        pool,
+        read_buffer,
+    });
+
+    // every connection task holds a sender, the receiver ends once they are all gone
+    let (notify, _) = broadcast::channel(1);
+    let (open, mut drained) = mpsc::channel::<()>(1);
+    let requested = shutdown::requested();
+    tokio::pin!(requested);
+    let mut accepted = 0;
 
     loop {
-        let (stream, addr) = listener.accept().await?;
-        tokio::spawn(async move {
-            info!("Connection from {}:{}", addr.ip(), addr.port());
-            process(stream, addr).await.unwrap_or_else(|err| warn!("Error: {}", err));
-        });
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => res?,
+            res = &mut requested => {
+                res?;
+                break;
+            },
+        };
+        let acceptor = acceptor.clone();
+
+        let Some(slot) = connections.admit() else {
+            warn!("Refusing {}:{}, {} connections are open", addr.ip(), addr.port(), connections.current());
+            tokio::spawn(async move {
+                connections::refuse(stream, acceptor).await.unwrap_or_else(|err| warn!("Could not refuse: {}", err));
+            });
+            continue;
+        };
+
+        let session = Session::new(Arc::clone(&tokens), addr);
+        let server = Arc::clone(&server);
+        let (mut shutdown, open) = (notify.subscribe(), open.clone());
+        accepted += 1;
+        tokio::spawn(logger::connection(accepted, async move {
+            logger::sampled!("Connection from {}:{} ({} open)", addr.ip(), addr.port(), server.connections.current());
+            let registered = server.clients.register(addr);
+            server.metrics.accepted();
+            let res = match acceptor {
+                Some(acceptor) => match timeouts.limit(Phase::Read, acceptor.accept(stream)).await {
+                    Ok(stream) => process(stream, addr, session, &server, &registered, timeouts, &mut shutdown).await,
+                    Err(err) => Err(err),
+                },
+                None => process(stream, addr, session, &server, &registered, timeouts, &mut shutdown).await,
+            };
+            drop((slot, open, registered));
+            server.metrics.closed();
+            match res {
+                Err(err) if err.kind() == ErrorKind::TimedOut => warn!("Closing {}:{}, {}", addr.ip(), addr.port(), err),
+                res => res.unwrap_or_else(|err| warn!("Error: {}", err)),
+            }
+        }));
     }
+
+    drop(listener);
+    let timeout = shutdown::drain_timeout();
+    info!("Draining {} connections for up to {:?}", connections.current(), timeout);
+
+    let _ = notify.send(());
+    drop(open);
+    if tokio::time::timeout(timeout, drained.recv()).await.is_err() {
+        // the tasks are dropped along with the runtime, which cancels their computes
+        warn!("Closing {} connections still busy after the drain timeout", connections.current());
+    }
+
+    Ok(())
 }
 
-async fn process(mut stream: TcpStream, addr: SocketAddr) -> Result<()> {
-    let mut buf = [0u8; 512];
+async fn process(mut stream: impl Stream, addr: SocketAddr, mut session: Session, server: &Server, registered: &Registered, timeouts: Timeouts, shutdown: &mut broadcast::Receiver<()>) -> Result<()> {
+    let mut buf = vec![0u8; server.read_buffer];
+    let span = trace::connection(registered.id());
 
-    loop {
-        let len = stream.read(&mut buf).await?;
+    'requests: loop {
+        // a TLS client closing the socket without a close_notify is taken as closing the connection
+        let len = tokio::select! {
+            res = timeouts.limit(Phase::Idle, stream.read(&mut buf)) => match res {
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => 0,
+                res => res?,
+            },
+            // also taken while the last request ran, the notice waits in the channel
+            _ = shutdown.recv() => {
+                info!("Sending bye to {}:{}", addr.ip(), addr.port());
+                let _ = timeouts.limit(Phase::Write, async { stream.write_all(b"bye\n").await?; stream.shutdown().await }).await;
+                break;
+            },
+            _ = registered.killed() => {
+                info!("Closing {}:{}, killed", addr.ip(), addr.port());
+                break;
+            },
+        };
 
         if len == 0 {
             break;
         }
 
-        let res = handler::handle(String::from_utf8_lossy(&buf[..len]).to_string());
+        let message = String::from_utf8_lossy(&buf[..len]).to_string();
+        let name = handler::name(&message);
+        // dropped with the reply written, a connection closing mid-request ends it as well
+        let request = trace::request(&span, registered.id(), name);
+        registered.record(name, len);
+        server.metrics.received(len);
+        let started = Instant::now();
+        let mut waited = Duration::ZERO;
+
+        let res = match handler::handle(message, &mut session, server) {
+            Reply::Ready(response) => response,
+
+            Reply::Compute(k, timeout, progress) => {
+                let cancel = Cancel::new(timeout.map(|timeout| Instant::now() + timeout));
+                let (worker_cancel, _guard) = (cancel.clone(), cancel.guard());
+
+                let (sender, mut updates) = mpsc::unbounded_channel();
+                let report = progress.then(|| Arc::new(move |percent| { let _ = sender.send(percent); }) as Progress);
 
-        stream.write_all(res.as_bytes()).await?;
+                // runs on the compute pool so the runtime workers keep serving other connections
+                let mut pooled = server.metrics.pooled();
+                let (queued, submitted) = (Queued::new(&request), Instant::now());
+                let (done, mut job) = oneshot::channel();
+                let spawner = server.pool.clone();
+                server.pool.execute(&cancel, move || {
+                    let wait = submitted.elapsed();
+                    let _job = queued.start();
+                    pooled.start();
+                    let _ = done.send((wait, handler::compute(k, &worker_cancel, &spawner, report)));
+                })?;
+
+                loop {
+                    tokio::select! {
+                        // progress goes out before a result that is ready at the same time
+                        biased;
+
+                        Some(percent) = updates.recv() => {
+                            let line = handler::progress(percent);
+                            registered.sent(line.len());
+                            server.metrics.sent(line.len());
+                            timeouts.limit(Phase::Write, stream.write_all(line.as_bytes())).await?
+                        },
+                        res = &mut job => break match res {
+                            Ok((wait, response)) => {
+                                waited = wait;
+                                response
+                            },
+                            // the pool dropped the job, which only happens once the server stops
+                            Err(err) => format!("ERR compute failed: {}\n", err),
+                        },
+                        _ = hangup(stream.socket()) => {
+                            cancel.cancel();
+                            break 'requests;
+                        },
+                        _ = registered.killed() => {
+                            info!("Closing {}:{}, killed", addr.ip(), addr.port());
+                            cancel.cancel();
+                            break 'requests;
+                        },
+                    }
+                }
+            },
+        };
+
+        server.metrics.request(name, &res, started.elapsed());
+        server.slowlog.record(addr, &String::from_utf8_lossy(&buf[..len]), started, waited);
+        registered.sent(res.len());
+        server.metrics.sent(res.len());
+        timeouts.limit(Phase::Write, stream.write_all(res.as_bytes())).await?;
     }
 
     info!("Disconnected {}:{}", addr.ip(), addr.port());
 
     Ok(())
 }
+
+/// Resolves once the peer closes the connection, a pipelined request is left in the socket.
+/// Over TLS the client's close_notify looks like such a request, only closing the socket itself counts.
+async fn hangup(stream: &TcpStream) {
+    let mut buf = [0u8; 1];
+
+    match stream.peek(&mut buf).await {
+        Ok(0) | Err(_) => {},
+        Ok(_) => std::future::pending().await,
+    }
+}
@@ -0,0 +1,30 @@
+use std::io;
+use std::time::Duration;
+
+use log::info;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::settings;
+
+pub static DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolves on the first SIGINT or SIGTERM.
+pub async fn requested() -> io::Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    let name = tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    };
+    info!("Received {}, shutting down", name);
+
+    Ok(())
+}
+
+/// How long open connections get to finish, `DRAIN_TIMEOUT` in milliseconds.
+pub fn drain_timeout() -> Duration {
+    settings::var("DRAIN_TIMEOUT").ok()
+        .and_then(|ms| ms.parse().ok())
+        .map_or(DRAIN_TIMEOUT, Duration::from_millis)
+}
@@ -0,0 +1,245 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use log::info;
+
+use crate::cancel::{Cancel, Stopped};
+use crate::logger;
+
+/// Chunks of a fork_join in flight at once.
+static WINDOW: usize = 64;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+enum Task {
+    /// Skipped if cancelled while still queued.
+    New(Job, Cancel),
+    Exit,
+}
+
+struct Worker {
+    id: usize,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Task>>>, load: Arc<AtomicUsize>) -> Self {
+        let thread = Some(thread::spawn(move || loop {
+            let task = { receiver.lock().unwrap().recv().unwrap() };
+
+            match task {
+                Task::New(_, cancel) if cancel.is_cancelled() => {
+                    info!("Worker {} dropped cancelled job", id);
+                }
+                Task::New(job, _) => {
+                    logger::sampled!("Worker {} received job", id);
+                    job();
+                }
+                Task::Exit => break,
+            }
+
+            load.fetch_sub(1, Ordering::Relaxed);
+        }));
+
+        Self { id, thread }
+    }
+}
+
+/// Fixed number of threads computing primes, so that computes never take threads from the runtime
+/// and never outnumber the cores. Jobs hand their results back through a channel of their own.
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    spawner: Spawner,
+}
+
+impl ThreadPool {
+    pub fn new(n: usize) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let load = Arc::new(AtomicUsize::new(0));
+
+        let mut workers = Vec::with_capacity(n);
+        for i in 0..n {
+            workers.push(Worker::new(i, Arc::clone(&receiver), Arc::clone(&load)));
+        }
+
+        info!("Computing on {} threads", n);
+
+        ThreadPool { workers, spawner: Spawner { sender, load, size: n } }
+    }
+
+    /// Handle for submitting jobs from the connection tasks and the workers themselves.
+    pub fn spawner(&self) -> Spawner {
+        self.spawner.clone()
+    }
+}
+
+#[derive(Clone)]
+pub struct Spawner {
+    sender: mpsc::Sender<Task>,
+    /// Jobs queued or running.
+    load: Arc<AtomicUsize>,
+    size: usize,
+}
+
+impl Spawner {
+    /// Queues `f`, which is dropped without running if `cancel` fires first.
+    /// Fails once the workers are gone, which a task outliving the pool at shutdown may see.
+    pub fn execute<F>(&self, cancel: &Cancel, f: F) -> io::Result<()>
+    where
+        F: FnOnce() + 'static + Send,
+    {
+        self.load.fetch_add(1, Ordering::Relaxed);
+        if self.sender.send(Task::New(Box::new(f), cancel.clone())).is_err() {
+            self.load.fetch_sub(1, Ordering::Relaxed);
+            return Err(io::Error::other("thread pool is gone"));
+        }
+        Ok(())
+    }
+
+    /// Workers with nothing to do right now.
+    pub fn idle(&self) -> usize {
+        self.size.saturating_sub(self.load.load(Ordering::Relaxed))
+    }
+    /// Runs `work` for every index in `0..chunks` and hands the results to `join` in index order,
+    /// stopping at the first chunk that gave up.
+    ///
+    /// The calling thread works through the chunks itself, helped by as many workers as are
+    /// idle at the time. Helpers take one chunk per job and queue up again behind whatever
+    /// else was submitted meanwhile, so small jobs are not starved. The caller only ever waits
+    /// for chunks a running thread picked up, so calling this from a worker of a saturated
+    /// pool can not deadlock. Chunks run `WINDOW` at a time, which bounds the results held
+    /// however many chunks there are.
+    pub fn fork_join<T, F, J>(&self, chunks: usize, cancel: &Cancel, work: F, mut join: J) -> Result<(), Stopped>
+    where
+        T: Send + 'static,
+        F: Fn(usize) -> Result<T, Stopped> + Send + Sync + 'static,
+        J: FnMut(T),
+    {
+        let work = Arc::new(work);
+
+        for first in (0..chunks).step_by(WINDOW) {
+            cancel.check()?;
+
+            let size = WINDOW.min(chunks - first);
+            let fork = Arc::new(Fork {
+                work: Arc::clone(&work),
+                cancel: cancel.clone(),
+                first,
+                size,
+                next: AtomicUsize::new(0),
+                results: Mutex::new((0..size).map(|_| None).collect()),
+                finished: Condvar::new(),
+            });
+
+            for _ in 0..self.idle().min(size - 1) {
+                Arc::clone(&fork).help(self.clone());
+            }
+
+            while fork.step() {}
+
+            let mut results = fork.results.lock().unwrap();
+            while results.iter().any(Option::is_none) {
+                results = fork.finished.wait(results).unwrap();
+            }
+
+            for result in results.drain(..).flatten() {
+                join(result?);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// One window of a fork_join, the chunks `first..first + size`.
+struct Fork<T, F> {
+    work: Arc<F>,
+    cancel: Cancel,
+    first: usize,
+    size: usize,
+    next: AtomicUsize,
+    results: Mutex<Vec<Option<Result<T, Stopped>>>>,
+    finished: Condvar,
+}
+
+impl<T, F> Fork<T, F>
+where
+    T: Send + 'static,
+    F: Fn(usize) -> Result<T, Stopped> + Send + Sync + 'static,
+{
+    /// Runs the next chunk, false once none are left. Chunks left once cancelled give up without running.
+    fn step(&self) -> bool {
+        let chunk = self.next.fetch_add(1, Ordering::Relaxed);
+        if chunk >= self.size {
+            return false;
+        }
+
+        let result = self.cancel.check().and_then(|()| (self.work)(self.first + chunk));
+        self.results.lock().unwrap()[chunk] = Some(result);
+        self.finished.notify_all();
+
+        true
+    }
+
+    /// Runs chunks one job at a time, a pool that is gone leaves them all to the caller.
+    fn help(self: Arc<Self>, pool: Spawner) {
+        let _ = pool.clone().execute(&self.cancel.clone(), move || {
+            if self.step() {
+                self.help(pool);
+            }
+        });
+    }
+}
+
+
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        for _ in &self.workers {
+            let _ = self.spawner.sender.send(Task::Exit);
+        }
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                info!("Joining thread {}", &worker.id);
+                thread.join().unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn skips_cancelled_jobs_and_refuses_once_gone() {
+        let pool = ThreadPool::new(1);
+        let spawner = pool.spawner();
+        let (sender, ran) = mpsc::channel();
+
+        // holds the only worker until the cancelled job is queued behind it
+        let (release, held) = mpsc::channel::<()>();
+        spawner.execute(&Cancel::default(), move || held.recv().unwrap()).unwrap();
+
+        let cancel = Cancel::default();
+        let cancelled = sender.clone();
+        spawner.execute(&cancel, move || cancelled.send("cancelled").unwrap()).unwrap();
+        let done = sender.clone();
+        spawner.execute(&Cancel::default(), move || done.send("done").unwrap()).unwrap();
+
+        cancel.cancel();
+        release.send(()).unwrap();
+        assert_eq!(ran.recv_timeout(Duration::from_secs(1)), Ok("done"));
+
+        drop(pool);
+        assert!(spawner.execute(&Cancel::default(), move || sender.send("late").unwrap()).is_err());
+        assert!(ran.try_recv().is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use log::info;
+
+use crate::settings;
+
+pub static IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+pub static READ_TIMEOUT: Duration = Duration::from_secs(10);
+pub static WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a connection is waiting on the client for.
+#[derive(Clone, Copy)]
+pub enum Phase {
+    /// The next request.
+    Idle,
+    /// The rest of the TLS handshake.
+    Read,
+    /// Room in the socket for the reply.
+    Write,
+}
+
+/// How long a client may keep a connection in each phase, None for as long as it likes.
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    pub idle: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
+
+fn from_env(name: &str, default: Duration) -> Option<Duration> {
+    match settings::parse(name, default.as_millis() as u64) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+impl Timeouts {
+    /// Reads `IDLE_TIMEOUT`, `READ_TIMEOUT` and `WRITE_TIMEOUT` in milliseconds, 0 turns a timeout off.
+    pub fn from_env() -> Self {
+        let timeouts = Self {
+            idle: from_env("IDLE_TIMEOUT", IDLE_TIMEOUT),
+            read: from_env("READ_TIMEOUT", READ_TIMEOUT),
+            write: from_env("WRITE_TIMEOUT", WRITE_TIMEOUT),
+        };
+
+        info!("Timeouts: idle {:?}, read {:?}, write {:?}", timeouts.idle, timeouts.read, timeouts.write);
+
+        timeouts
+    }
+
+    /// Runs `io`, failing with `TimedOut` if the client keeps it in `phase` for longer than allowed.
+    pub async fn limit<T>(&self, phase: Phase, io: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+        let after = match phase {
+            Phase::Idle => self.idle,
+            Phase::Read => self.read,
+            Phase::Write => self.write,
+        };
+
+        let Some(after) = after else {
+            return io.await;
+        };
+
+        tokio::time::timeout(after, io).await.unwrap_or_else(|_| {
+            let message = match phase {
+                Phase::Idle => format!("idle for {:?}", after),
+                Phase::Read => format!("no full request within {:?}", after),
+                Phase::Write => format!("the reply was not taken within {:?}", after),
+            };
+            Err(io::Error::new(io::ErrorKind::TimedOut, message))
+        })
+    }
+}
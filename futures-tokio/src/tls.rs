@@ -0,0 +1,65 @@
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+
+use log::info;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+
+use crate::settings;
+
+/// TLS settings from `TLS_CERT` and `TLS_KEY`, PEM files holding the certificate chain and its private key.
+/// With `TLS_CLIENT_CA` set too, clients must present a certificate signed by one of the CAs in that file.
+/// None if `TLS_CERT` is not set, connections stay plain TCP then.
+pub fn from_env() -> io::Result<Option<Arc<ServerConfig>>> {
+    let Ok(cert) = settings::var("TLS_CERT") else {
+        return Ok(None);
+    };
+    let key = settings::var("TLS_KEY").map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "TLS_CERT is set without TLS_KEY"))?;
+
+    let chain = CertificateDer::pem_file_iter(&cert).map_err(invalid)?.collect::<Result<Vec<_>, _>>().map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_file(&key).map_err(invalid)?;
+
+    let builder = ServerConfig::builder();
+    let builder = match settings::var("TLS_CLIENT_CA") {
+        Ok(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(&path).map_err(invalid)? {
+                roots.add(ca.map_err(invalid)?).map_err(invalid)?;
+            }
+            info!("Requiring client certificates signed by {}", path);
+            builder.with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build().map_err(invalid)?)
+        },
+        Err(_) => builder.with_no_client_auth(),
+    };
+
+    info!("Serving TLS with certificate {}", cert);
+
+    Ok(Some(Arc::new(builder.with_single_cert(chain, key).map_err(invalid)?)))
+}
+
+fn invalid(err: impl Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Connection of a client, plain or over TLS, along with the socket under it.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin {
+    fn socket(&self) -> &TcpStream;
+}
+
+impl Stream for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl Stream for TlsStream<TcpStream> {
+    fn socket(&self) -> &TcpStream {
+        self.get_ref().0
+    }
+}
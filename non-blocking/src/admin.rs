@@ -0,0 +1,232 @@
+use std::fmt::Write;
+use std::fs;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{info, LevelFilter};
+
+use crate::logger;
+use crate::metrics::Metrics;
+use crate::slowlog::Slowlog;
+
+/// Commands about the server rather than its data, answered by the loop that knows every connection.
+pub enum Admin {
+    Info,
+    Stats,
+    ClientList,
+    ClientKill(String),
+    ConfigGet(String),
+    ConfigSet(String, String),
+    SlowlogGet(Option<String>),
+    SlowlogReset,
+}
+
+impl Admin {
+    pub fn parse(str: &str) -> Option<Self> {
+        match str {
+            "info" => Some(Admin::Info),
+            "stats" => Some(Admin::Stats),
+            "client list" => Some(Admin::ClientList),
+            "slowlog get" => Some(Admin::SlowlogGet(None)),
+            "slowlog reset" => Some(Admin::SlowlogReset),
+            other => {
+                if let Some(addr) = other.strip_prefix("client kill ") {
+                    return Some(Admin::ClientKill(String::from(addr)));
+                }
+
+                if let Some(name) = other.strip_prefix("config get ") {
+                    return Some(Admin::ConfigGet(String::from(name)));
+                }
+
+                if let Some((name, value)) = other.strip_prefix("config set ").and_then(|rest| rest.split_once(' ')) {
+                    return Some(Admin::ConfigSet(String::from(name), String::from(value)));
+                }
+
+                if let Some(count) = other.strip_prefix("slowlog get ") {
+                    return Some(Admin::SlowlogGet(Some(String::from(count))));
+                }
+
+                None
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Admin::Info => "info",
+            Admin::Stats => "stats",
+            Admin::ClientList => "client-list",
+            Admin::ClientKill(_) => "client-kill",
+            Admin::ConfigGet(_) => "config-get",
+            Admin::ConfigSet(..) => "config-set",
+            Admin::SlowlogGet(_) => "slowlog-get",
+            Admin::SlowlogReset => "slowlog-reset",
+        }
+    }
+}
+
+/// Requests and errors per command since the server started, as counted for `/metrics`.
+pub struct Stats {
+    started: Instant,
+    metrics: Arc<Metrics>,
+}
+
+impl Stats {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { started: Instant::now(), metrics }
+    }
+
+    /// Reply to `stats`, a header with the number of commands followed by a line for each.
+    pub fn report(&self) -> String {
+        let requests = self.metrics.requests();
+        let mut report = format!("stats: {}\n", requests.len());
+        for (command, calls, errors) in requests {
+            let _ = writeln!(report, "{} calls={} errors={}", command, calls, errors);
+        }
+        report
+    }
+
+    /// Reply to `info`, about the process as a whole.
+    pub fn info(&self, clients: usize) -> String {
+        let lines = [
+            format!("uptime: {}s", self.started.elapsed().as_secs()),
+            format!("version: {}", env!("CARGO_PKG_VERSION")),
+            format!("architecture: {} ({})", env!("CARGO_PKG_NAME"), std::env::consts::ARCH),
+            format!("threads: {}", proc_status("Threads").unwrap_or_else(|| String::from("unknown"))),
+            format!("memory: {}", proc_status("VmRSS").unwrap_or_else(|| String::from("unknown"))),
+            format!("clients: {}", clients),
+        ];
+
+        format!("info: {}\n{}\n", lines.len(), lines.join("\n"))
+    }
+}
+
+/// A field of `/proc/self/status`, only there on Linux.
+fn proc_status(field: &str) -> Option<String> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status.lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .map(|value| value.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// What `client list` shows about a connection, kept along with it.
+pub struct Client {
+    connected: Instant,
+    last_active: Instant,
+    last_command: &'static str,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Set by `client kill`, the loop closes the connection at the end of its turn.
+    pub killed: bool,
+}
+
+impl Client {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self { connected: now, last_active: now, last_command: "none", bytes_in: 0, bytes_out: 0, killed: false }
+    }
+
+    pub fn record(&mut self, command: &'static str) {
+        self.last_active = Instant::now();
+        self.last_command = command;
+    }
+}
+
+/// Reply to `client list`, a header with the number of clients followed by a line for each.
+pub fn list<'a>(clients: impl Iterator<Item = (SocketAddr, &'a Client)>) -> String {
+    let mut clients = clients.collect::<Vec<_>>();
+    clients.sort_by_key(|(_, client)| client.connected);
+
+    let mut list = format!("clients: {}\n", clients.len());
+    for (addr, client) in clients {
+        let _ = writeln!(list, "addr={} age={}s idle={}s cmd={} in={} out={}",
+            addr, client.connected.elapsed().as_secs(), client.last_active.elapsed().as_secs(),
+            client.last_command, client.bytes_in, client.bytes_out);
+    }
+    list
+}
+
+/// Reply to `client kill <addr>`, marks the client connected from `addr` for closing.
+pub fn kill<'a>(addr: &str, mut clients: impl Iterator<Item = (SocketAddr, &'a mut Client)>) -> String {
+    let Ok(addr) = addr.parse::<SocketAddr>() else {
+        return format!("ERR invalid address {}\n", addr);
+    };
+
+    match clients.find(|(peer, _)| *peer == addr) {
+        Some((_, client)) => {
+            info!("Killing client {}", addr);
+            client.killed = true;
+            format!("killed: {}\n", addr)
+        },
+        None => format!("ERR no client {}\n", addr),
+    }
+}
+
+/// Settings for `config get` and `config set`.
+///
+/// All but `loglevel` and `slowlog-threshold` are fixed once the server started and only shown.
+pub struct Config {
+    fixed: Vec<(&'static str, String)>,
+    slowlog: Arc<Slowlog>,
+}
+
+impl Config {
+    pub fn new(fixed: Vec<(&'static str, String)>, slowlog: Arc<Slowlog>) -> Self {
+        Self { fixed, slowlog }
+    }
+
+    /// The slow log, whose threshold is one of the settings.
+    pub fn slowlog(&self) -> &Slowlog {
+        &self.slowlog
+    }
+
+    /// Reply to `config get <name>`, `*` lists every setting.
+    pub fn get(&self, name: &str) -> String {
+        let settings = self.settings();
+        let found = settings.iter().filter(|(setting, _)| name == "*" || *setting == name).collect::<Vec<_>>();
+
+        if found.is_empty() {
+            return format!("ERR unknown setting {}\n", name);
+        }
+
+        let mut reply = format!("config: {}\n", found.len());
+        for (setting, value) in found {
+            let _ = writeln!(reply, "{} {}", setting, value);
+        }
+        reply
+    }
+
+    /// Reply to `config set <name> <value>`.
+    pub fn set(&self, name: &str, value: &str) -> String {
+        let res = match name {
+            "loglevel" => LevelFilter::from_str(value).map(logger::set_level).map_err(|_| format!("invalid level {}", value)),
+            "slowlog-threshold" => value.parse().map(|ms| self.slowlog.set_threshold(ms)).map_err(|_| format!("invalid number {}", value)),
+            name if self.fixed.iter().any(|(setting, _)| *setting == name) => Err(format!("{} cannot be changed while running", name)),
+            name => Err(format!("unknown setting {}", name)),
+        };
+
+        match res {
+            Ok(()) => {
+                info!("Set {} to {}", name, value);
+                format!("set: {} {}\n", name, value)
+            },
+            Err(err) => format!("ERR {}\n", err),
+        }
+    }
+
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        let mut settings = vec![
+            ("loglevel", logger::level().to_string().to_lowercase()),
+            ("slowlog-threshold", self.slowlog.threshold().to_string()),
+        ];
+        settings.extend(self.fixed.iter().cloned());
+        settings
+    }
+}
+
+/// A timeout as its variable takes it, in milliseconds with 0 for none.
+pub fn millis(timeout: Option<Duration>) -> String {
+    timeout.map_or(0, |timeout| timeout.as_millis()).to_string()
+}
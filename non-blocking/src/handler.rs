@@ -1,13 +1,27 @@
 use std::collections::HashSet;
+use std::time::Duration;
+
 use rand::{self, RngCore};
 
+use crate::admin::Admin;
+use crate::cancel::{Cancel, Stopped};
+use crate::primes::{self, Progress};
+use crate::persistence::{AppendLog, Entry};
+use crate::session::Session;
+use crate::snapshot::Snapshotter;
+use crate::thread_pool::Spawner;
+
 enum Command {
     Fortune,
     Increment,
     Counter,
     Upload(String),
     Download(String),
-    Compute(u64),
+    /// The `k` to sum up to, or why it was refused.
+    Compute(Result<u64, String>, Option<u64>, bool),
+    Save,
+    Auth(String),
+    Admin(Admin),
     None
 }
 
@@ -17,6 +31,7 @@ impl Command {
             "fortune" => Command::Fortune,
             "increment" => Command::Increment,
             "counter" => Command::Counter,
+            "save" => Command::Save,
             other => {
                 if other.starts_with("upload") {
                     if let Some(split) = other.split_once(' ') {
@@ -30,9 +45,25 @@ impl Command {
                     }
                 }
 
-                if other.starts_with("compute") {
+                if other.starts_with("auth") {
                     if let Some(split) = other.split_once(' ') {
-                        return Command::Compute(split.1.parse().unwrap_or(0));
+                        return Command::Auth(String::from(split.1));
+                    }
+                }
+
+                if let Some(admin) = Admin::parse(other) {
+                    return Command::Admin(admin);
+                }
+
+                if other.starts_with("compute") {
+                    if let [_, k, options @ ..] = &other.split_whitespace().collect::<Vec<_>>()[..] {
+                        let timeout = options.iter().find_map(|option| option.strip_prefix("timeout=")).and_then(|ms| ms.parse().ok());
+                        let k = match k.parse() {
+                            Ok(k) if k > primes::MAX_K => Err(String::from("k too large")),
+                            Ok(k) => Ok(k),
+                            Err(_) => Err(format!("invalid k {}", k)),
+                        };
+                        return Command::Compute(k, timeout, options.contains(&"progress"));
                     }
                 }
 
@@ -40,6 +71,22 @@ impl Command {
             }
         }
     }
+
+    /// What `stats` counts the command as.
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Fortune => "fortune",
+            Command::Increment => "increment",
+            Command::Counter => "counter",
+            Command::Upload(_) => "upload",
+            Command::Download(_) => "download",
+            Command::Compute(..) => "compute",
+            Command::Save => "save",
+            Command::Auth(_) => "auth",
+            Command::Admin(admin) => admin.name(),
+            Command::None => "unknown",
+        }
+    }
 }
 
 static FORTUNES: &[&str] = &[
@@ -49,46 +96,99 @@ static FORTUNES: &[&str] = &[
     "Doing your best means never stop trying.\n",
 ];
 
-fn is_prime(x: u64) -> bool {
-    if x == 0 || x == 1 {
-        return false;
+/// Sum of the primes up to `k`, may have to sieve for a while so it runs off the polling thread.
+pub fn compute(k: u64, cancel: &Cancel, pool: &Spawner, progress: Option<Progress>) -> String {
+    match primes::sum(k, cancel, pool, progress) {
+        Ok(sum) => format!("computed: {}\n", sum),
+        Err(Stopped::Timeout) => "ERR timeout\n".to_string(),
+        Err(Stopped::Cancelled) => "ERR cancelled\n".to_string(),
     }
+}
 
-    for i in 2..x/2 {
-        if x % i == 0 {
-            return false;
+pub enum Reply {
+    Ready(String),
+    /// The response has to be computed by the worker pool, within the timeout if one is given
+    /// and with progress lines ahead of it if asked for.
+    Compute(u64, Option<Duration>, bool),
+    /// The loop answers it, it knows every connection.
+    Admin(Admin),
+}
+
+/// What a compute job sends back to the loop.
+pub enum Update {
+    Progress(u8),
+    /// The response, and how long the job waited for a worker.
+    Done(String, Duration),
+}
+
+impl Update {
+    pub fn line(self) -> String {
+        match self {
+            Update::Progress(percent) => format!("progress: {}%\n", percent),
+            Update::Done(response, _) => response,
         }
     }
-
-    true
 }
 
-pub fn handle(message: String, counter: &mut u64, uploads: &mut HashSet<String>) -> String {
-    match Command::parse(message.trim_end()) {
+/// Runs one request of the client `session` belongs to, nothing but `auth` passes before it authenticated.
+/// The reply comes with the name `stats` counts the request as.
+pub fn handle(message: String, session: &mut Session, counter: &mut u64, uploads: &mut HashSet<String>, log: &AppendLog, snapshots: &Snapshotter) -> (&'static str, Reply) {
+    let command = Command::parse(message.trim_end());
+    let name = command.name();
+
+    let response = match command {
+        Command::Auth(token) => match session.authenticate(&token) {
+            Ok(identity) => format!("authenticated: {}\n", identity),
+            Err(err) => format!("ERR {}\n", err),
+        },
+        _ if session.identity().is_none() => {
+            "ERR auth required\n".to_string()
+        },
+        _ if !session.permits(message.trim_end()) => {
+            "ERR forbidden\n".to_string()
+        },
         Command::Fortune => {
             FORTUNES[rand::thread_rng().next_u32() as usize % FORTUNES.len()].to_string()
         },
-        Command::Increment => {
-            *counter += 1;
-            "incremented\n".to_string()
+        Command::Increment => match log.append(Entry::Increment) {
+            Ok(()) => {
+                *counter += 1;
+                snapshots.touch();
+                "incremented\n".to_string()
+            },
+            Err(err) => format!("ERR {}\n", err),
         },
         Command::Counter => {
             format!("counter: {}\n", *counter)
         },
-        Command::Upload(item) => {
-            uploads.insert(item);
-            "uploaded\n".to_string()
+        Command::Upload(item) => match log.append(Entry::Upload(item.clone())) {
+            Ok(()) => {
+                uploads.insert(item);
+                snapshots.touch();
+                "uploaded\n".to_string()
+            },
+            Err(err) => format!("ERR {}\n", err),
         },
         Command::Download(item) => {
             let found = uploads.get(&item).cloned().unwrap_or_else(|| String::from("not found"));
             format!("download: {}\n", found)
         },
-        Command::Compute(k) => {
-            let sum = (0..=k).fold(0, |acc, x| acc + if is_prime(x) { x } else { 0 });
-            format!("computed: {}\n", sum)
+        Command::Compute(Err(err), ..) => {
+            format!("ERR {}\n", err)
+        },
+        Command::Compute(Ok(k), timeout, progress) => {
+            return (name, Reply::Compute(k, timeout.map(Duration::from_millis), progress));
+        },
+        Command::Admin(admin) => {
+            return (name, Reply::Admin(admin));
+        },
+        Command::Save => {
+            if snapshots.save(*counter, uploads.clone()) { "saving\n" } else { "already saving\n" }.to_string()
         },
         Command::None => {
             "ok\n".to_string()
         }
-    }
+    };
+
+    (name, Reply::Ready(response))
 }
@@ -1,14 +1,436 @@
-pub fn setup() -> Result<(), log::SetLoggerError> {
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{warn, LevelFilter, Metadata};
+
+use crate::settings;
+
+/// Records that can wait for the writer thread, `LOG_BUFFER` changes it.
+pub static LOG_BUFFER: usize = 1024;
+/// How long a flush waits for an output that does not take the log.
+static FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+static LEVELS: RwLock<Levels> = RwLock::new(Levels { default: LevelFilter::Info, modules: Vec::new() });
+/// Every how many times a sampled message is logged, `LOG_SAMPLE` changes it.
+static SAMPLE: AtomicU64 = AtomicU64::new(1);
+/// Records the sink had no room for.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Connection the thread is working for, logged along with JSON records.
+    static CONNECTION: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Level of every module, `LOG_LEVEL` sets them as `info,non_blocking::handler=debug`.
+pub struct Levels {
+    default: LevelFilter,
+    /// Longest matching module wins.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl FromStr for Levels {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut levels = Levels { default: LevelFilter::Info, modules: Vec::new() };
+
+        for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            match part.split_once('=') {
+                Some((module, level)) => levels.modules.push((module.to_string(), parse_level(level)?)),
+                None => levels.default = parse_level(part)?,
+            }
+        }
+
+        levels.modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(levels)
+    }
+}
+
+impl Levels {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        let level = self.modules.iter()
+            .find(|(module, _)| target == module || target.strip_prefix(module.as_str()).is_some_and(|rest| rest.starts_with("::")))
+            .map_or(self.default, |(_, level)| *level);
+
+        metadata.level() <= level
+    }
+
+    /// What `log` has to pass on at all, the most verbose of the levels.
+    fn max(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).chain([self.default]).max().unwrap_or(self.default)
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(level.trim()).map_err(|_| format!("invalid level {}", level))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    /// One object per line, with a timestamp and the connection id.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "" | "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            other => Err(format!("invalid log format {}, expected text or json", other)),
+        }
+    }
+}
+
+/// When a log file is moved aside for a new one.
+#[derive(Clone, Copy)]
+pub enum Rotation {
+    Never,
+    Size(u64),
+    /// Whenever the UTC clock enters a new period of this many seconds.
+    Every(u64),
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "" | "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Every(60 * 60)),
+            "daily" => Ok(Rotation::Every(24 * 60 * 60)),
+            bytes => match bytes.parse() {
+                Ok(0) => Ok(Rotation::Never),
+                Ok(bytes) => Ok(Rotation::Size(bytes)),
+                Err(_) => Err(format!("invalid rotation {}, expected hourly, daily or a size in bytes", bytes)),
+            },
+        }
+    }
+}
+
+/// Log file that renames itself to `<path>.<unix time in ms>` and starts over as `rotation` says.
+struct RotatingFile {
+    path: String,
+    file: File,
+    rotation: Rotation,
+    size: u64,
+    period: u64,
+}
+
+impl RotatingFile {
+    fn open(path: String, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, rotation, size, period: period(rotation) })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let (secs, millis) = unix_time();
+        let mut rotated = format!("{}.{}{:03}", self.path, secs, millis);
+        // rotations by size can come quicker than the clock moves on
+        while fs::metadata(&rotated).is_ok() {
+            rotated.push('_');
+        }
+
+        fs::rename(&self.path, rotated)?;
+        *self = Self::open(self.path.clone(), self.rotation)?;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.file.write(buf)?;
+        self.size += len as u64;
+        Ok(len)
+    }
+
+    /// Called after every record, so a rotation never splits one.
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let due = match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max) => self.size >= max,
+            Rotation::Every(_) => period(self.rotation) != self.period,
+        };
+
+        if due { self.rotate() } else { Ok(()) }
+    }
+}
+
+/// What becomes of a record while `LOG_BUFFER` others wait for the writer thread.
+#[derive(Clone, Copy)]
+pub enum Overflow {
+    Drop,
+    /// The logging thread waits, as it did writing the record itself.
+    Block,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "" | "drop" => Ok(Overflow::Drop),
+            "block" => Ok(Overflow::Block),
+            other => Err(format!("invalid log overflow {}, expected drop or block", other)),
+        }
+    }
+}
+
+enum Message {
+    Record(Vec<u8>),
+    /// Answered once everything sent before has been written.
+    Flush(SyncSender<()>),
+}
+
+/// Hands records to a thread of their own, so a slow output holds up only that thread.
+struct Sink {
+    record: Vec<u8>,
+    sender: SyncSender<Message>,
+    overflow: Overflow,
+}
+
+impl Sink {
+    fn spawn(mut output: Box<dyn Write + Send>, buffer: usize, overflow: Overflow) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(buffer);
+
+        thread::Builder::new().name(String::from("logger")).spawn(move || {
+            for message in receiver {
+                let res = match message {
+                    Message::Record(record) => output.write_all(&record).and_then(|_| output.flush()),
+                    Message::Flush(done) => output.flush().map(|_| { let _ = done.send(()); }),
+                };
+
+                if let Err(err) = res {
+                    eprintln!("Could not write the log: {}", err);
+                }
+            }
+        })?;
+
+        Ok(Self { record: Vec::new(), sender, overflow })
+    }
+
+    /// Waits up to `FLUSH_TIMEOUT` for the writer to be done with what was sent.
+    fn wait(&self) -> io::Result<()> {
+        let deadline = Instant::now() + FLUSH_TIMEOUT;
+        let (done, wait) = mpsc::sync_channel(1);
+
+        let mut flush = Message::Flush(done);
+        loop {
+            match self.sender.try_send(flush) {
+                Ok(()) => break,
+                Err(TrySendError::Full(_)) if Instant::now() >= deadline => return Err(io::ErrorKind::TimedOut.into()),
+                Err(TrySendError::Full(message)) => {
+                    flush = message;
+                    thread::sleep(Duration::from_millis(10));
+                },
+                Err(TrySendError::Disconnected(_)) => return Err(stopped()),
+            }
+        }
+
+        wait.recv_timeout(deadline.saturating_duration_since(Instant::now())).map_err(|_| io::Error::from(io::ErrorKind::TimedOut))
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.record.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    /// fern flushes after every record, which sends it on. With nothing written waits for the writer to catch up.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.record.is_empty() {
+            return self.wait();
+        }
+
+        let record = Message::Record(mem::take(&mut self.record));
+        match self.overflow {
+            Overflow::Block => self.sender.send(record).map_err(|_| stopped()),
+            Overflow::Drop => match self.sender.try_send(record) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                },
+                Err(TrySendError::Disconnected(_)) => Err(stopped()),
+            },
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the log writer stopped")
+}
+
+fn period(rotation: Rotation) -> u64 {
+    match rotation {
+        Rotation::Every(secs) => unix_time().0 / secs,
+        _ => 0,
+    }
+}
+
+/// Logs at `LOG_LEVEL` to stdout, or to `LOG_FILE` rotated as `LOG_ROTATE` says, as text or `LOG_FORMAT=json`.
+/// A writer thread does the writing, records past `LOG_BUFFER` are dropped or wait as `LOG_OVERFLOW` says.
+/// `set_level` changes the default level while running.
+pub fn setup() -> Result<(), Box<dyn Error>> {
+    let levels: Levels = settings::var("LOG_LEVEL").unwrap_or_default().parse()?;
+    let format: Format = settings::var("LOG_FORMAT").unwrap_or_default().parse()?;
+    let output: Box<dyn Write + Send> = match settings::var("LOG_FILE") {
+        Ok(path) => Box::new(RotatingFile::open(path, settings::var("LOG_ROTATE").unwrap_or_default().parse()?)?),
+        Err(_) => Box::new(io::stdout()),
+    };
+    let buffer = match settings::var("LOG_BUFFER") {
+        Ok(buffer) => buffer.parse::<usize>().map_err(|_| format!("invalid log buffer {}", buffer))?.max(1),
+        Err(_) => LOG_BUFFER,
+    };
+    let overflow: Overflow = settings::var("LOG_OVERFLOW").unwrap_or_default().parse()?;
+    if let Ok(sample) = settings::var("LOG_SAMPLE") {
+        SAMPLE.store(sample.parse::<u64>().map_err(|_| format!("invalid sample rate {}", sample))?.max(1), Ordering::Relaxed);
+    }
+
+    let sink: Box<dyn Write + Send> = Box::new(Sink::spawn(output, buffer, overflow)?);
+    let max = levels.max();
+    *LEVELS.write().unwrap() = levels;
+
+    // fern passes everything on, the levels alone decide what is logged
     fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{}][{}] {}",
-                record.target(),
-                record.level(),
-                message
-            ))
+        .format(move |out, message, record| {
+            let timestamp = timestamp();
+            match format {
+                Format::Text => out.finish(format_args!("{} [{}][{}] {}", timestamp, record.target(), record.level(), message)),
+                Format::Json => {
+                    let mut line = format!("{{\"ts\":\"{}\",\"level\":\"{}\",\"target\":{}", timestamp, record.level(), json(record.target()));
+                    if let Some(connection) = CONNECTION.with(Cell::get) {
+                        let _ = write!(line, ",\"conn\":{}", connection);
+                    }
+                    let _ = write!(line, ",\"msg\":{}}}", json(&message.to_string()));
+                    out.finish(format_args!("{}", line))
+                },
+            }
         })
-        .level(log::LevelFilter::Info)
-        .chain(std::io::stdout())
-        .apply()
+        .level(LevelFilter::Trace)
+        .filter(|metadata| LEVELS.read().unwrap().enabled(metadata))
+        .chain(sink)
+        .apply()?;
+
+    log::set_max_level(max);
+    Ok(())
+}
+
+/// Changes the level of modules `LOG_LEVEL` gave none of their own.
+pub fn set_level(level: LevelFilter) {
+    let mut levels = LEVELS.write().unwrap();
+    levels.default = level;
+    log::set_max_level(levels.max());
+}
+
+pub fn level() -> LevelFilter {
+    LEVELS.read().unwrap().default
+}
+
+/// Records dropped since the start for want of room.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Waits for every record so far to be written, for the end of the program.
+pub fn flush() {
+    let dropped = dropped();
+    if dropped > 0 {
+        warn!("Dropped {} log records, LOG_BUFFER or LOG_OVERFLOW=block keep them", dropped);
+    }
+
+    log::logger().flush();
+}
+
+/// Marks what the thread logs as being for `connection` until the guard is dropped.
+pub fn connection(connection: u64) -> Connection {
+    Connection { previous: CONNECTION.with(|current| current.replace(Some(connection))) }
+}
+
+pub struct Connection {
+    previous: Option<u64>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        CONNECTION.with(|current| current.set(self.previous));
+    }
+}
+
+/// Whether a sampled call site, reached `seen` times before, logs this time.
+pub fn sample(seen: &AtomicU64) -> bool {
+    seen.fetch_add(1, Ordering::Relaxed).is_multiple_of(SAMPLE.load(Ordering::Relaxed))
+}
+
+/// Logs at info one in `LOG_SAMPLE` times, for messages on the hot path.
+macro_rules! sampled {
+    ($($arg:tt)+) => {{
+        static SEEN: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        if $crate::logger::sample(&SEEN) {
+            log::info!($($arg)+);
+        }
+    }};
+}
+
+pub(crate) use sampled;
+
+/// Seconds and milliseconds since the epoch.
+fn unix_time() -> (u64, u32) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (now.as_secs(), now.subsec_millis())
+}
+
+/// The current UTC time as `2024-01-31T12:00:00.000Z`.
+fn timestamp() -> String {
+    let (secs, millis) = unix_time();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // days to a civil date, after Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, secs / 3600, secs % 3600 / 60, secs % 60, millis)
+}
+
+/// `str` as a JSON string literal.
+fn json(str: &str) -> String {
+    let mut out = String::with_capacity(str.len() + 2);
+    out.push('"');
+    for c in str.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
 }
@@ -1,64 +1,216 @@
+mod admin;
+mod cancel;
 mod handler;
 mod logger;
+mod metrics;
+mod persistence;
+mod primes;
+mod roles;
+mod session;
+mod settings;
+mod shutdown;
+mod slowlog;
+mod snapshot;
+mod thread_pool;
+mod timeouts;
+mod tls;
+mod trace;
 
-use std::io::{self, Write};
-use std::io::Read;
+use std::io;
 use std::net;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
 use std::os::unix::io::AsRawFd;
 
-use std::collections::{HashSet, HashMap};
+use std::collections::HashMap;
 
 use log::{info, warn};
 use polling::{Event, Poller};
+use tracing::Span;
 
-static PORT: i32 = 3000;
-static THREADS: i32 = 4;
+use crate::admin::{Admin, Client, Config, Stats};
+use crate::cancel::Cancel;
+use crate::handler::{Reply, Update};
+use crate::metrics::{Metrics, Open};
+use crate::persistence::{AppendLog, Fsync};
+use crate::primes::Progress;
+use crate::session::{Session, Tokens};
+use crate::shutdown::Signals;
+use crate::slowlog::Slowlog;
+use crate::snapshot::Snapshotter;
+use crate::thread_pool::ThreadPool;
+use crate::timeouts::{Deadline, Phase, Timeouts};
+use crate::tls::Stream;
+
+pub static PORT: u16 = 3000;
+pub static THREADS: i32 = 4;
+pub static READ_BUFFER: usize = 256;
+static LOG_PATH: &str = "appendonly.log";
+static SNAPSHOT_PATH: &str = "dump.snap";
+static SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
 
 struct Connection {
-    stream: net::TcpStream,
-    response: Option<String>
+    stream: Stream,
+    response: Option<String>,
+    /// Compute job running for this connection, the id keeps a late result away from a reused descriptor.
+    job: Option<(u64, Cancel)>,
+    session: Session,
+    /// None while a compute runs, it has a timeout of its own.
+    deadline: Option<Deadline>,
+    client: Client,
+    /// When the request being answered was read.
+    started: Instant,
+    /// Command line of the request being answered, for the slow log.
+    line: String,
+    /// Start of a request line whose end is still on the way.
+    partial: Vec<u8>,
+    _open: Open,
+    /// Span of the connection, open as long as it is.
+    span: Span,
+    /// Span of the request being answered, none between requests.
+    request: Span,
+}
+
+impl Connection {
+    /// Waiting for the next request with nothing left to send.
+    fn is_idle(&self) -> bool {
+        self.job.is_none() && self.response.is_none() && !self.stream.wants_write()
+            && self.deadline.is_none_or(|deadline| deadline.phase == Phase::Idle)
+    }
 }
 
 fn main() -> io::Result<()> {
+    settings::load();
     logger::setup().expect("Could not start logger");
+    let _trace = trace::setup().expect("Could not start tracing");
+
+    let addr = settings::bind(settings::parse("PORT", PORT))?;
+    let threads = settings::parse("THREADS", THREADS).max(1);
 
-    info!("Created thread pool with {} threads", THREADS);
+    let pool = ThreadPool::new(threads);
+    let (done, completed) = mpsc::channel::<(usize, u64, Update)>();
+    let mut jobs = 0;
 
-    let listener = net::TcpListener::bind(format!("127.0.0.1:{}", PORT))?;
+    info!("Created thread pool with {} threads", threads);
+
+    let listener = net::TcpListener::bind(addr)?;
     let listener_id = listener.as_raw_fd() as usize;
     listener.set_nonblocking(true)?;
 
-    info!("Server started on port {}", PORT);
+    info!("Server started on port {}", addr.port());
 
-    let poller = Poller::new()?;
+    let poller = Arc::new(Poller::new()?);
     poller.add(&listener, Event::readable(listener_id))?;
     let mut events = Vec::new();
 
-    let mut connections = HashMap::new();
-    let mut buf = [0; 256];
+    let mut signals = Signals::new()?;
+    let signals_id = signals.as_raw_fd() as usize;
+    poller.add(&signals, Event::readable(signals_id))?;
+    // set once a signal came in, the loop ends by then at the latest
+    let mut drain: Option<Instant> = None;
+
+    let mut connections: HashMap<usize, Connection> = HashMap::new();
+    let mut buf = vec![0; settings::parse("READ_BUFFER", READ_BUFFER).max(1)];
+
+    let fsync = Fsync::from_env();
+    let (log, mut counter, mut uploads) = AppendLog::open(LOG_PATH, fsync)?;
+    let snapshots = Snapshotter::new(SNAPSHOT_PATH, SNAPSHOT_INTERVAL);
+    let tokens = Arc::new(Tokens::from_env()?);
+    let tls = tls::from_env()?;
+    let timeouts = Timeouts::from_env();
+
+    let metrics = Arc::new(Metrics::default());
+    metrics::serve(Arc::clone(&metrics), pool.spawner())?;
 
-    let (mut counter, mut uploads) = (0u64, HashSet::new());
+    let stats = Stats::new(Arc::clone(&metrics));
+    let slowlog = Slowlog::from_env();
+    let config = Config::new(vec![
+        ("bind", addr.ip().to_string()),
+        ("port", addr.port().to_string()),
+        ("threads", threads.to_string()),
+        ("read-buffer", buf.len().to_string()),
+        ("idle-timeout", admin::millis(timeouts.idle)),
+        ("read-timeout", admin::millis(timeouts.read)),
+        ("write-timeout", admin::millis(timeouts.write)),
+        ("appendfsync", fsync.to_string()),
+        ("tls", if tls.is_some() { "on" } else { "off" }.to_string()),
+        ("slowlog-max-len", slowlog.capacity().to_string()),
+    ], slowlog);
+
+    if counter == 0 && uploads.is_empty() {
+        if let Some((saved_counter, saved_uploads)) = snapshots.load()? {
+            log.seed(saved_counter, saved_uploads.clone());
+            (counter, uploads) = (saved_counter, saved_uploads);
+        }
+    }
 
-    let mut iter = 0;
     loop {
         events.clear();
-        poller.wait(&mut events, None)?;
+        let deadline = connections.values().flat_map(|conn| conn.job.as_ref().and_then(|(_, cancel)| cancel.deadline()).into_iter().chain(conn.deadline.map(|deadline| deadline.at))).min();
+        let deadline = deadline.into_iter().chain(drain).min();
+        let timeout = deadline.map_or(snapshots.next_check(), |deadline| deadline.saturating_duration_since(Instant::now()).min(snapshots.next_check()));
+        match poller.wait(&mut events, Some(timeout)) {
+            // the signal pipe is readable on the next wait
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            res => res?,
+        };
 
-        if events.len() > 1 {
-            info!("Event loop iter [{}] -> events [{}]", iter, events.len());
+        if snapshots.due() {
+            snapshots.save(counter, uploads.clone());
         }
-        iter += 1;
+
+        for (key, job, update) in completed.try_iter() {
+            if let Some(conn) = connections.get_mut(&key).filter(|conn| conn.job.as_ref().is_some_and(|(id, _)| *id == job)) {
+                let queued = match update {
+                    Update::Done(_, queued) => Some(queued),
+                    Update::Progress(_) => None,
+                };
+                let line = update.line();
+                if let Some(queued) = queued {
+                    conn.job = None;
+                    metrics.request("compute", &line, conn.started.elapsed());
+                    config.slowlog().record(conn.session.peer(), &conn.line, conn.started, queued);
+                }
+                conn.response.get_or_insert_with(String::new).push_str(&line);
+                conn.deadline = timeouts.start(Phase::Write);
+                poller.modify(conn.stream.socket(), Event::writable(key))?;
+            }
+        }
+
+        // answer computes past their deadline, even those no worker has picked up yet
+        for (key, conn) in connections.iter_mut().filter(|(_, conn)| conn.job.as_ref().is_some_and(|(_, cancel)| cancel.is_expired())) {
+            if let Some((_, cancel)) = conn.job.take() {
+                cancel.cancel();
+            }
+            conn.response.get_or_insert_with(String::new).push_str("ERR timeout\n");
+            metrics.request("compute", "ERR timeout\n", conn.started.elapsed());
+            config.slowlog().record(conn.session.peer(), &conn.line, conn.started, Duration::ZERO);
+            conn.deadline = timeouts.start(Phase::Write);
+            poller.modify(conn.stream.socket(), Event::writable(*key))?;
+        }
+
+        metrics.wake(events.len());
 
         for ev in events.iter() {
-            if ev.key == listener_id && ev.readable {
+            if ev.key == signals_id {
+                if signals.received() && drain.is_none() {
+                    let timeout = shutdown::drain_timeout();
+                    info!("Shutting down, draining {} connections for up to {:?}", connections.len(), timeout);
+                    drain = Some(Instant::now() + timeout);
+                    poller.delete(&listener)?;
+                }
+                poller.modify(&signals, Event::readable(signals_id))?;
+
+            } else if ev.key == listener_id && ev.readable {
                 match listener.accept() {
                     Ok((stream, socket)) => {
-                        info!("Received connection from [{}:{}]", socket.ip(), socket.port());
+                        logger::sampled!("Received connection from [{}:{}]", socket.ip(), socket.port());
 
                         let connection_fd = stream.as_raw_fd() as usize;
                         poller.add(&stream, Event::readable(connection_fd))?;
-                        connections.insert(connection_fd, Connection{ stream, response: None});
+                        let stream = Stream::accept(stream, tls.as_ref())?;
+                        connections.insert(connection_fd, Connection{ stream, response: None, job: None, session: Session::new(Arc::clone(&tokens), socket), deadline: timeouts.start(Phase::Idle), client: Client::new(), started: Instant::now(), line: String::new(), partial: Vec::new(), _open: metrics.open(), span: trace::connection(connection_fd as u64), request: Span::none() });
                     },
 
                     Err(err) => {
@@ -69,36 +221,230 @@ fn main() -> io::Result<()> {
                 poller.modify(&listener, Event::readable(listener_id))?;
 
             } else {
+                let _connection = logger::connection(ev.key as u64);
 
                 if ev.readable {
                     let conn = connections.get_mut(&ev.key).unwrap();
 
-                    let len = conn.stream.read(&mut buf)?;
-                    if len > 0 {
-                        let message = String::from_utf8_lossy(&buf[..len]).to_string();
-                        conn.response = Some(handler::handle(message, &mut counter, &mut uploads));
-                        poller.modify(&conn.stream, Event::writable(ev.key))?;
-                    } else {
-                        poller.delete(&conn.stream)?;
+                    // a request line is at most as long as the buffer
+                    let room = buf.len() - conn.partial.len();
+                    // while computing only a hangup matters, a pipelined request waits in the socket
+                    let len = match conn.job {
+                        Some(_) => conn.stream.socket().peek(&mut buf),
+                        None => conn.stream.read(&mut buf[..room]),
+                    };
+
+                    // the TLS handshake is still going on, or only part of a record came in
+                    if len.as_ref().is_err_and(|err| err.kind() == io::ErrorKind::WouldBlock) {
+                        if conn.deadline.is_none_or(|deadline| deadline.phase != Phase::Read) {
+                            conn.deadline = timeouts.start(Phase::Read);
+                        }
+                        poller.modify(conn.stream.socket(), interest(ev.key, &conn.stream))?;
+                        continue;
+                    }
+
+                    // a client leaving with progress lines unread resets the connection instead of closing it
+                    let len = len.unwrap_or_else(|err| {
+                        warn!("Client error: {}", err);
+                        0
+                    });
+
+                    if len == 0 {
+                        if let Some((_, cancel)) = &conn.job {
+                            cancel.cancel();
+                        }
+                        poller.delete(conn.stream.socket())?;
                         connections.remove(&ev.key);
+                    } else if conn.job.is_some() {
+                        poller.modify(conn.stream.socket(), Event::none(ev.key))?;
+                    } else if buf[len - 1] != b'\n' && conn.partial.len() + len < buf.len() {
+                        // the rest of the line has to come in before the read deadline, a full buffer is taken as it is
+                        conn.partial.extend_from_slice(&buf[..len]);
+                        if conn.deadline.is_none_or(|deadline| deadline.phase != Phase::Read) {
+                            conn.deadline = timeouts.start(Phase::Read);
+                        }
+                        poller.modify(conn.stream.socket(), interest(ev.key, &conn.stream))?;
+                    } else {
+                        conn.partial.extend_from_slice(&buf[..len]);
+                        let line = std::mem::take(&mut conn.partial);
+                        let (message, len) = (String::from_utf8_lossy(&line).to_string(), line.len());
+                        conn.client.bytes_in += len as u64;
+                        conn.started = Instant::now();
+                        conn.line.clear();
+                        conn.line.push_str(&message);
+                        conn.request = trace::request(&conn.span, ev.key as u64);
+                        metrics.received(len);
+
+                        let (name, reply) = handler::handle(message, &mut conn.session, &mut counter, &mut uploads, &log, &snapshots);
+                        conn.client.record(name);
+                        conn.request.record("command", name);
+
+                        let response = match reply {
+                            Reply::Ready(response) => response,
+
+                            Reply::Admin(command) => admin(command, &mut connections, &stats, &config),
+
+                            Reply::Compute(k, timeout, progress) => {
+                                jobs += 1;
+
+                                let cancel = Cancel::new(timeout.map(|timeout| Instant::now() + timeout));
+                                conn.job = Some((jobs, cancel.clone()));
+                                conn.deadline = None;
+
+                                let (key, job, done, notifier) = (ev.key, jobs, done.clone(), Arc::clone(&poller));
+                                let send = Arc::new(move |update| {
+                                    if done.send((key, job, update)).is_ok() {
+                                        let _ = notifier.notify();
+                                    }
+                                });
+
+                                let report = progress.then(|| {
+                                    let send = Arc::clone(&send);
+                                    Arc::new(move |percent| send(Update::Progress(percent))) as Progress
+                                });
+
+                                let (worker_cancel, spawner, submitted) = (cancel.clone(), pool.spawner(), Instant::now());
+                                let _request = conn.request.enter();
+                                pool.execute(&cancel, move || {
+                                    let queued = submitted.elapsed();
+                                    send(Update::Done(handler::compute(k, &worker_cancel, &spawner, report), queued));
+                                });
+
+                                // watch for hangups until the result is back
+                                poller.modify(conn.stream.socket(), Event::readable(ev.key))?;
+                                continue;
+                            },
+                        };
+
+                        let conn = connections.get_mut(&ev.key).unwrap();
+                        metrics.request(name, &response, conn.started.elapsed());
+                        config.slowlog().record(conn.session.peer(), &conn.line, conn.started, Duration::ZERO);
+
+                        conn.response = Some(response);
+                        conn.deadline = timeouts.start(Phase::Write);
+                        poller.modify(conn.stream.socket(), Event::writable(ev.key))?;
                     }
 
                 } else if ev.writable {
                     let conn = connections.get_mut(&ev.key).unwrap();
 
-                    match &conn.response {
+                    // a compute may still be running, its next lines will request another write
+                    let written = match conn.response.take() {
                         Some(res) => {
-                            conn.stream.write_all(res.as_bytes())?;
+                            conn.client.bytes_out += res.len() as u64;
+                            metrics.sent(res.len());
+                            conn.stream.write_all(res.as_bytes())
                         },
 
+                        // records the socket could not take at once
+                        None if conn.stream.wants_write() => conn.stream.flush(),
+
                         None => {
                             warn!("Response not created!");
+                            Ok(())
+                        }
+                    };
+
+                    // progress lines can go out after the client left, which only ends this connection
+                    if let Err(err) = written {
+                        warn!("Client error: {}", err);
+                        if let Some((_, cancel)) = &conn.job {
+                            cancel.cancel();
                         }
+                        poller.delete(conn.stream.socket())?;
+                        connections.remove(&ev.key);
+                        continue;
                     }
 
-                    poller.modify(&conn.stream, Event::readable(ev.key))?;
+                    conn.deadline = match (&conn.job, conn.stream.wants_write()) {
+                        (Some(_), _) => None,
+                        (None, true) => timeouts.start(Phase::Write),
+                        (None, false) => {
+                            // the reply is out
+                            conn.request = Span::none();
+                            timeouts.start(Phase::Idle)
+                        },
+                    };
+                    poller.modify(conn.stream.socket(), interest(ev.key, &conn.stream))?;
                 }
             }
         }
+
+        let now = Instant::now();
+        connections.retain(|_, conn| {
+            let peer = conn.session.peer();
+            if conn.client.killed {
+                info!("Closing [{}:{}], killed", peer.ip(), peer.port());
+            } else if let Some(deadline) = conn.deadline.filter(|deadline| deadline.at <= now) {
+                warn!("Closing [{}:{}], {}", peer.ip(), peer.port(), deadline);
+            } else {
+                return true;
+            }
+
+            if let Some((_, cancel)) = &conn.job {
+                cancel.cancel();
+            }
+            if let Err(err) = poller.delete(conn.stream.socket()) {
+                warn!("Could not stop polling [{}:{}]: {}", peer.ip(), peer.port(), err);
+            }
+            false
+        });
+
+        let Some(drain) = drain else {
+            continue;
+        };
+
+        connections.retain(|_, conn| {
+            if !conn.is_idle() {
+                return true;
+            }
+
+            let peer = conn.session.peer();
+            info!("Sending bye to [{}:{}]", peer.ip(), peer.port());
+            let _ = conn.stream.write_all(b"bye\n");
+            let _ = poller.delete(conn.stream.socket());
+            false
+        });
+
+        if connections.is_empty() {
+            break;
+        }
+
+        if drain <= now {
+            warn!("Closing {} connections still busy after the drain timeout", connections.len());
+            for conn in connections.values() {
+                if let Some((_, cancel)) = &conn.job {
+                    cancel.cancel();
+                }
+            }
+            break;
+        }
+    }
+
+    // the workers only get to exit once the cancelled computes gave up
+    drop(pool);
+    log.close();
+    info!("Server stopped");
+    logger::flush();
+
+    Ok(())
+}
+
+/// Answers an admin command, which may look at every connection.
+fn admin(command: Admin, connections: &mut HashMap<usize, Connection>, stats: &Stats, config: &Config) -> String {
+    match command {
+        Admin::Info => stats.info(connections.len()),
+        Admin::Stats => stats.report(),
+        Admin::ClientList => admin::list(connections.values().map(|conn| (conn.session.peer(), &conn.client))),
+        Admin::ClientKill(addr) => admin::kill(&addr, connections.values_mut().map(|conn| (conn.session.peer(), &mut conn.client))),
+        Admin::ConfigGet(name) => config.get(&name),
+        Admin::ConfigSet(name, value) => config.set(&name, &value),
+        Admin::SlowlogGet(count) => config.slowlog().get(count),
+        Admin::SlowlogReset => config.slowlog().reset(),
     }
 }
+
+/// Waits for the next request, and for the socket to take more if TLS records are still pending.
+fn interest(key: usize, stream: &Stream) -> Event {
+    if stream.wants_write() { Event::all(key) } else { Event::readable(key) }
+}
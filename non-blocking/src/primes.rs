@@ -0,0 +1,265 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::cancel::{Cancel, Stopped};
+use crate::thread_pool::Spawner;
+
+/// Numbers sieved per segment, the sieve grows by whole segments.
+static SEGMENT: u64 = 1 << 16;
+/// Numbers per parallel chunk.
+static CHUNK: u64 = 16 * SEGMENT;
+/// Largest number the memoized table covers, about 2M primes or 24MB.
+static MAX_LIMIT: u64 = 1 << 25;
+/// Largest `k` a compute accepts, the sum of the primes up to it still fits in a u64.
+pub static MAX_K: u64 = 1 << 34;
+
+/// Receives the percentage of the sieving done so far.
+pub type Progress = Arc<dyn Fn(u8) + Send + Sync>;
+
+static TABLE: RwLock<Table> = RwLock::new(Table { limit: 0, primes: Vec::new(), sums: Vec::new() });
+
+/// Primes below `limit` with the running sum up to and including each of them.
+struct Table {
+    limit: u64,
+    primes: Vec<u32>,
+    sums: Vec<u64>,
+}
+
+impl Table {
+    /// Sum of the primes up to `k`, which must be below `limit`.
+    fn lookup(&self, k: u64) -> u64 {
+        match self.primes.partition_point(|&p| p as u64 <= k) {
+            0 => 0,
+            n => self.sums[n - 1],
+        }
+    }
+
+    fn extend(&mut self, limit: u64, cancel: &Cancel, pool: &Spawner, progress: Option<Progress>) -> Result<(), Stopped> {
+        // the first segment holds the base primes of every later one
+        if self.limit == 0 {
+            self.append(sieve_segment(0, SEGMENT, &[]).map(|p| p as u32).collect(), SEGMENT);
+        }
+
+        let lo = self.limit;
+        let tracker = Arc::new(Tracker::new(limit.saturating_sub(lo) / SEGMENT, progress));
+        let base: Arc<Vec<u32>> = Arc::new(self.primes.iter().copied().take_while(|&p| (p as u64) * (p as u64) < limit).collect());
+        let mut chunk = 0;
+        pool.fork_join(limit.saturating_sub(lo).div_ceil(CHUNK) as usize, cancel, {
+            let cancel = cancel.clone();
+            move |chunk| {
+                let start = lo + chunk as u64 * CHUNK;
+                let mut primes = Vec::new();
+                for segment in (start..(start + CHUNK).min(limit)).step_by(SEGMENT as usize) {
+                    cancel.check()?;
+                    primes.extend(sieve_segment(segment, segment + SEGMENT, &base).map(|p| p as u32));
+                    tracker.tick();
+                }
+                Ok(primes)
+            }
+        }, |primes| {
+            // keeps whatever was sieved before a chunk gave up
+            chunk += 1;
+            self.append(primes, (lo + chunk * CHUNK).min(limit));
+        })
+    }
+
+    fn append(&mut self, primes: Vec<u32>, limit: u64) {
+        let mut total = self.sums.last().copied().unwrap_or(0);
+        for p in primes {
+            total += p as u64;
+            self.primes.push(p);
+            self.sums.push(total);
+        }
+        self.limit = limit;
+    }
+}
+
+/// Sum of the primes up to `k`.
+///
+/// Served from a shared table that is extended on demand, values of `k` past
+/// the memory cap are sieved segment by segment without being remembered.
+/// Large ranges are split into chunks sieved in parallel on `pool`,
+/// `cancel` is checked and `progress` updated after every segment.
+pub fn sum(k: u64, cancel: &Cancel, pool: &Spawner, progress: Option<Progress>) -> Result<u64, Stopped> {
+    {
+        let table = TABLE.read().unwrap();
+        if k < table.limit {
+            return Ok(table.lookup(k));
+        }
+    }
+
+    if k < MAX_LIMIT {
+        let mut table = TABLE.write().unwrap();
+        // another request may have grown it while this one waited for the lock
+        if k < table.limit {
+            return Ok(table.lookup(k));
+        }
+        // at least double, so that growing by small steps stays cheap
+        let limit = (k + 1).max(table.limit * 2).min(MAX_LIMIT).div_ceil(SEGMENT) * SEGMENT;
+        table.extend(limit, cancel, pool, progress)?;
+        return Ok(table.lookup(k));
+    }
+
+    sum_unbounded(k, cancel, pool, progress)
+}
+
+fn sum_unbounded(k: u64, cancel: &Cancel, pool: &Spawner, progress: Option<Progress>) -> Result<u64, Stopped> {
+    let end = k.saturating_add(1);
+    let tracker = Arc::new(Tracker::new(end.div_ceil(SEGMENT), progress));
+    let base = Arc::new(base_primes(k.isqrt(), cancel)?);
+
+    let mut sum = 0;
+    pool.fork_join(end.div_ceil(CHUNK) as usize, cancel, {
+        let cancel = cancel.clone();
+        move |chunk| {
+            let start = chunk as u64 * CHUNK;
+            let end = (start + CHUNK).min(end);
+            let mut total = 0;
+            for segment in (start..end).step_by(SEGMENT as usize) {
+                cancel.check()?;
+                total += sieve_segment(segment, (segment + SEGMENT).min(end), &base).sum::<u64>();
+                tracker.tick();
+            }
+            Ok(total)
+        }
+    }, |total| sum += total)?;
+
+    Ok(sum)
+}
+
+/// Primes up to and including `root`, sieved a segment at a time so that `cancel` is checked between segments.
+fn base_primes(root: u64, cancel: &Cancel) -> Result<Vec<u32>, Stopped> {
+    let mut primes = Vec::new();
+    for lo in (0..=root).step_by(SEGMENT as usize) {
+        cancel.check()?;
+        let found: Vec<u32> = sieve_segment(lo, (lo + SEGMENT).min(root + 1), &primes).map(|p| p as u32).collect();
+        primes.extend(found);
+    }
+    Ok(primes)
+}
+
+/// Counts sieved segments and reports every new percentage.
+struct Tracker {
+    segments: u64,
+    done: AtomicU64,
+    percent: AtomicU64,
+    progress: Option<Progress>,
+}
+
+impl Tracker {
+    fn new(segments: u64, progress: Option<Progress>) -> Self {
+        Self { segments: segments.max(1), done: AtomicU64::new(0), percent: AtomicU64::new(0), progress }
+    }
+
+    fn tick(&self) {
+        if let Some(progress) = &self.progress {
+            let percent = (self.done.fetch_add(1, Ordering::Relaxed) + 1) * 100 / self.segments;
+            if self.percent.fetch_max(percent, Ordering::Relaxed) < percent {
+                progress(percent as u8);
+            }
+        }
+    }
+}
+
+/// Primes in `lo..hi`, `primes` has to hold every prime up to the square root of `hi`.
+/// An empty `primes` sieves the range on its own, which is only cheap for small ranges.
+fn sieve_segment(lo: u64, hi: u64, primes: &[u32]) -> impl Iterator<Item = u64> {
+    let mut composite = vec![false; (hi - lo) as usize];
+
+    let mut cross = |p: u64| {
+        let start = (p * p).max(lo.div_ceil(p) * p);
+        for multiple in (start..hi).step_by(p as usize) {
+            composite[(multiple - lo) as usize] = true;
+        }
+    };
+
+    if primes.is_empty() {
+        for p in (2..hi).take_while(|p| p * p < hi) {
+            cross(p);
+        }
+    } else {
+        for &p in primes.iter().take_while(|&&p| (p as u64) * (p as u64) < hi) {
+            cross(p as u64);
+        }
+    }
+
+    composite.into_iter().enumerate().filter_map(move |(i, composite)| {
+        let x = lo + i as u64;
+        (x >= 2 && !composite).then_some(x)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Primes up to and including `n`, sieved in one go.
+    fn primes_to(n: u64) -> Vec<u64> {
+        let mut prime = vec![true; n as usize + 1];
+        for p in 2..=n.isqrt() {
+            if prime[p as usize] {
+                for multiple in (p * p..=n).step_by(p as usize) {
+                    prime[multiple as usize] = false;
+                }
+            }
+        }
+        (2..=n).filter(|&x| prime[x as usize]).collect()
+    }
+
+    #[test]
+    fn segments_match_a_whole_sieve() {
+        let expected = primes_to(4 * SEGMENT);
+        let base: Vec<u32> = expected.iter().map(|&p| p as u32).take_while(|&p| p < 1024).collect();
+
+        assert_eq!(sieve_segment(0, 100, &[]).collect::<Vec<_>>(), primes_to(99));
+        for (lo, hi) in [(SEGMENT - 50, SEGMENT + 50), (3 * SEGMENT, 4 * SEGMENT)] {
+            let within: Vec<u64> = expected.iter().copied().filter(|p| (lo..hi).contains(p)).collect();
+            assert_eq!(sieve_segment(lo, hi, &base).collect::<Vec<_>>(), within);
+        }
+    }
+
+    #[test]
+    fn base_primes_span_segments() {
+        let root = 2 * SEGMENT + 7;
+        let primes = base_primes(root, &Cancel::default()).ok().unwrap();
+        assert_eq!(primes.into_iter().map(u64::from).collect::<Vec<_>>(), primes_to(root));
+    }
+
+    #[test]
+    fn sums_at_segment_and_chunk_boundaries() {
+        let pool = ThreadPool::new(2);
+        let primes = primes_to(2 * CHUNK + 3);
+        let expected = |k: u64| primes.iter().take_while(|&&p| p <= k).sum::<u64>();
+
+        for k in [0, 1, 2, 3, SEGMENT - 1, SEGMENT, SEGMENT + 1, CHUNK - 1, CHUNK, CHUNK + 1] {
+            assert_eq!(sum(k, &Cancel::default(), &pool.spawner(), None).ok(), Some(expected(k)), "sum up to {}", k);
+        }
+        for k in [CHUNK - 1, CHUNK, CHUNK + 1, 2 * CHUNK + 3] {
+            assert_eq!(sum_unbounded(k, &Cancel::default(), &pool.spawner(), None).ok(), Some(expected(k)), "unbounded sum up to {}", k);
+        }
+    }
+    use crate::thread_pool::ThreadPool;
+
+    #[test]
+    fn cancelled_sum_releases_its_worker() {
+        let pool = ThreadPool::new(1);
+        let (spawner, cancel) = (pool.spawner(), Cancel::new(None));
+        let (sender, stopped) = mpsc::channel();
+        pool.execute(&cancel, {
+            let cancel = cancel.clone();
+            move || sender.send(matches!(sum(MAX_K, &cancel, &spawner, None), Err(Stopped::Cancelled))).unwrap()
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        cancel.cancel();
+        assert!(stopped.recv_timeout(Duration::from_secs(1)).unwrap());
+
+        let (sender, done) = mpsc::channel();
+        pool.execute(&Cancel::default(), move || sender.send(()).unwrap());
+        done.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use log::{info, warn};
+
+use crate::settings;
+
+/// Used unless `ROLES` names a file of its own, one role per line.
+static DEFAULT_ROLES: &str = "
+reader fortune counter download compute
+writer fortune counter download compute increment upload
+admin *
+";
+
+/// A command the role may run, `*` allows every one of them.
+struct Grant {
+    command: Option<String>,
+    /// Keys the command is restricted to, written as `upload:public/`.
+    prefix: Option<String>,
+}
+
+impl Grant {
+    fn parse(str: &str) -> Self {
+        let (command, prefix) = match str.split_once(':') {
+            Some((command, prefix)) => (command, Some(prefix.to_string())),
+            None => (str, None),
+        };
+
+        Grant { command: (command != "*").then(|| command.to_string()), prefix }
+    }
+
+    fn permits(&self, command: &str, key: &str) -> bool {
+        self.command.as_deref().is_none_or(|name| name == command)
+            && self.prefix.as_deref().is_none_or(|prefix| key.starts_with(prefix))
+    }
+}
+
+pub struct Role {
+    grants: Vec<Grant>,
+}
+
+impl Role {
+    /// Whether the request `message` is covered by one of the grants, the key is what follows the command.
+    pub fn permits(&self, message: &str) -> bool {
+        let (command, key) = message.split_once(' ').unwrap_or((message, ""));
+        self.grants.iter().any(|grant| grant.permits(command, key))
+    }
+}
+
+/// Roles by name, each line of the config holds a name and the commands granted to it.
+pub struct Roles {
+    roles: HashMap<String, Role>,
+}
+
+impl Roles {
+    pub fn from_env() -> io::Result<Self> {
+        let (config, source) = match settings::var("ROLES") {
+            Ok(path) => (fs::read_to_string(&path)?, path),
+            Err(_) => (DEFAULT_ROLES.to_string(), String::from("defaults")),
+        };
+
+        let mut roles = HashMap::new();
+        for line in config.lines() {
+            let mut words = line.split('#').next().unwrap_or_default().split_whitespace();
+            if let Some(name) = words.next() {
+                roles.insert(name.to_string(), Role { grants: words.map(Grant::parse).collect() });
+            }
+        }
+
+        info!("Loaded {} roles from {}", roles.len(), source);
+        if !roles.contains_key("admin") {
+            warn!("No admin role in {}, identities without a role are refused", source);
+        }
+
+        Ok(Self { roles })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Role> {
+        self.roles.get(name)
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use log::info;
+use tracing::span::EnteredSpan;
+use tracing::{info_span, Span};
+
+use crate::cancel::{Cancel, Stopped};
+use crate::logger;
+
+/// Chunks of a fork_join in flight at once.
+static WINDOW: usize = 64;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+enum Task {
+    /// Skipped if cancelled while still queued.
+    New(Job, Cancel, Queued),
+    Exit,
+}
+
+/// Span a job was submitted in, and the span of its wait for a worker under it.
+struct Queued {
+    parent: Span,
+    span: Span,
+}
+
+impl Queued {
+    fn new() -> Self {
+        Self { parent: Span::current(), span: info_span!("queued") }
+    }
+
+    /// Ends the wait, the span returned covers the job on `worker` until dropped.
+    fn start(self, worker: i32) -> EnteredSpan {
+        let Queued { parent, span } = self;
+        drop(span);
+        info_span!(parent: &parent, "job", worker).entered()
+    }
+}
+
+struct Worker {
+    id: i32,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: i32, receiver: Arc<Mutex<mpsc::Receiver<Task>>>, load: Arc<AtomicUsize>, busy: Arc<AtomicUsize>) -> Self {
+        let thread = Some(thread::spawn(move || loop {
+            let task = { receiver.lock().unwrap().recv().unwrap() };
+
+            match task {
+                Task::New(_, cancel, _) if cancel.is_cancelled() => {
+                    info!("Worker {} dropped cancelled job", id);
+                }
+                Task::New(job, _, queued) => {
+                    logger::sampled!("Worker {} received job", id);
+                    // jobs the job submits are queued under it in turn
+                    let _job = queued.start(id);
+                    busy.fetch_add(1, Ordering::Relaxed);
+                    job();
+                    busy.fetch_sub(1, Ordering::Relaxed);
+                }
+                Task::Exit => break,
+            }
+
+            load.fetch_sub(1, Ordering::Relaxed);
+        }));
+
+        Self { id, thread }
+    }
+}
+
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    spawner: Spawner,
+}
+
+impl ThreadPool {
+    pub fn new(n: i32) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let load = Arc::new(AtomicUsize::new(0));
+        let busy = Arc::new(AtomicUsize::new(0));
+
+        let mut workers = Vec::with_capacity(n as usize);
+        for i in 0..n {
+            workers.push(Worker::new(i, Arc::clone(&receiver), Arc::clone(&load), Arc::clone(&busy)));
+        }
+
+        ThreadPool { workers, spawner: Spawner { sender, load, busy, size: n as usize } }
+    }
+
+    pub fn execute<F>(&self, cancel: &Cancel, f: F)
+    where
+        F: FnOnce() + 'static + Send,
+    {
+        self.spawner.execute(cancel, f);
+    }
+
+    /// Handle for submitting jobs from the workers themselves.
+    pub fn spawner(&self) -> Spawner {
+        self.spawner.clone()
+    }
+}
+
+#[derive(Clone)]
+pub struct Spawner {
+    sender: mpsc::Sender<Task>,
+    /// Jobs queued or running.
+    load: Arc<AtomicUsize>,
+    /// Jobs running, cancelled ones a worker drops are never counted.
+    busy: Arc<AtomicUsize>,
+    size: usize,
+}
+
+impl Spawner {
+    pub fn execute<F>(&self, cancel: &Cancel, f: F)
+    where
+        F: FnOnce() + 'static + Send,
+    {
+        self.load.fetch_add(1, Ordering::Relaxed);
+        self.sender.send(Task::New(Box::new(f), cancel.clone(), Queued::new())).unwrap();
+    }
+
+    /// Workers with nothing to do right now.
+    pub fn idle(&self) -> usize {
+        self.size.saturating_sub(self.load.load(Ordering::Relaxed))
+    }
+
+    /// Jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.load.load(Ordering::Relaxed).saturating_sub(self.busy())
+    }
+
+    /// Workers running a job.
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::Relaxed)
+    }
+
+    /// Runs `work` for every index in `0..chunks` and hands the results to `join` in index order,
+    /// stopping at the first chunk that gave up.
+    ///
+    /// The calling thread works through the chunks itself, helped by as many workers as are
+    /// idle at the time. Helpers take one chunk per job and queue up again behind whatever
+    /// else was submitted meanwhile, so small jobs are not starved. The caller only ever waits
+    /// for chunks a running thread picked up, so calling this from a worker of a saturated
+    /// pool can not deadlock. Chunks run `WINDOW` at a time, which bounds the results held
+    /// however many chunks there are.
+    pub fn fork_join<T, F, J>(&self, chunks: usize, cancel: &Cancel, work: F, mut join: J) -> Result<(), Stopped>
+    where
+        T: Send + 'static,
+        F: Fn(usize) -> Result<T, Stopped> + Send + Sync + 'static,
+        J: FnMut(T),
+    {
+        let work = Arc::new(work);
+
+        for first in (0..chunks).step_by(WINDOW) {
+            cancel.check()?;
+
+            let size = WINDOW.min(chunks - first);
+            let fork = Arc::new(Fork {
+                work: Arc::clone(&work),
+                cancel: cancel.clone(),
+                first,
+                size,
+                next: AtomicUsize::new(0),
+                results: Mutex::new((0..size).map(|_| None).collect()),
+                finished: Condvar::new(),
+            });
+
+            for _ in 0..self.idle().min(size - 1) {
+                Arc::clone(&fork).help(self.clone());
+            }
+
+            while fork.step() {}
+
+            let mut results = fork.results.lock().unwrap();
+            while results.iter().any(Option::is_none) {
+                results = fork.finished.wait(results).unwrap();
+            }
+
+            for result in results.drain(..).flatten() {
+                join(result?);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// One window of a fork_join, the chunks `first..first + size`.
+struct Fork<T, F> {
+    work: Arc<F>,
+    cancel: Cancel,
+    first: usize,
+    size: usize,
+    next: AtomicUsize,
+    results: Mutex<Vec<Option<Result<T, Stopped>>>>,
+    finished: Condvar,
+}
+
+impl<T, F> Fork<T, F>
+where
+    T: Send + 'static,
+    F: Fn(usize) -> Result<T, Stopped> + Send + Sync + 'static,
+{
+    /// Runs the next chunk, false once none are left. Chunks left once cancelled give up without running.
+    fn step(&self) -> bool {
+        let chunk = self.next.fetch_add(1, Ordering::Relaxed);
+        if chunk >= self.size {
+            return false;
+        }
+
+        let result = self.cancel.check().and_then(|()| (self.work)(self.first + chunk));
+        self.results.lock().unwrap()[chunk] = Some(result);
+        self.finished.notify_all();
+
+        true
+    }
+
+    fn help(self: Arc<Self>, pool: Spawner) {
+        pool.clone().execute(&self.cancel.clone(), move || {
+            if self.step() {
+                self.help(pool);
+            }
+        });
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        for _ in &self.workers {
+            self.spawner.sender.send(Task::Exit).unwrap();
+        }
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                info!("Joining thread {}", &worker.id);
+                thread.join().unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::error::Error;
+use std::fs::File;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::info;
+use tracing::{field, info_span, Span};
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard, TraceStyle};
+use tracing_subscriber::prelude::*;
+
+use crate::settings;
+
+/// Requests read on any connection, the last one's number is the id of the next.
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Records spans to `TRACE_FILE` as Chrome trace JSON, for `chrome://tracing` or Perfetto.
+///
+/// Without the variable spans are never recorded. The file is complete once the guard is dropped.
+pub fn setup() -> Result<Option<FlushGuard>, Box<dyn Error>> {
+    let Ok(path) = settings::var("TRACE_FILE") else {
+        return Ok(None);
+    };
+
+    // every span is drawn from opening to closing under the connection it belongs to,
+    // wherever it was entered, as a connection hops between threads and loop iterations
+    let (chrome, guard) = ChromeLayerBuilder::new()
+        .writer(File::create(&path)?)
+        .include_args(true)
+        .trace_style(TraceStyle::Async)
+        .build();
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(chrome))?;
+
+    info!("Tracing to {}", path);
+    Ok(Some(guard))
+}
+
+/// Span of a connection from accepting it to closing it, its requests are children.
+pub fn connection(connection: u64) -> Span {
+    info_span!("connection", conn = connection)
+}
+
+/// Span of a request from reading it to having its reply out, `command` is recorded once it is parsed.
+pub fn request(parent: &Span, connection: u64) -> Span {
+    info_span!(parent: parent, "request", conn = connection, request = REQUESTS.fetch_add(1, Ordering::Relaxed) + 1, command = field::Empty)
+}
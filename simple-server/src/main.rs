@@ -1,29 +1,23 @@
-use std::io::{self, BufRead, Write};
+mod logger;
+mod settings;
+mod shutdown;
+mod timeouts;
+
+use std::io::{self, Write};
 use std::net;
 
-use fern;
 use log::{info, warn, error};
 
-static PORT: u32 = 3000;
-
-fn setup_logger() -> Result<(), log::SetLoggerError> {
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{}][{}] {}",
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Info)
-        .chain(std::io::stdout())
-        .apply()
-}
+use crate::shutdown::Shutdown;
+use crate::timeouts::Timeouts;
 
-fn handle(stream: net::TcpStream) -> io::Result<usize> {
+pub static PORT: u16 = 3000;
+pub static READ_BUFFER: usize = 8 * 1024;
+
+/// The only thread serves one client at a time, so a client that stalls is dropped once it times out.
+fn handle(stream: net::TcpStream, timeouts: Timeouts, read_buffer: usize, shutdown: &Shutdown) -> io::Result<usize> {
     let mut buf = String::new();
-    let mut reader = io::BufReader::new(&stream);
+    let mut reader = io::BufReader::with_capacity(read_buffer, &stream);
     let mut writer = io::BufWriter::new(&stream);
 
     let (ip, port) = (
@@ -31,11 +25,27 @@ fn handle(stream: net::TcpStream) -> io::Result<usize> {
         stream.peer_addr().unwrap().port(),
     );
 
-    info!("Connection from {}:{}", ip, port);
+    logger::sampled!("Connection from {}:{}", ip, port);
+
+    stream.set_write_timeout(timeouts.write)?;
+    let _tracked = shutdown.track(&stream)?;
 
     loop {
         buf.clear();
-        let len = reader.read_line(&mut buf)?;
+        let len = match timeouts.read_line(&mut reader, &stream, &mut buf) {
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                warn!("Closing {}:{}, {}", ip, port, err);
+                break;
+            },
+            res => res?,
+        };
+
+        if len == 0 && shutdown.requested() {
+            info!("Sending bye to {}:{}", ip, port);
+            let _ = writer.write_all("bye\n".as_bytes()).and_then(|_| writer.flush());
+            let _ = stream.shutdown(net::Shutdown::Both);
+            break;
+        }
 
         if len == 0 || buf == "done\n" {
             info!("Shutdown {}:{}", ip, port);
@@ -43,33 +53,48 @@ fn handle(stream: net::TcpStream) -> io::Result<usize> {
             break;
         }
 
-        info!("Received from {}:{} > [bytes {}][{}]", ip, port, len, &buf[..len-1]);
+        logger::sampled!("Received from {}:{} > [bytes {}][{}]", ip, port, len, &buf[..len-1]);
 
-        if let Err(_) = writer.write_all("ack\n".as_bytes()) {
+        if let Err(err) = writer.write_all("ack\n".as_bytes()).and_then(|_| writer.flush()) {
+            if timeouts::is_timeout(&err) {
+                warn!("Closing {}:{}, the reply was not taken within {:?}", ip, port, timeouts.write.unwrap_or_default());
+                break;
+            }
             error!("Could not respond!");
+            return Err(err);
         }
-
-        writer.flush()?;
     }
 
     Ok(0)
 }
 
 fn main() -> io::Result<()> {
-    setup_logger()
-        .err()
-        .map(|err| println!("Could not start logging: {}", err));
+    settings::load();
+    if let Err(err) = logger::setup() {
+        println!("Could not start logging: {}", err);
+    }
 
-    let listener = net::TcpListener::bind(format!("127.0.0.1:{}", PORT))?;
+    let timeouts = Timeouts::from_env();
+    let read_buffer = settings::parse("READ_BUFFER", READ_BUFFER).max(1);
+    let addr = settings::bind(settings::parse("PORT", PORT))?;
+    let listener = net::TcpListener::bind(addr)?;
+    let shutdown = Shutdown::install(addr)?;
 
-    info!("Server started on port {}", PORT);
+    info!("Server started on port {}", addr.port());
 
+    let mut accepted = 0;
     for connection in listener.incoming() {
+        if shutdown.requested() {
+            break;
+        }
+
         match connection {
             Ok(stream) => {
-                handle(stream)
-                    .err()
-                    .map(|err| warn!("Stream error: {}", err));
+                accepted += 1;
+                let _connection = logger::connection(accepted);
+                if let Err(err) = handle(stream, timeouts, read_buffer, &shutdown) {
+                    warn!("Stream error: {}", err);
+                }
             }
             Err(err) => {
                 warn!("Bad connection: {}", err);
@@ -77,5 +102,8 @@ fn main() -> io::Result<()> {
         }
     }
 
+    info!("Server stopped");
+    logger::flush();
+
     Ok(())
 }
@@ -0,0 +1,74 @@
+use std::io;
+use std::net::{self, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::info;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+/// Stops the server on SIGINT or SIGTERM.
+///
+/// The signal does not interrupt a blocking `accept` or read, so a watcher thread
+/// shuts down the read half of the client being served, which then reads end of file,
+/// and connects once to the listener to wake `accept`.
+pub struct Shutdown {
+    requested: AtomicBool,
+    client: Mutex<Option<net::TcpStream>>,
+}
+
+impl Shutdown {
+    pub fn install(addr: SocketAddr) -> io::Result<Arc<Self>> {
+        let shutdown = Arc::new(Self {
+            requested: AtomicBool::new(false),
+            client: Mutex::new(None),
+        });
+
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let watcher = Arc::clone(&shutdown);
+
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                info!("Received signal {}, shutting down", signal);
+                watcher.trigger(addr);
+            }
+        });
+
+        Ok(shutdown)
+    }
+
+    fn trigger(&self, addr: SocketAddr) {
+        self.requested.store(true, Ordering::SeqCst);
+
+        if let Some(client) = self.client.lock().unwrap().as_ref() {
+            let _ = client.shutdown(net::Shutdown::Read);
+        }
+
+        let _ = net::TcpStream::connect(addr);
+    }
+
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Marks `stream` as the client being served until the returned guard is dropped.
+    pub fn track(&self, stream: &net::TcpStream) -> io::Result<Tracked<'_>> {
+        *self.client.lock().unwrap() = Some(stream.try_clone()?);
+
+        // The signal may have arrived before the client was stored.
+        if self.requested() {
+            stream.shutdown(net::Shutdown::Read)?;
+        }
+
+        Ok(Tracked(self))
+    }
+}
+
+pub struct Tracked<'a>(&'a Shutdown);
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.0.client.lock().unwrap().take();
+    }
+}
@@ -1,15 +1,28 @@
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use rand::{self, RngCore};
 
+use crate::admin::{Admin, Server};
+use crate::cancel::{Cancel, Stopped};
+use crate::primes::{self, Progress};
+use crate::persistence::{AppendLog, Entry};
+use crate::session::Session;
+use crate::snapshot::Snapshotter;
+use crate::thread_pool::Spawner;
+
 enum Command {
     Fortune,
     Increment,
     Counter,
     Upload(String),
     Download(String),
-    Compute(u64),
+    /// The `k` to sum up to, or why it was refused.
+    Compute(Result<u64, String>, Option<u64>, bool),
+    Save,
+    Auth(String),
+    Admin(Admin),
     None
 }
 
@@ -19,6 +32,7 @@ impl Command {
             "fortune" => Command::Fortune,
             "increment" => Command::Increment,
             "counter" => Command::Counter,
+            "save" => Command::Save,
             other => {
                 if other.starts_with("upload") {
                     if let Some(split) = other.split_once(' ') {
@@ -32,9 +46,25 @@ impl Command {
                     }
                 }
 
-                if other.starts_with("compute") {
+                if other.starts_with("auth") {
                     if let Some(split) = other.split_once(' ') {
-                        return Command::Compute(split.1.parse().unwrap_or(0));
+                        return Command::Auth(String::from(split.1));
+                    }
+                }
+
+                if let Some(admin) = Admin::parse(other) {
+                    return Command::Admin(admin);
+                }
+
+                if other.starts_with("compute") {
+                    if let [_, k, options @ ..] = &other.split_whitespace().collect::<Vec<_>>()[..] {
+                        let timeout = options.iter().find_map(|option| option.strip_prefix("timeout=")).and_then(|ms| ms.parse().ok());
+                        let k = match k.parse() {
+                            Ok(k) if k > primes::MAX_K => Err(String::from("k too large")),
+                            Ok(k) => Ok(k),
+                            Err(_) => Err(format!("invalid k {}", k)),
+                        };
+                        return Command::Compute(k, timeout, options.contains(&"progress"));
                     }
                 }
 
@@ -42,6 +72,26 @@ impl Command {
             }
         }
     }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Fortune => "fortune",
+            Command::Increment => "increment",
+            Command::Counter => "counter",
+            Command::Upload(_) => "upload",
+            Command::Download(_) => "download",
+            Command::Compute(..) => "compute",
+            Command::Save => "save",
+            Command::Auth(_) => "auth",
+            Command::Admin(admin) => admin.name(),
+            Command::None => "unknown",
+        }
+    }
+}
+
+/// What `stats` counts the request `message` as, rate limited ones included.
+pub fn name(message: &str) -> &'static str {
+    Command::parse(message.trim_end()).name()
 }
 
 static FORTUNES: &[&str] = &[
@@ -51,46 +101,98 @@ static FORTUNES: &[&str] = &[
     "Doing your best means never stop trying.\n",
 ];
 
-fn is_prime(x: u64) -> bool {
-    if x == 0 || x == 1 {
-        return false;
-    }
+/// Receives lines that go out ahead of the response.
+pub type Partial = Arc<dyn Fn(String) + Send + Sync>;
 
-    for i in 2..x/2 {
-        if x % i == 0 {
-            return false;
-        }
-    }
+type Counter = Arc<Mutex<u64>>;
+type Uploads = Arc<Mutex<HashSet<String>>>;
 
-    true
+/// Captures a copy of the state and hands it to the snapshotter.
+pub fn save(counter: &Counter, uploads: &Uploads, snapshots: &Snapshotter) -> bool {
+    // both at once, counter first, so the snapshot is of a single moment
+    let (counter, uploads) = {
+        let counter = counter.lock().unwrap();
+        let uploads = uploads.lock().unwrap();
+        (*counter, uploads.clone())
+    };
+    snapshots.save(counter, uploads)
 }
 
-type Counter = Arc<Mutex<u64>>;
-type Uploads = Arc<Mutex<HashSet<String>>>;
+/// What a request needs from the worker it runs on.
+pub struct Job {
+    /// Flagged once the connection goes away.
+    pub cancel: Cancel,
+    pub pool: Spawner,
+    /// Receives the progress lines of a compute while it runs.
+    pub partial: Partial,
+    /// What admin commands look at.
+    pub server: Arc<Server>,
+}
 
-pub fn handle(message: String, counter: &mut Counter, uploads: &mut Uploads) -> String {
+/// Runs one request on a worker, nothing but `auth` passes before the client authenticated.
+pub fn handle(message: String, session: &mut Session, counter: &mut Counter, uploads: &mut Uploads, log: &AppendLog, snapshots: &Snapshotter, job: &Job) -> String {
     match Command::parse(message.trim_end()) {
+        Command::Auth(token) => match session.authenticate(&token) {
+            Ok(identity) => format!("authenticated: {}\n", identity),
+            Err(err) => format!("ERR {}\n", err),
+        },
+        _ if session.identity().is_none() => {
+            "ERR auth required\n".to_string()
+        },
+        _ if !session.permits(message.trim_end()) => {
+            "ERR forbidden\n".to_string()
+        },
         Command::Fortune => {
             FORTUNES[rand::thread_rng().next_u32() as usize % FORTUNES.len()].to_string()
         },
         Command::Increment => {
-            *counter.lock().unwrap() += 1;
-            "incremented\n".to_string()
+            // the log is in the order the counter moved
+            let mut counter = counter.lock().unwrap();
+            match log.append(Entry::Increment) {
+                Ok(()) => {
+                    *counter += 1;
+                    snapshots.touch();
+                    "incremented\n".to_string()
+                },
+                Err(err) => format!("ERR {}\n", err),
+            }
         },
         Command::Counter => {
             format!("counter: {}\n", *counter.lock().unwrap())
         },
-        Command::Upload(item) => {
-            uploads.lock().unwrap().insert(item);
-            "uploaded\n".to_string()
+        Command::Upload(item) => match log.append(Entry::Upload(item.clone())) {
+            Ok(()) => {
+                uploads.lock().unwrap().insert(item);
+                snapshots.touch();
+                "uploaded\n".to_string()
+            },
+            Err(err) => format!("ERR {}\n", err),
         },
         Command::Download(item) => {
             let found = uploads.lock().unwrap().get(&item).cloned().unwrap_or_else(|| String::from("not found"));
             format!("download: {}\n", found)
         },
-        Command::Compute(k) => {
-            let sum = (0..=k).fold(0, |acc, x| acc + if is_prime(x) { x } else { 0 });
-            format!("computed: {}\n", sum)
+        Command::Compute(Err(err), ..) => {
+            format!("ERR {}\n", err)
+        },
+        Command::Compute(Ok(k), timeout, progress) => {
+            let cancel = job.cancel.with_deadline(timeout.map(|ms| Instant::now() + Duration::from_millis(ms)));
+            let progress = progress.then(|| {
+                let partial = Arc::clone(&job.partial);
+                Arc::new(move |percent| partial(format!("progress: {}%\n", percent))) as Progress
+            });
+
+            match primes::sum(k, &cancel, &job.pool, progress) {
+                Ok(sum) => format!("computed: {}\n", sum),
+                Err(Stopped::Timeout) => "ERR timeout\n".to_string(),
+                Err(Stopped::Cancelled) => "ERR cancelled\n".to_string(),
+            }
+        },
+        Command::Admin(admin) => {
+            job.server.run(admin)
+        },
+        Command::Save => {
+            if save(counter, uploads, snapshots) { "saving\n" } else { "already saving\n" }.to_string()
         },
         Command::None => {
             "ok\n".to_string()
@@ -1,9 +1,26 @@
+mod admin;
+mod cancel;
 mod handler;
 mod logger;
+mod metrics;
+mod persistence;
+mod primes;
+mod ratelimit;
+mod roles;
+mod session;
+mod settings;
+mod shutdown;
+mod slowlog;
+mod snapshot;
 mod thread_pool;
+mod timeouts;
+mod tls;
+mod trace;
 
-use std::io::{self, Write, Read};
+use std::io;
 use std::net;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use std::collections::HashSet;
 use std::os::unix::io::AsRawFd;
@@ -11,71 +28,205 @@ use std::sync::{Arc, Mutex};
 
 use log::{info, warn};
 use polling::{Event, Poller};
-
+use rustls::ServerConfig;
+use tracing::Span;
+
+use crate::admin::{Clients, Config, Server, Stats};
+use crate::cancel::Cancel;
+use crate::handler::Job;
+use crate::metrics::Metrics;
+use crate::persistence::{AppendLog, Fsync};
+use crate::ratelimit::RateLimiter;
+use crate::session::{Session, Tokens};
+use crate::shutdown::Signals;
+use crate::slowlog::Slowlog;
+use crate::snapshot::Snapshotter;
 use crate::thread_pool::ThreadPool;
-
-static PORT: i32 = 3000;
-static THREADS: i32 = 4;
+use crate::timeouts::{Deadline, Phase, Timeouts};
+use crate::tls::Stream;
+
+pub static PORT: u16 = 3000;
+pub static THREADS: i32 = 4;
+pub static READ_BUFFER: usize = 256;
+static LOG_PATH: &str = "appendonly.log";
+static SNAPSHOT_PATH: &str = "dump.snap";
+static SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Output of the request running on a connection, written out as it comes in.
+#[derive(Default)]
+struct Response {
+    output: String,
+    done: bool,
+}
 
 struct State {
     listener: net::TcpListener,
     listener_id: usize,
 
-    responses: Arc<Mutex<Vec<Option<String>>>>,
-    connections: Vec<Option<net::TcpStream>>,
-
-    poller: Poller,
+    responses: Arc<Mutex<Vec<Response>>>,
+    connections: Vec<Option<Stream>>,
+    /// Flagged when the connection goes away, so that its running request can give up.
+    cancels: Vec<Cancel>,
+    /// Held by the worker running a request of the connection.
+    sessions: Vec<Option<Arc<Mutex<Session>>>>,
+    tokens: Arc<Tokens>,
+    tls: Option<Arc<ServerConfig>>,
+    /// None while a request runs.
+    deadlines: Vec<Option<Deadline>>,
+    /// Whether the connection waits for its next request, those are sent a bye on shutdown.
+    waiting: Vec<bool>,
+    /// Span of the connection on the descriptor.
+    spans: Vec<Span>,
+    /// Span of the request running on the connection, none between requests.
+    requests: Vec<Span>,
+    timeouts: Timeouts,
+    /// Where requests are read into, `READ_BUFFER` bytes long.
+    buffer: Vec<u8>,
+    limiter: Arc<Mutex<RateLimiter>>,
+    server: Arc<Server>,
+
+    poller: Arc<Poller>,
     events: Vec<Event>,
+    signals: Signals,
+    signals_id: usize,
+    /// Set once a signal came in, the loop ends by then at the latest.
+    drain: Option<Instant>,
 
     counter: Arc<Mutex<u64>>,
     uploads: Arc<Mutex<HashSet<String>>>,
+    log: Arc<AppendLog>,
+    snapshots: Arc<Snapshotter>,
 }
 
 fn main() -> io::Result<()> {
+    settings::load();
     logger::setup().expect("Could not start logger");
+    let _trace = trace::setup().expect("Could not start tracing");
 
-    info!("Created thread pool with {} threads", THREADS);
+    let addr = settings::bind(settings::parse("PORT", PORT))?;
+    let threads = settings::parse("THREADS", THREADS).max(1);
+    let read_buffer = settings::parse("READ_BUFFER", READ_BUFFER).max(1);
 
-    let listener = net::TcpListener::bind(format!("127.0.0.1:{}", PORT))?;
+    info!("Created thread pool with {} threads", threads);
+
+    let listener = net::TcpListener::bind(addr)?;
     let listener_id = listener.as_raw_fd() as usize;
     listener.set_nonblocking(true)?;
 
-    info!("Server started on port {}", PORT);
+    info!("Server started on port {}", addr.port());
+
+    let thread_pool = ThreadPool::new(threads);
+
+    let fsync = Fsync::from_env();
+    let (log, mut counter, mut uploads) = AppendLog::open(LOG_PATH, fsync)?;
+    let snapshots = Snapshotter::new(SNAPSHOT_PATH, SNAPSHOT_INTERVAL);
 
-    let thread_pool = ThreadPool::new(4);
+    if counter == 0 && uploads.is_empty() {
+        if let Some((saved_counter, saved_uploads)) = snapshots.load()? {
+            log.seed(saved_counter, saved_uploads.clone());
+            (counter, uploads) = (saved_counter, saved_uploads);
+        }
+    }
+
+    let signals = Signals::new()?;
+    let signals_id = signals.as_raw_fd() as usize;
+
+    let poller = Arc::new(Poller::new()?);
+    let tls = tls::from_env()?;
+    let timeouts = Timeouts::from_env();
+
+    let metrics = Arc::new(Metrics::default());
+    metrics::serve(Arc::clone(&metrics), thread_pool.spawner())?;
+
+    let slowlog = Slowlog::from_env();
+    let server = Server {
+        stats: Stats::new(Arc::clone(&metrics)),
+        metrics,
+        clients: Clients::new(Arc::clone(&poller)),
+        config: Config::new(vec![
+            ("bind", addr.ip().to_string()),
+            ("port", addr.port().to_string()),
+            ("threads", threads.to_string()),
+            ("read-buffer", read_buffer.to_string()),
+            ("idle-timeout", admin::millis(timeouts.idle)),
+            ("read-timeout", admin::millis(timeouts.read)),
+            ("write-timeout", admin::millis(timeouts.write)),
+            ("appendfsync", fsync.to_string()),
+            ("tls", if tls.is_some() { "on" } else { "off" }.to_string()),
+            ("slowlog-max-len", slowlog.capacity().to_string()),
+        ], Arc::clone(&slowlog)),
+        slowlog,
+    };
 
     let mut state = State {
         listener,
         listener_id,
 
         connections: Vec::new(),
+        cancels: Vec::new(),
+        sessions: Vec::new(),
+        tokens: Arc::new(Tokens::from_env()?),
+        tls,
+        deadlines: Vec::new(),
+        waiting: Vec::new(),
+        spans: Vec::new(),
+        requests: Vec::new(),
+        timeouts,
+        buffer: vec![0; read_buffer],
+        limiter: Arc::new(Mutex::new(RateLimiter::from_env())),
+        server: Arc::new(server),
         responses: Arc::new(Mutex::new(Vec::new())),
 
         events: Vec::new(),
-        poller: Poller::new()?,
-
-        counter: Arc::new(Mutex::new(0u64)),
-        uploads: Arc::new(Mutex::new(HashSet::new())),
+        poller,
+        signals,
+        signals_id,
+        drain: None,
+
+        counter: Arc::new(Mutex::new(counter)),
+        uploads: Arc::new(Mutex::new(uploads)),
+        log: Arc::new(log),
+        snapshots: Arc::new(snapshots),
     };
 
-    state.poller.add(&state.listener, Event::readable(state.listener_id))?;
+    {
+        let (counter, uploads, snapshots) = (Arc::clone(&state.counter), Arc::clone(&state.uploads), Arc::clone(&state.snapshots));
+        thread::spawn(move || loop {
+            thread::sleep(snapshots.next_check());
+            if snapshots.due() {
+                handler::save(&counter, &uploads, &snapshots);
+            }
+        });
+    }
 
-    let mut iter = 0;
+    state.poller.add(&state.listener, Event::readable(state.listener_id))?;
+    state.poller.add(&state.signals, Event::readable(state.signals_id))?;
 
     loop {
         state.events.clear();
-        state.poller.wait(&mut state.events, None)?;
-
-        if state.events.len() > 1 {
-            info!("Event loop iter [{}] -> events [{}]", iter, state.events.len());
-        }
-        iter += 1;
+        let deadline = state.deadlines.iter().flatten().map(|deadline| deadline.at).chain(state.drain).min();
+        match state.poller.wait(&mut state.events, deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))) {
+            // the signal pipe is readable on the next wait
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            res => res?,
+        };
+
+        state.server.metrics.wake(state.events.len());
+
+        for ev in state.events.clone() {
+            if ev.key == state.signals_id {
+                if state.signals.received() && state.drain.is_none() {
+                    let timeout = shutdown::drain_timeout();
+                    info!("Shutting down, draining {} connections for up to {:?}", state.connections.iter().flatten().count(), timeout);
+                    state.drain = Some(Instant::now() + timeout);
+                    state.poller.delete(&state.listener)?;
+                }
+                state.poller.modify(&state.signals, Event::readable(state.signals_id))?;
 
-        for ev in state.events.iter() {
-            if ev.key == state.listener_id && ev.readable {
+            } else if ev.key == state.listener_id && ev.readable {
                 match state.listener.accept() {
                     Ok((stream, socket)) => {
-                        info!("Received connection from [{}:{}]", socket.ip(), socket.port());
+                        logger::sampled!("Received connection from [{}:{}]", socket.ip(), socket.port());
                         stream.set_nonblocking(true)?;
 
                         let connection_fd = stream.as_raw_fd() as usize;
@@ -84,11 +235,29 @@ fn main() -> io::Result<()> {
                         let mut locked_responses = state.responses.lock().unwrap();
                         while state.connections.len() <= connection_fd {
                             state.connections.push(None);
-                            locked_responses.push(None);
+                            state.cancels.push(Cancel::default());
+                            state.sessions.push(None);
+                            state.deadlines.push(None);
+                            state.waiting.push(false);
+                            state.spans.push(Span::none());
+                            state.requests.push(Span::none());
+                            locked_responses.push(Response::default());
                         }
 
-                        state.connections[connection_fd] = Some(stream);
-                        locked_responses[connection_fd] = None;
+                        state.connections[connection_fd] = Some(Stream::accept(stream, state.tls.as_ref())?);
+                        state.cancels[connection_fd] = Cancel::default();
+                        state.deadlines[connection_fd] = state.timeouts.start(Phase::Idle);
+                        state.waiting[connection_fd] = true;
+                        state.spans[connection_fd] = trace::connection(connection_fd as u64);
+                        locked_responses[connection_fd] = Response::default();
+                        state.server.clients.register(connection_fd, socket);
+                        state.server.metrics.accepted();
+
+                        // the previous connection on this descriptor is gone
+                        let session = Session::new(Arc::clone(&state.tokens), socket);
+                        if let Some(previous) = state.sessions[connection_fd].replace(Arc::new(Mutex::new(session))) {
+                            state.limiter.lock().unwrap().forget(previous.lock().unwrap().peer());
+                        }
                     },
 
                     Err(err) => {
@@ -99,47 +268,207 @@ fn main() -> io::Result<()> {
                 state.poller.modify(&state.listener, Event::readable(state.listener_id))?;
 
             } else {
+                let _connection = logger::connection(ev.key as u64);
 
                 if ev.readable {
                     let conn = state.connections.get_mut(ev.key).unwrap().as_mut().unwrap();
 
-                    let mut buf = [0; 256];
-                    let len = conn.read(&mut buf)?;
+                    let len = conn.read(&mut state.buffer);
+
+                    // the TLS handshake is still going on, or only part of a record came in
+                    if len.as_ref().is_err_and(|err| err.kind() == io::ErrorKind::WouldBlock) {
+                        if state.deadlines[ev.key].is_none_or(|deadline| deadline.phase != Phase::Read) {
+                            state.deadlines[ev.key] = state.timeouts.start(Phase::Read);
+                        }
+                        state.poller.modify(conn.socket(), Event::readable(ev.key))?;
+                        continue;
+                    }
+
+                    // a client leaving with progress lines unread resets the connection instead of closing it
+                    let len = len.unwrap_or_else(|err| {
+                        warn!("Client error: {}", err);
+                        0
+                    });
                     if len > 0 {
-                        let message = String::from_utf8_lossy(&buf[..len]).to_string();
+                        let message = String::from_utf8_lossy(&state.buffer[..len]).to_string();
+                        state.server.clients.received(ev.key, len);
+                        state.server.metrics.received(len);
+                        let started = Instant::now();
 
                         let responses = Arc::clone(&state.responses);
                         let key = ev.key;
                         let (mut counter, mut uploads) = (Arc::clone(&state.counter), Arc::clone(&state.uploads));
-
-                        thread_pool.execute(move || {
-                            responses.lock().unwrap()[key] = Some(handler::handle(message, &mut counter, &mut uploads));
+                        let (log, snapshots) = (Arc::clone(&state.log), Arc::clone(&state.snapshots));
+                        let (cancel, spawner) = (state.cancels[key].clone(), thread_pool.spawner());
+                        let (limiter, session) = (Arc::clone(&state.limiter), Arc::clone(state.sessions[key].as_ref().unwrap()));
+                        let server = Arc::clone(&state.server);
+
+                        // the request waits for a worker and runs on it under its span
+                        state.requests[key] = trace::request(&state.spans[key], key as u64, handler::name(&message));
+                        let _request = state.requests[key].enter();
+
+                        // the slot may belong to a new connection once cancelled
+                        let partial = {
+                            let (responses, cancel) = (Arc::clone(&responses), cancel.clone());
+                            Arc::new(move |line: String| {
+                                if !cancel.is_cancelled() {
+                                    responses.lock().unwrap()[key].output.push_str(&line);
+                                }
+                            })
+                        };
+
+                        thread_pool.execute(&state.cancels[key], move || {
+                            let _connection = logger::connection(key as u64);
+                            let queued = started.elapsed();
+                            let mut session = session.lock().unwrap();
+                            let (name, line) = (handler::name(&message), message.clone());
+                            let limited = limiter.lock().unwrap().check(session.peer(), &message);
+                            let response = match limited {
+                                Ok(()) => {
+                                    let job = Job { cancel: cancel.clone(), pool: spawner, partial, server: Arc::clone(&server) };
+                                    handler::handle(message, &mut session, &mut counter, &mut uploads, &log, &snapshots, &job)
+                                },
+                                Err(wait) => ratelimit::rejection(wait),
+                            };
+                            server.metrics.request(name, &response, started.elapsed());
+                            server.slowlog.record(session.peer(), &line, started, queued);
+                            if !cancel.is_cancelled() {
+                                server.clients.record(key, name);
+                                let slot = &mut responses.lock().unwrap()[key];
+                                slot.output.push_str(&response);
+                                slot.done = true;
+                            }
                         });
 
-                        state.poller.modify(&*conn, Event::writable(ev.key))?;
+                        state.deadlines[ev.key] = None;
+                        state.waiting[ev.key] = false;
+                        state.poller.modify(conn.socket(), Event::writable(ev.key))?;
                     } else {
-                        state.poller.delete(&*conn)?;
+                        close(&mut state, ev.key)?;
                     }
 
                 } else if ev.writable {
                     let conn = state.connections.get_mut(ev.key).unwrap().as_mut().unwrap();
 
-                    let response = {
-                        state.responses.lock().unwrap().get_mut(ev.key).unwrap().take()
+                    let (output, done) = {
+                        let slot = &mut state.responses.lock().unwrap()[ev.key];
+                        (std::mem::take(&mut slot.output), std::mem::take(&mut slot.done))
                     };
 
-                    match response {
-                        Some(res) => {
-                            conn.write_all(res.as_bytes())?;
-                            state.poller.modify(&*conn, Event::readable(ev.key))?;
-                        },
-
-                        None => {
-                            state.poller.modify(&*conn, Event::writable(ev.key))?;
+                    // records the socket could not take at once go out first
+                    let written = conn.flush().and_then(|_| conn.write_all(output.as_bytes()));
+                    state.server.clients.sent(ev.key, output.len());
+                    state.server.metrics.sent(output.len());
+
+                    // progress lines can go out after the client left, which only ends this connection
+                    if let Err(err) = written {
+                        warn!("Client error: {}", err);
+                        close(&mut state, ev.key)?;
+                    } else if done && conn.wants_write() {
+                        // the client has to take the rest of the reply before the next request
+                        state.responses.lock().unwrap()[ev.key].done = true;
+                        if state.deadlines[ev.key].is_none() {
+                            state.deadlines[ev.key] = state.timeouts.start(Phase::Write);
                         }
+                        state.poller.modify(conn.socket(), Event::writable(ev.key))?;
+                    } else if done {
+                        state.deadlines[ev.key] = state.timeouts.start(Phase::Idle);
+                        state.waiting[ev.key] = true;
+                        state.requests[ev.key] = Span::none();
+                        state.poller.modify(conn.socket(), Event::readable(ev.key))?;
+                    } else if output.is_empty() && hung_up(conn.socket()) {
+                        // still running, give it up if the client hung up meanwhile
+                        close(&mut state, ev.key)?;
+                    } else {
+                        state.poller.modify(conn.socket(), Event::writable(ev.key))?;
                     }
                 }
             }
         }
+
+        let now = Instant::now();
+        for key in 0..state.deadlines.len() {
+            let Some(deadline) = state.deadlines[key].filter(|deadline| deadline.at <= now) else {
+                continue;
+            };
+
+            if let Some(session) = &state.sessions[key] {
+                let peer = session.lock().unwrap().peer();
+                warn!("Closing [{}:{}], {}", peer.ip(), peer.port(), deadline);
+            }
+            close(&mut state, key)?;
+        }
+
+        for key in state.server.clients.killed() {
+            if let Some(session) = &state.sessions[key] {
+                let peer = session.lock().unwrap().peer();
+                info!("Closing [{}:{}], killed", peer.ip(), peer.port());
+            }
+            close(&mut state, key)?;
+        }
+
+        let Some(drain) = state.drain else {
+            continue;
+        };
+
+        for key in 0..state.connections.len() {
+            if !state.waiting[key] {
+                continue;
+            }
+
+            if let (Some(conn), Some(session)) = (state.connections[key].as_mut(), &state.sessions[key]) {
+                let peer = session.lock().unwrap().peer();
+                info!("Sending bye to [{}:{}]", peer.ip(), peer.port());
+                let _ = conn.write_all(b"bye\n");
+                close(&mut state, key)?;
+            }
+        }
+
+        let open = state.connections.iter().flatten().count();
+        if open == 0 {
+            break;
+        }
+
+        if drain <= now {
+            warn!("Closing {} connections still busy after the drain timeout", open);
+            for key in 0..state.connections.len() {
+                close(&mut state, key)?;
+            }
+            break;
+        }
     }
+
+    // the workers only get to exit once the cancelled requests gave up
+    drop(thread_pool);
+    state.log.close();
+    info!("Server stopped");
+    logger::flush();
+
+    Ok(())
 }
+
+/// Stops polling the connection and gives up its running request.
+fn close(state: &mut State, key: usize) -> io::Result<()> {
+    state.cancels[key].cancel();
+    state.deadlines[key] = None;
+    state.waiting[key] = false;
+    state.requests[key] = Span::none();
+    state.spans[key] = Span::none();
+    state.server.clients.remove(key);
+
+    if let Some(conn) = state.connections[key].take() {
+        state.server.metrics.closed();
+        state.poller.delete(conn.socket())?;
+    }
+
+    Ok(())
+}
+
+/// Whether the peer closed or reset the connection, a pipelined request is left in the socket.
+fn hung_up(stream: &net::TcpStream) -> bool {
+    match stream.peek(&mut [0u8; 1]) {
+        Ok(len) => len == 0,
+        Err(err) => err.kind() != io::ErrorKind::WouldBlock,
+    }
+}
+
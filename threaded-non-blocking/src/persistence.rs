@@ -0,0 +1,412 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn, error};
+
+use crate::settings;
+
+static REWRITE_MIN_SIZE: u64 = 64 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Fsync {
+    Always,
+    EverySecond,
+    Never,
+}
+
+impl Fsync {
+    /// Reads the policy from `APPEND_FSYNC`, defaulting to `everysec`.
+    pub fn from_env() -> Self {
+        match settings::var("APPEND_FSYNC") {
+            Ok(value) => value.parse().unwrap_or_else(|err| {
+                warn!("{}, using everysec", err);
+                Fsync::EverySecond
+            }),
+            Err(_) => Fsync::EverySecond,
+        }
+    }
+}
+
+impl FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::EverySecond),
+            "never" => Ok(Fsync::Never),
+            other => Err(format!("unknown fsync policy: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Fsync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Fsync::Always => "always",
+            Fsync::EverySecond => "everysec",
+            Fsync::Never => "never",
+        })
+    }
+}
+
+pub enum Entry {
+    Increment,
+    Upload(String),
+}
+
+enum Message {
+    /// An entry, with where to report once it is on disk under `Fsync::Always`.
+    Append(Entry, Option<mpsc::SyncSender<Result<(), String>>>),
+    Seed(u64, HashSet<String>),
+    Exit,
+}
+
+/// Append-only mutation log, written by a background thread so that callers
+/// (including the single threaded event loops) only wait on the disk under `Fsync::Always`.
+///
+/// Once the writer failed, every append reports why instead of dropping the entry.
+pub struct AppendLog {
+    sender: mpsc::Sender<Message>,
+    fsync: Fsync,
+    failed: Arc<Mutex<Option<String>>>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl AppendLog {
+    /// Replays the log at `path` and starts the writer thread.
+    /// Returns the log together with the restored counter and uploads.
+    pub fn open(path: impl AsRef<Path>, fsync: Fsync) -> io::Result<(Self, u64, HashSet<String>)> {
+        let path = path.as_ref().to_path_buf();
+        let (counter, uploads, len) = replay(&path)?;
+
+        info!("Replayed {} -> counter {}, {} uploads", path.display(), counter, uploads.len());
+
+        let writer = Writer::new(path, fsync, len, counter, uploads.clone())?;
+        Ok((Self::start(writer), counter, uploads))
+    }
+
+    fn start(mut writer: Writer) -> Self {
+        let fsync = writer.fsync;
+        let (sender, receiver) = mpsc::channel();
+        let failed = Arc::new(Mutex::new(None));
+
+        let reason = Arc::clone(&failed);
+        let thread = Mutex::new(Some(thread::spawn(move || {
+            if let Err(err) = writer.run(&receiver) {
+                error!("Append log writer failed: {}", err);
+                reason.lock().unwrap().replace(err.to_string());
+            }
+            // entries still queued are dropped with the receiver, their senders find the reason set by then
+        })));
+
+        Self { sender, fsync, failed, thread }
+    }
+
+    /// Replaces the log contents with the given state, used when it was restored from elsewhere.
+    pub fn seed(&self, counter: u64, uploads: HashSet<String>) {
+        if self.sender.send(Message::Seed(counter, uploads)).is_err() {
+            warn!("Append log writer is gone, seed dropped");
+        }
+    }
+
+    /// Queues the entry, and under `Fsync::Always` waits until it is synced to disk.
+    pub fn append(&self, entry: Entry) -> io::Result<()> {
+        let (ack, written) = match self.fsync {
+            Fsync::Always => {
+                let (ack, written) = mpsc::sync_channel(1);
+                (Some(ack), Some(written))
+            },
+            _ => (None, None),
+        };
+
+        if self.sender.send(Message::Append(entry, ack)).is_err() {
+            return Err(self.gone());
+        }
+
+        match written.map(|written| written.recv()) {
+            Some(Ok(Err(err))) => Err(io::Error::other(format!("append log failed: {}", err))),
+            Some(Err(mpsc::RecvError)) => Err(self.gone()),
+            Some(Ok(Ok(()))) | None => Ok(()),
+        }
+    }
+
+    /// Why the writer stopped taking entries.
+    fn gone(&self) -> io::Error {
+        match self.failed.lock().unwrap().as_ref() {
+            Some(err) => io::Error::other(format!("append log failed: {}", err)),
+            None => io::Error::other("append log is closed"),
+        }
+    }
+
+    /// Stops the writer once everything sent before is written and synced, later entries are dropped.
+    pub fn close(&self) {
+        let _ = self.sender.send(Message::Exit);
+
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl Drop for AppendLog {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Rebuilds the state from the log, along with the length of its complete lines.
+///
+/// A last line without its newline was torn by a crash in the middle of the write, it is left out.
+fn replay(path: &Path) -> io::Result<(u64, HashSet<String>, u64)> {
+    let (mut counter, mut uploads, mut len) = (0u64, HashSet::new(), 0u64);
+
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((counter, uploads, len)),
+        Err(err) => return Err(err),
+    };
+
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+
+        if line.pop() != Some(b'\n') {
+            warn!("Dropping torn last entry of the log: {}", String::from_utf8_lossy(&line));
+            break;
+        }
+        len += line.len() as u64 + 1;
+        let line = String::from_utf8_lossy(&line).to_string();
+
+        match line.split_once(' ') {
+            None if line == "increment" => counter += 1,
+            Some(("counter", value)) => match value.parse() {
+                Ok(value) => counter = value,
+                Err(_) => warn!("Bad counter entry in log: {}", line),
+            },
+            Some(("upload", item)) => {
+                uploads.insert(String::from(item));
+            },
+            _ => warn!("Skipping unknown log entry: {}", line),
+        }
+    }
+
+    Ok((counter, uploads, len))
+}
+
+struct Writer {
+    path: PathBuf,
+    fsync: Fsync,
+    file: BufWriter<File>,
+    size: u64,
+    rewrite_size: u64,
+    last_sync: Instant,
+
+    counter: u64,
+    uploads: HashSet<String>,
+}
+
+impl Writer {
+    /// Opens the log to append after its first `len` bytes, cutting off a torn last line.
+    fn new(path: PathBuf, fsync: Fsync, len: u64, counter: u64, uploads: HashSet<String>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if file.metadata()?.len() > len {
+            file.set_len(len)?;
+        }
+        let size = len;
+
+        Ok(Self {
+            path,
+            fsync,
+            file: BufWriter::new(file),
+            size,
+            rewrite_size: REWRITE_MIN_SIZE.max(size * 2),
+            last_sync: Instant::now(),
+            counter,
+            uploads,
+        })
+    }
+
+    fn run(&mut self, receiver: &mpsc::Receiver<Message>) -> io::Result<()> {
+        loop {
+            let (written, ack) = match receiver.recv_timeout(Duration::from_secs(1)) {
+                Ok(Message::Append(entry, ack)) => (self.write(entry), ack),
+                Ok(Message::Seed(counter, uploads)) => {
+                    (self.counter, self.uploads) = (counter, uploads);
+                    (self.rewrite(), None)
+                },
+                Ok(Message::Exit) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => (Ok(()), None),
+            };
+
+            let written = written.and_then(|()| self.sync(false));
+            if let Some(ack) = ack {
+                let _ = ack.send(written.as_ref().map(|_| ()).map_err(|err| err.to_string()));
+            }
+            written?;
+
+            if self.size >= self.rewrite_size {
+                self.rewrite()?;
+            }
+        }
+
+        self.sync(true)
+    }
+
+    fn write(&mut self, entry: Entry) -> io::Result<()> {
+        let line = match entry {
+            Entry::Increment => {
+                self.counter += 1;
+                String::from("increment\n")
+            },
+            Entry::Upload(item) if item.contains('\n') => {
+                warn!("Not logging multi-line upload");
+                return Ok(());
+            },
+            Entry::Upload(item) => {
+                let line = format!("upload {}\n", item);
+                self.uploads.insert(item);
+                line
+            },
+        };
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn sync(&mut self, force: bool) -> io::Result<()> {
+        self.file.flush()?;
+
+        let due = match self.fsync {
+            Fsync::Always => true,
+            Fsync::EverySecond => self.last_sync.elapsed() >= Duration::from_secs(1),
+            Fsync::Never => false,
+        };
+
+        if due || (force && !matches!(self.fsync, Fsync::Never)) {
+            self.file.get_ref().sync_data()?;
+            self.last_sync = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    /// Compacts the log into the minimal set of entries rebuilding the current state.
+    fn rewrite(&mut self) -> io::Result<()> {
+        let temp = self.path.with_extension("rewrite");
+
+        let mut out = BufWriter::new(File::create(&temp)?);
+        writeln!(out, "counter {}", self.counter)?;
+        for item in &self.uploads {
+            writeln!(out, "upload {}", item)?;
+        }
+        out.flush()?;
+        out.get_ref().sync_all()?;
+        drop(out);
+
+        self.file.flush()?;
+        fs::rename(&temp, &self.path)?;
+
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        let old_size = self.size;
+        self.size = file.metadata()?.len();
+        self.rewrite_size = REWRITE_MIN_SIZE.max(self.size * 2);
+        self.file = BufWriter::new(file);
+
+        info!("Rewrote append log {} -> {} bytes", old_size, self.size);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("append-log-test-{}-{}.log", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn uploads(items: &[&str]) -> HashSet<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn replays_what_was_appended_before_a_crash() {
+        let path = temp("crash");
+        let (log, ..) = AppendLog::open(&path, Fsync::Always).unwrap();
+        log.append(Entry::Increment).unwrap();
+        log.append(Entry::Upload(String::from("a"))).unwrap();
+        log.append(Entry::Increment).unwrap();
+        // synced entries are on disk before the writer gets to stop
+        let (counter, items, _) = replay(&path).unwrap();
+        log.close();
+
+        assert_eq!((counter, items), (2, uploads(&["a"])));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drops_a_torn_last_line() {
+        let path = temp("torn");
+        fs::write(&path, "counter 41\nupload a\nupload b\nincrement\ncounter 9").unwrap();
+
+        let (log, counter, items) = AppendLog::open(&path, Fsync::Always).unwrap();
+        assert_eq!((counter, items), (42, uploads(&["a", "b"])));
+
+        log.append(Entry::Upload(String::from("c"))).unwrap();
+        log.close();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "counter 41\nupload a\nupload b\nincrement\nupload c\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rewrites_into_the_same_state() {
+        let path = temp("rewrite");
+        let (log, ..) = AppendLog::open(&path, Fsync::Never).unwrap();
+        log.append(Entry::Upload(String::from("a"))).unwrap();
+        log.append(Entry::Upload(String::from("a"))).unwrap();
+        // enough to pass the minimal size for a rewrite
+        let increments = REWRITE_MIN_SIZE / 10;
+        for _ in 0..increments {
+            log.append(Entry::Increment).unwrap();
+        }
+        log.close();
+
+        // the counter and the upload, then the few increments after the rewrite
+        let log = fs::read_to_string(&path).unwrap();
+        assert!(log.starts_with("counter ") && log.lines().count() < 10, "{}", log);
+
+        let (counter, items, _) = replay(&path).unwrap();
+        assert_eq!((counter, items), (increments, uploads(&["a"])));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_a_failed_writer_on_every_append() {
+        let writer = Writer::new(PathBuf::from("/dev/full"), Fsync::Always, 0, 0, HashSet::new()).unwrap();
+        let log = AppendLog::start(writer);
+
+        for _ in 0..3 {
+            let err = log.append(Entry::Increment).unwrap_err();
+            assert!(err.to_string().starts_with("append log failed: "), "{}", err);
+        }
+    }
+}
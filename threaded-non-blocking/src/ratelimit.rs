@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::settings;
+
+pub static DEFAULT_LIMITS: &str = "ip.compute=5,ip.*=1000";
+
+/// Whom a limit applies to.
+#[derive(Clone, Copy, PartialEq)]
+enum Scope {
+    Ip,
+    Connection,
+}
+
+/// `rate` requests per second of `command`, or if there is none of every command the scope has no limit of its own for.
+struct Limit {
+    scope: Scope,
+    command: Option<String>,
+    rate: f64,
+}
+
+impl Limit {
+    /// Tokens a bucket holds at most, bursts of up to one second's worth pass at once.
+    fn capacity(&self) -> f64 {
+        self.rate.max(1.0)
+    }
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    /// Parses `<scope>.<command>=<rate>`, where the scope is `ip` or `conn` and the command may be `*`.
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let (key, rate) = str.split_once('=').ok_or_else(|| format!("missing rate in limit: {}", str))?;
+        let (scope, command) = key.split_once('.').ok_or_else(|| format!("missing scope in limit: {}", str))?;
+
+        let scope = match scope {
+            "ip" => Scope::Ip,
+            "conn" => Scope::Connection,
+            other => return Err(format!("unknown limit scope: {}", other)),
+        };
+        let rate = rate.parse().ok().filter(|rate: &f64| *rate > 0.0).ok_or_else(|| format!("bad rate in limit: {}", str))?;
+
+        Ok(Limit { scope, command: (command != "*").then(|| command.to_string()), rate })
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    Connection(SocketAddr),
+}
+
+impl Client {
+    fn of(scope: Scope, peer: SocketAddr) -> Self {
+        match scope {
+            Scope::Ip => Client::Ip(peer.ip()),
+            Scope::Connection => Client::Connection(peer),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * limit.rate).min(limit.capacity());
+        self.updated = now;
+    }
+}
+
+/// Token buckets per client address and per connection, for every command or a single one.
+pub struct RateLimiter {
+    limits: Vec<Limit>,
+    /// Keyed by client and index into `limits`, a missing bucket counts as a full one.
+    buckets: HashMap<(Client, usize), Bucket>,
+}
+
+impl FromStr for RateLimiter {
+    type Err = String;
+
+    /// Parses a comma separated list of limits, each as `Limit` reads it.
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let limits = str.split(',')
+            .map(str::trim)
+            .filter(|limit| !limit.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { limits, buckets: HashMap::new() })
+    }
+}
+
+impl RateLimiter {
+    /// Reads the limits from `RATE_LIMITS`, a comma separated list like `ip.compute=5,conn.*=100`.
+    /// Defaults to compute at 5 and everything else at 1000 requests per second and address.
+    pub fn from_env() -> Self {
+        settings::var("RATE_LIMITS").unwrap_or_else(|_| DEFAULT_LIMITS.to_string()).parse().unwrap_or_else(|err| {
+            warn!("{}, using the default limits", err);
+            DEFAULT_LIMITS.parse().unwrap()
+        })
+    }
+
+    /// Takes a token from every bucket the request `message` of `peer` falls under.
+    /// If one of them is empty none is taken, and the error tells how long until the request would pass.
+    pub fn check(&mut self, peer: SocketAddr, message: &str) -> Result<(), Duration> {
+        let command = message.split_whitespace().next().unwrap_or_default();
+        let now = Instant::now();
+
+        let named = |scope| self.limits.iter().any(|limit| limit.scope == scope && limit.command.as_deref() == Some(command));
+        let matching = || self.limits.iter().enumerate().filter(|(_, limit)| match limit.command.as_deref() {
+            Some(name) => name == command,
+            None => !named(limit.scope),
+        });
+
+        let mut wait: f64 = 0.0;
+        for (i, limit) in matching() {
+            let bucket = self.buckets.entry((Client::of(limit.scope, peer), i)).or_insert(Bucket { tokens: limit.capacity(), updated: now });
+            bucket.refill(limit, now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max((1.0 - bucket.tokens) / limit.rate);
+            }
+        }
+
+        if wait > 0.0 {
+            return Err(Duration::from_secs_f64(wait));
+        }
+
+        for (i, limit) in matching() {
+            if let Some(bucket) = self.buckets.get_mut(&(Client::of(limit.scope, peer), i)) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops the buckets of a closed connection, along with every other bucket that filled up again.
+    pub fn forget(&mut self, peer: SocketAddr) {
+        let now = Instant::now();
+        let limits = &self.limits;
+
+        self.buckets.retain(|(client, i), bucket| {
+            bucket.refill(&limits[*i], now);
+            *client != Client::Connection(peer) && bucket.tokens < limits[*i].capacity()
+        });
+    }
+}
+
+/// Response to a request that was turned away, the wait is rounded up to whole milliseconds.
+pub fn rejection(wait: Duration) -> String {
+    format!("ERR rate-limited retry-after={}\n", wait.as_micros().div_ceil(1000))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 4000))
+    }
+
+    #[test]
+    fn wildcard_covers_only_commands_without_a_limit() {
+        let mut limiter: RateLimiter = "ip.compute=1,ip.*=2".parse().unwrap();
+
+        assert!(limiter.check(peer(), "compute 10").is_ok());
+        assert!(limiter.check(peer(), "compute 10").is_err());
+
+        // compute took nothing from the `*` bucket
+        assert!(limiter.check(peer(), "counter").is_ok());
+        assert!(limiter.check(peer(), "increment").is_ok());
+        assert!(limiter.check(peer(), "counter").is_err());
+    }
+
+    #[test]
+    fn empty_bucket_waits_for_the_next_token() {
+        let mut limiter: RateLimiter = "conn.*=4".parse().unwrap();
+
+        for _ in 0..4 {
+            assert!(limiter.check(peer(), "counter").is_ok());
+        }
+        let wait = limiter.check(peer(), "counter").unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(250));
+
+        // another connection from the same address has a bucket of its own
+        assert!(limiter.check(SocketAddr::from(([127, 0, 0, 1], 4001)), "counter").is_ok());
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, error};
+
+static MAGIC: &[u8; 4] = b"WSNP";
+static VERSION: u32 = 1;
+
+/// Point-in-time snapshots of the counter and uploads.
+///
+/// Callers capture a copy of the state and hand it over, the file itself is
+/// written on a background thread to a temp path and renamed into place.
+pub struct Snapshotter {
+    path: PathBuf,
+    interval: Duration,
+    /// Mutations not yet in a saved snapshot.
+    changes: Arc<AtomicU64>,
+    last_save: Mutex<Instant>,
+    saving: Arc<AtomicBool>,
+}
+
+impl Snapshotter {
+    pub fn new(path: impl AsRef<Path>, interval: Duration) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            interval,
+            changes: Arc::new(AtomicU64::new(0)),
+            last_save: Mutex::new(Instant::now()),
+            saving: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn load(&self) -> io::Result<Option<(u64, HashSet<String>)>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let (counter, uploads) = read(&mut BufReader::new(file))?;
+        info!("Loaded snapshot {} -> counter {}, {} uploads", self.path.display(), counter, uploads.len());
+
+        Ok(Some((counter, uploads)))
+    }
+
+    /// Records a mutation since the last snapshot.
+    pub fn touch(&self) {
+        self.changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// True once the interval has passed with unsaved changes and no save running.
+    pub fn due(&self) -> bool {
+        self.changes.load(Ordering::Relaxed) > 0
+            && self.last_save.lock().unwrap().elapsed() >= self.interval
+            && !self.saving.load(Ordering::Acquire)
+    }
+
+    /// Time until the next scheduled check, rechecking every second once the interval has passed.
+    pub fn next_check(&self) -> Duration {
+        match self.interval.saturating_sub(self.last_save.lock().unwrap().elapsed()) {
+            Duration::ZERO => Duration::from_secs(1),
+            remaining => remaining,
+        }
+    }
+
+    /// Writes the captured state in the background, returns false if a save is already running.
+    /// The changes captured only count as saved once the file is in place, a failed save is retried
+    /// after the next interval.
+    pub fn save(&self, counter: u64, uploads: HashSet<String>) -> bool {
+        if self.saving.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+
+        let captured = self.changes.load(Ordering::Relaxed);
+        *self.last_save.lock().unwrap() = Instant::now();
+
+        let (path, changes, saving) = (self.path.clone(), Arc::clone(&self.changes), Arc::clone(&self.saving));
+
+        thread::spawn(move || {
+            let start = Instant::now();
+
+            match write_atomic(&path, counter, &uploads) {
+                Ok(()) => {
+                    changes.fetch_sub(captured, Ordering::Relaxed);
+                    info!("Saved snapshot {} in {:?}", path.display(), start.elapsed());
+                },
+                Err(err) => error!("Could not save snapshot {}: {}", path.display(), err),
+            }
+
+            saving.store(false, Ordering::Release);
+        });
+
+        true
+    }
+}
+
+fn write_atomic(path: &Path, counter: u64, uploads: &HashSet<String>) -> io::Result<()> {
+    let temp = path.with_extension("tmp");
+
+    let mut out = BufWriter::new(File::create(&temp)?);
+    write(&mut out, counter, uploads)?;
+    out.flush()?;
+    out.get_ref().sync_all()?;
+    drop(out);
+
+    fs::rename(&temp, path)
+}
+
+fn write(out: &mut impl Write, counter: u64, uploads: &HashSet<String>) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&counter.to_le_bytes())?;
+    out.write_all(&(uploads.len() as u64).to_le_bytes())?;
+
+    for item in uploads {
+        out.write_all(&(item.len() as u32).to_le_bytes())?;
+        out.write_all(item.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn read(input: &mut impl Read) -> io::Result<(u64, HashSet<String>)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let mut magic = [0u8; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a snapshot file"));
+    }
+
+    let version = u32::from_le_bytes(read_bytes(input)?);
+    if version != VERSION {
+        return Err(invalid(&format!("unsupported snapshot version {}", version)));
+    }
+
+    let counter = u64::from_le_bytes(read_bytes(input)?);
+    let len = u64::from_le_bytes(read_bytes(input)?);
+
+    // lengths are only trusted as far as the bytes behind them go, a corrupt one must not size an allocation
+    let truncated = |err: io::Error| if err.kind() == io::ErrorKind::UnexpectedEof { invalid("snapshot is truncated") } else { err };
+
+    let mut uploads = HashSet::new();
+    for _ in 0..len {
+        let item_len = u32::from_le_bytes(read_bytes(input).map_err(truncated)?) as u64;
+        let mut item = Vec::new();
+        if input.take(item_len).read_to_end(&mut item)? as u64 != item_len {
+            return Err(invalid("snapshot is truncated"));
+        }
+        uploads.insert(String::from_utf8(item).map_err(|_| invalid("upload is not utf-8"))?);
+    }
+
+    Ok((counter, uploads))
+}
+
+fn read_bytes<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    fn uploads(items: &[&str]) -> HashSet<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    fn encode(counter: u64, uploads: &HashSet<String>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write(&mut bytes, counter, uploads).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn decodes_what_it_encoded() {
+        for (counter, items) in [(0, uploads(&[])), (42, uploads(&["a", "", "ünïcode", "with space"])), (u64::MAX, uploads(&["x"]))] {
+            let (decoded, decoded_items) = read(&mut &encode(counter, &items)[..]).unwrap();
+            assert_eq!((decoded, decoded_items), (counter, items));
+        }
+    }
+
+    #[test]
+    fn refuses_other_files() {
+        let mut bytes = encode(7, &uploads(&["a"]));
+        for cut in [1, 2, 5] {
+            let truncated = &bytes[..bytes.len() - cut];
+            assert_eq!(read(&mut &truncated[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        assert_eq!(read(&mut &bytes[..10]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        bytes[4] = 2;
+        assert_eq!(read(&mut &bytes[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        bytes[0] = b'X';
+        assert_eq!(read(&mut &bytes[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn refuses_lengths_past_the_end() {
+        let mut huge_len = encode(7, &uploads(&[]));
+        huge_len[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(read(&mut &huge_len[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut huge_item = encode(7, &uploads(&["a"]));
+        huge_item[24..28].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(read(&mut &huge_item[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn keeps_changes_until_saved() {
+        let dir = std::env::temp_dir().join(format!("snapshot-test-missing-{}", process::id()));
+        let snapshots = Snapshotter::new(dir.join("dump.wsnp"), Duration::ZERO);
+        snapshots.touch();
+
+        assert!(snapshots.save(1, uploads(&[])));
+        while snapshots.saving.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(snapshots.due());
+
+        fs::create_dir(&dir).unwrap();
+        assert!(snapshots.save(1, uploads(&[])));
+        while snapshots.saving.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let saved = snapshots.load();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!snapshots.due());
+        assert_eq!(saved.unwrap(), Some((1, uploads(&[]))));
+    }
+
+    #[test]
+    fn loads_what_was_written_to_disk() {
+        let path = std::env::temp_dir().join(format!("snapshot-test-{}.wsnp", process::id()));
+        let snapshots = Snapshotter::new(&path, Duration::from_secs(60));
+        assert!(snapshots.load().unwrap().is_none());
+
+        write_atomic(&path, 3, &uploads(&["a", "b"])).unwrap();
+        let loaded = snapshots.load();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.unwrap(), Some((3, uploads(&["a", "b"]))));
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::settings;
+
+pub static IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+pub static READ_TIMEOUT: Duration = Duration::from_secs(10);
+pub static WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a connection is waiting on the client for.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// The next request.
+    Idle,
+    /// The rest of a request that already started, or of the TLS handshake.
+    Read,
+    /// Room in the socket for the reply.
+    Write,
+}
+
+/// How long a client may keep a connection in each phase, None for as long as it likes.
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    pub idle: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
+
+fn from_env(name: &str, default: Duration) -> Option<Duration> {
+    match settings::parse(name, default.as_millis() as u64) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+impl Timeouts {
+    /// Reads `IDLE_TIMEOUT`, `READ_TIMEOUT` and `WRITE_TIMEOUT` in milliseconds, 0 turns a timeout off.
+    pub fn from_env() -> Self {
+        let timeouts = Self {
+            idle: from_env("IDLE_TIMEOUT", IDLE_TIMEOUT),
+            read: from_env("READ_TIMEOUT", READ_TIMEOUT),
+            write: from_env("WRITE_TIMEOUT", WRITE_TIMEOUT),
+        };
+
+        info!("Timeouts: idle {:?}, read {:?}, write {:?}", timeouts.idle, timeouts.read, timeouts.write);
+
+        timeouts
+    }
+
+    /// Deadline of a connection entering `phase` now.
+    pub fn start(&self, phase: Phase) -> Option<Deadline> {
+        let after = match phase {
+            Phase::Idle => self.idle,
+            Phase::Read => self.read,
+            Phase::Write => self.write,
+        }?;
+
+        Some(Deadline { at: Instant::now() + after, phase, after })
+    }
+}
+
+/// Point by which the client has to move a connection out of its phase.
+#[derive(Clone, Copy)]
+pub struct Deadline {
+    pub at: Instant,
+    pub phase: Phase,
+    after: Duration,
+}
+
+impl fmt::Display for Deadline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.phase {
+            Phase::Idle => write!(f, "idle for {:?}", self.after),
+            Phase::Read => write!(f, "no full request within {:?}", self.after),
+            Phase::Write => write!(f, "the reply was not taken within {:?}", self.after),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net;
+use std::sync::Arc;
+
+use log::info;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+
+use crate::settings;
+
+/// TLS settings from `TLS_CERT` and `TLS_KEY`, PEM files holding the certificate chain and its private key.
+/// With `TLS_CLIENT_CA` set too, clients must present a certificate signed by one of the CAs in that file.
+/// None if `TLS_CERT` is not set, connections stay plain TCP then.
+pub fn from_env() -> io::Result<Option<Arc<ServerConfig>>> {
+    let Ok(cert) = settings::var("TLS_CERT") else {
+        return Ok(None);
+    };
+    let key = settings::var("TLS_KEY").map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "TLS_CERT is set without TLS_KEY"))?;
+
+    let chain = CertificateDer::pem_file_iter(&cert).map_err(invalid)?.collect::<Result<Vec<_>, _>>().map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_file(&key).map_err(invalid)?;
+
+    let builder = ServerConfig::builder();
+    let builder = match settings::var("TLS_CLIENT_CA") {
+        Ok(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(&path).map_err(invalid)? {
+                roots.add(ca.map_err(invalid)?).map_err(invalid)?;
+            }
+            info!("Requiring client certificates signed by {}", path);
+            builder.with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build().map_err(invalid)?)
+        },
+        Err(_) => builder.with_no_client_auth(),
+    };
+
+    info!("Serving TLS with certificate {}", cert);
+
+    Ok(Some(Arc::new(builder.with_single_cert(chain, key).map_err(invalid)?)))
+}
+
+fn invalid(err: impl Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Connection of a client over a non-blocking socket, encrypted if the server has TLS set up.
+///
+/// The TLS session only moves forward when the socket is read or written, so a read
+/// can fail with `WouldBlock` although the socket was readable, for instance while handshaking.
+pub enum Stream {
+    Plain(net::TcpStream),
+    Tls(Box<ServerConnection>, net::TcpStream),
+}
+
+impl Stream {
+    pub fn accept(socket: net::TcpStream, config: Option<&Arc<ServerConfig>>) -> io::Result<Self> {
+        Ok(match config {
+            Some(config) => Stream::Tls(Box::new(ServerConnection::new(Arc::clone(config)).map_err(invalid)?), socket),
+            None => Stream::Plain(socket),
+        })
+    }
+
+    /// What to register with the poller, or peek at for a hangup.
+    pub fn socket(&self) -> &net::TcpStream {
+        match self {
+            Stream::Plain(socket) | Stream::Tls(_, socket) => socket,
+        }
+    }
+
+    /// Whether records are still waiting for the socket to take them.
+    pub fn wants_write(&self) -> bool {
+        match self {
+            Stream::Plain(_) => false,
+            Stream::Tls(conn, _) => conn.wants_write(),
+        }
+    }
+
+    /// Plaintext that arrived so far, 0 once the client closed the connection.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (conn, socket) = match self {
+            Stream::Plain(socket) => return socket.read(buf),
+            Stream::Tls(conn, socket) => (conn, socket),
+        };
+
+        loop {
+            match conn.reader().read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                // a client closing the socket without a close_notify is taken as closing the connection
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                res => return res,
+            }
+
+            if conn.read_tls(socket)? == 0 {
+                return Ok(0);
+            }
+
+            let processed = conn.process_new_packets();
+            // handshake messages or the alert telling the client what went wrong
+            flush(conn, socket)?;
+            processed.map_err(invalid)?;
+        }
+    }
+
+    pub fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.write_all(buf),
+            Stream::Tls(conn, socket) => {
+                conn.writer().write_all(buf)?;
+                flush(conn, socket)
+            },
+        }
+    }
+
+    /// Sends on what the socket did not take before.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(_) => Ok(()),
+            Stream::Tls(conn, socket) => flush(conn, socket),
+        }
+    }
+}
+
+/// Writes out pending records until done or the socket is full.
+fn flush(conn: &mut ServerConnection, socket: &mut net::TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        match conn.write_tls(socket) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            res => res?,
+        };
+    }
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::io::{self, Write};
+use std::net::{self, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use socket2::{Domain, Socket, Type};
+
+use crate::settings;
+use crate::tls::Stream;
+
+pub static MAX_CONNECTIONS: usize = 1024;
+pub static LISTEN_BACKLOG: i32 = 128;
+/// How long a refused client gets to take the reply.
+static REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Refused clients told so at once, more are closed without a word.
+static MAX_REFUSING: usize = 64;
+
+/// Binds `addr` with room for `LISTEN_BACKLOG` connections the kernel keeps until they are accepted.
+pub fn listen(addr: SocketAddr) -> io::Result<net::TcpListener> {
+    let backlog = settings::parse("LISTEN_BACKLOG", LISTEN_BACKLOG);
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+
+    info!("Listening with a backlog of {}", backlog);
+
+    Ok(socket.into())
+}
+
+/// Gauge of the connections being served, at most `MAX_CONNECTIONS` of them at once.
+pub struct Connections {
+    current: AtomicUsize,
+    max: usize,
+    /// Refused clients still being told so.
+    refusing: AtomicUsize,
+}
+
+impl Connections {
+    pub fn from_env() -> Arc<Self> {
+        let max = settings::parse("MAX_CONNECTIONS", MAX_CONNECTIONS);
+        info!("Serving up to {} connections", max);
+        Arc::new(Self { current: AtomicUsize::new(0), max, refusing: AtomicUsize::new(0) })
+    }
+
+    /// Counts a new connection in, None if all places are taken.
+    pub fn admit(self: &Arc<Self>) -> Option<Slot> {
+        self.current.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| (current < self.max).then_some(current + 1)).ok()?;
+        Some(Slot { connections: Arc::clone(self) })
+    }
+
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Tells a client there is no place for it on a thread of its own, so the accept loop goes on meanwhile.
+    pub fn refuse(self: &Arc<Self>, stream: Stream) {
+        if self.refusing.fetch_add(1, Ordering::SeqCst) >= MAX_REFUSING {
+            self.refusing.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+
+        let connections = Arc::clone(self);
+        thread::spawn(move || {
+            if let Err(err) = busy(stream) {
+                warn!("Could not refuse: {}", err);
+            }
+            connections.refusing.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Place of one connection, given back once dropped.
+pub struct Slot {
+    connections: Arc<Connections>,
+}
+
+impl Slot {
+    /// Connections open along with this one.
+    pub fn open(&self) -> usize {
+        self.connections.current()
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.connections.current.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Tells a client there is no place for it before closing the connection.
+fn busy(mut stream: Stream) -> io::Result<()> {
+    // a TLS client has to finish the handshake first, which must not hold up a thread for long
+    stream.socket().set_read_timeout(Some(REFUSE_TIMEOUT))?;
+    stream.socket().set_write_timeout(Some(REFUSE_TIMEOUT))?;
+
+    stream.write_all(b"ERR server-busy\n")?;
+    stream.shutdown()
+}
@@ -1,76 +1,139 @@
-use std::io::{BufRead, Write};
-use std::{io, net, thread};
+mod connections;
+mod logger;
+mod metrics;
+mod settings;
+mod shutdown;
+mod timeouts;
+mod tls;
+
+use std::io::Write;
+use std::sync::Arc;
+use std::{io, thread};
 
-use fern;
 use log::{info, warn, error};
 
-static PORT: u32 = 3000;
-
-fn setup_logger() -> Result<(), log::SetLoggerError> {
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{}][{}] {}",
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Info)
-        .chain(std::io::stdout())
-        .apply()
-}
+use crate::connections::{Connections, Slot};
+use crate::metrics::Metrics;
+use crate::shutdown::{Shutdown, Tracked};
+use crate::timeouts::Timeouts;
+use crate::tls::Stream;
+
+pub static PORT: u16 = 3000;
+pub static READ_BUFFER: usize = 8 * 1024;
 
-fn handle(stream: net::TcpStream) -> io::Result<usize> {
+fn handle(stream: Stream, slot: Slot, tracked: Tracked, timeouts: Timeouts, read_buffer: usize) -> io::Result<usize> {
     let mut buf = String::new();
-    let mut reader = io::BufReader::new(&stream);
-    let mut writer = io::BufWriter::new(&stream);
 
     let (ip, port) = (
-        stream.peer_addr().unwrap().ip(),
-        stream.peer_addr().unwrap().port(),
+        stream.socket().peer_addr().unwrap().ip(),
+        stream.socket().peer_addr().unwrap().port(),
     );
 
-    info!("Connection from {}:{}", ip, port);
+    // the timeouts are set through a second handle to the socket, the stream is busy reading
+    let socket = stream.socket().try_clone()?;
+    socket.set_write_timeout(timeouts.write)?;
+
+    // replies go out through the reader, so a TLS session has a single owner
+    let mut reader = io::BufReader::with_capacity(read_buffer, stream);
+
+    logger::sampled!("Connection from {}:{} ({} open)", ip, port, slot.open());
 
     loop {
         buf.clear();
-        let len = reader.read_line(&mut buf)?;
+        let len = match timeouts.read_line(&mut reader, &socket, &mut buf) {
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                warn!("Closing {}:{}, {}", ip, port, err);
+                break;
+            },
+            res => res?,
+        };
+
+        if len == 0 && tracked.shutdown_requested() {
+            info!("Sending bye to {}:{}", ip, port);
+            let writer = reader.get_mut();
+            let _ = writer.write_all("bye\n".as_bytes()).and_then(|_| writer.shutdown());
+            break;
+        }
 
         if len == 0 || buf == "done\n" {
             info!("Shutdown {}:{}", ip, port);
-            stream.shutdown(net::Shutdown::Both)?;
+            reader.get_mut().shutdown()?;
             break;
         }
 
-        info!("Received from {}:{} > [bytes {}][{}]", ip, port, len, &buf[..len - 1]);
+        logger::sampled!("Received from {}:{} > [bytes {}][{}]", ip, port, len, &buf[..len - 1]);
 
-        if let Err(_) = writer.write_all("ack\n".as_bytes()) {
+        let writer = reader.get_mut();
+        if let Err(err) = writer.write_all("ack\n".as_bytes()).and_then(|_| writer.flush()) {
+            if timeouts::is_timeout(&err) {
+                warn!("Closing {}:{}, the reply was not taken within {:?}", ip, port, timeouts.write.unwrap_or_default());
+                break;
+            }
             error!("Could not respond!");
+            return Err(err);
         }
-
-        writer.flush()?;
     }
 
     Ok(0)
 }
 
 fn main() -> io::Result<()> {
-    setup_logger()
-        .err()
-        .map(|err| println!("Could not start logging: {}", err));
+    settings::load();
+    if let Err(err) = logger::setup() {
+        println!("Could not start logging: {}", err);
+    }
 
-    let listener = net::TcpListener::bind(format!("127.0.0.1:{}", PORT))?;
+    let tls = tls::from_env()?;
+    let connections = Connections::from_env();
+    let timeouts = Timeouts::from_env();
+    let read_buffer = settings::parse("READ_BUFFER", READ_BUFFER).max(1);
+    let addr = settings::bind(settings::parse("PORT", PORT))?;
+    let listener = connections::listen(addr)?;
+    let shutdown = Shutdown::install(addr)?;
+    let metrics = Arc::new(Metrics::new(Arc::clone(&connections)));
+    metrics::serve(Arc::clone(&metrics))?;
 
-    info!("Server started on port {}", PORT);
+    info!("Server started on port {}", addr.port());
 
+    let mut accepted = 0;
     for connection in listener.incoming() {
+        if shutdown.requested() {
+            break;
+        }
+
         match connection {
-            Ok(stream) => {
-                thread::spawn(|| {
-                    handle(stream)
-                        .err()
-                        .map(|err| warn!("Stream error: {}", err));
+            Ok(socket) => {
+                accepted += 1;
+                let id = accepted;
+                let stream = match Stream::accept(socket, tls.as_ref()) {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Bad connection: {}", err);
+                        continue;
+                    }
+                };
+                let Some(slot) = connections.admit() else {
+                    warn!("Refusing a connection, {} are open", connections.current());
+                    metrics.refused();
+                    connections.refuse(stream);
+                    continue;
+                };
+
+                // tracked before the thread starts, so the drain cannot miss it
+                let tracked = match shutdown.track(stream.socket()) {
+                    Ok(tracked) => tracked,
+                    Err(err) => {
+                        warn!("Bad connection: {}", err);
+                        continue;
+                    }
+                };
+
+                metrics.accepted();
+                thread::spawn(move || {
+                    let _connection = logger::connection(id);
+                    if let Err(err) = handle(stream, slot, tracked, timeouts, read_buffer) {
+                        warn!("Stream error: {}", err);
+                    }
                 });
             }
             Err(err) => {
@@ -79,5 +142,9 @@ fn main() -> io::Result<()> {
         }
     }
 
+    shutdown.drain();
+    info!("Server stopped");
+    logger::flush();
+
     Ok(())
 }
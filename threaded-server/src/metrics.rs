@@ -0,0 +1,131 @@
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::connections::Connections;
+use crate::logger;
+use crate::settings;
+
+pub static METRICS_PORT: u16 = 9100;
+/// How long a scraper gets to send its request and take the reply, the listener serves one at a time.
+static SCRAPE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Bytes the request line and headers of a scrape may take together.
+static MAX_REQUEST: u64 = 8 * 1024;
+
+/// Counters behind `/metrics`, the open connections are read from the gauge that admits them.
+pub struct Metrics {
+    connections: Arc<Connections>,
+    accepted: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(connections: Arc<Connections>) -> Self {
+        Self { connections, accepted: AtomicU64::default(), refused: AtomicU64::default() }
+    }
+
+    pub fn accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn refused(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The Prometheus text format of everything.
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        metric(&mut out, "server_connections_active", "gauge", "Connections open right now.", self.connections.current());
+        metric(&mut out, "server_connections_max", "gauge", "Connections served at once, more are refused.", self.connections.max());
+        metric(&mut out, "server_connections_accepted_total", "counter", "Connections accepted and served.", self.accepted.load(Ordering::Relaxed));
+        metric(&mut out, "server_connections_refused_total", "counter", "Connections refused for want of a place.", self.refused.load(Ordering::Relaxed));
+        metric(&mut out, "server_log_dropped_total", "counter", "Log records dropped for want of room in the buffer.", logger::dropped());
+
+        out
+    }
+}
+
+fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl fmt::Display) {
+    describe(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Serves `/metrics` on `METRICS_PORT` from a thread of its own, 0 turns it off.
+pub fn serve(metrics: Arc<Metrics>) -> io::Result<()> {
+    let port = settings::parse("METRICS_PORT", METRICS_PORT);
+    if port == 0 {
+        info!("Metrics are off");
+        return Ok(());
+    }
+
+    let listener = net::TcpListener::bind(settings::bind(port)?)?;
+    info!("Serving metrics on port {}", port);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(err) = stream.and_then(|stream| scrape(stream, &metrics)) {
+                warn!("Bad metrics request: {}", err);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Answers one HTTP request, `GET /metrics` with the metrics and anything else with a 404.
+fn scrape(stream: net::TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+
+    // scrapes are served one at a time, so a slow or endless request must not hold up the next
+    let deadline = Deadline { stream: &stream, at: Instant::now() + SCRAPE_TIMEOUT };
+    let mut reader = BufReader::new(deadline.take(MAX_REQUEST));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    // the headers say nothing of interest, but closing with them unread would reset the connection
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+
+    write!(&stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body)?;
+
+    Ok(())
+}
+
+/// Reads of a stream that time out once `at` has passed, however many came before.
+struct Deadline<'a> {
+    stream: &'a net::TcpStream,
+    at: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.at.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request took too long"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
@@ -0,0 +1,171 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::net;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::settings;
+
+pub static IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+pub static READ_TIMEOUT: Duration = Duration::from_secs(10);
+pub static WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client may keep the server waiting, None for as long as it likes.
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    /// Between the end of one request and the start of the next one.
+    pub idle: Option<Duration>,
+    /// From the first byte of a request to its newline.
+    pub read: Option<Duration>,
+    /// For every write of a reply.
+    pub write: Option<Duration>,
+}
+
+fn from_env(name: &str, default: Duration) -> Option<Duration> {
+    match settings::parse(name, default.as_millis() as u64) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+impl Timeouts {
+    /// Reads `IDLE_TIMEOUT`, `READ_TIMEOUT` and `WRITE_TIMEOUT` in milliseconds, 0 turns a timeout off.
+    pub fn from_env() -> Self {
+        let timeouts = Self {
+            idle: from_env("IDLE_TIMEOUT", IDLE_TIMEOUT),
+            read: from_env("READ_TIMEOUT", READ_TIMEOUT),
+            write: from_env("WRITE_TIMEOUT", WRITE_TIMEOUT),
+        };
+
+        info!("Timeouts: idle {:?}, read {:?}, write {:?}", timeouts.idle, timeouts.read, timeouts.write);
+
+        timeouts
+    }
+
+    /// Like `read_line`, but fails with `TimedOut` if the request does not start within the idle timeout
+    /// or its newline does not follow within the read timeout. `socket` is what `reader` reads from.
+    pub fn read_line<R: Read>(&self, reader: &mut BufReader<R>, socket: &net::TcpStream, buf: &mut String) -> io::Result<usize> {
+        socket.set_read_timeout(self.idle)?;
+        // a signal interrupts reads on a socket with a timeout even when the handler asks for a restart
+        loop {
+            match reader.fill_buf() {
+                Ok([]) => return Ok(0),
+                Ok(_) => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if is_timeout(&err) => return Err(timed_out(format!("idle for {:?}", self.idle.unwrap_or_default()))),
+                Err(err) => return Err(err),
+            }
+        }
+
+        // a deadline for the whole line, a client trickling in a byte at a time gets no longer
+        let deadline = self.read.map(|read| Instant::now() + read);
+        socket.set_read_timeout(self.read)?;
+
+        let mut line = Vec::new();
+        loop {
+            let available = match reader.fill_buf() {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if is_timeout(&err) => return Err(timed_out(format!("no full line within {:?}", self.read.unwrap_or_default()))),
+                res => res?,
+            };
+
+            if available.is_empty() {
+                break;
+            }
+
+            let len = match available.iter().position(|&byte| byte == b'\n') {
+                Some(newline) => newline + 1,
+                None => available.len(),
+            };
+            line.extend_from_slice(&available[..len]);
+            reader.consume(len);
+
+            if line.ends_with(b"\n") {
+                break;
+            }
+
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(timed_out(format!("no full line within {:?}", self.read.unwrap_or_default())));
+                }
+                socket.set_read_timeout(Some(left))?;
+            }
+        }
+
+        let line = String::from_utf8(line).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))?;
+        buf.push_str(&line);
+        Ok(line.len())
+    }
+}
+
+/// Blocking sockets report a passed timeout as `WouldBlock`, or `TimedOut` on some platforms.
+pub fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn timed_out(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::thread;
+
+    use super::*;
+
+    /// Both ends of a connection over loopback, the client's first.
+    fn connection() -> (net::TcpStream, net::TcpStream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (client, listener.accept().unwrap().0)
+    }
+
+    fn timeouts(idle: u64, read: u64) -> Timeouts {
+        Timeouts { idle: Some(Duration::from_millis(idle)), read: Some(Duration::from_millis(read)), write: None }
+    }
+
+    #[test]
+    fn reads_a_line_and_the_end_of_the_stream() {
+        let (mut client, server) = connection();
+        let mut reader = BufReader::new(&server);
+        client.write_all(b"counter\nincrement").unwrap();
+        drop(client);
+
+        let mut line = String::new();
+        assert_eq!(timeouts(1000, 1000).read_line(&mut reader, &server, &mut line).unwrap(), 8);
+        assert_eq!(timeouts(1000, 1000).read_line(&mut reader, &server, &mut line).unwrap(), 9);
+        assert_eq!(timeouts(1000, 1000).read_line(&mut reader, &server, &mut line).unwrap(), 0);
+        assert_eq!(line, "counter\nincrement");
+    }
+
+    #[test]
+    fn idle_client_times_out() {
+        let (_client, server) = connection();
+        let started = Instant::now();
+
+        let err = timeouts(50, 1000).read_line(&mut BufReader::new(&server), &server, &mut String::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn trickling_client_gets_no_more_than_the_read_timeout() {
+        let (mut client, server) = connection();
+        let trickle = thread::spawn(move || {
+            // a byte well within the timeout of every single read, but never a newline
+            while client.write_all(b"x").is_ok() {
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let started = Instant::now();
+
+        let err = timeouts(1000, 200).read_line(&mut BufReader::new(&server), &server, &mut String::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_millis(1000));
+
+        drop(server);
+        trickle.join().unwrap();
+    }
+}